@@ -0,0 +1,756 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::notebook::encryption::NotebookKey;
+use crate::notebook::{Notebook, NotebookError};
+use crate::utils::copy_dir;
+
+const BACKUPS_DIR: &str = "backups";
+const MANIFEST_FILE: &str = "manifest.json";
+const MANIFEST_VERSION: u32 = 1;
+// the settings file is copied into this dir of a snapshot
+const SETTINGS_DIR: &str = "settings";
+// a snapshot is written under this suffix and renamed once complete
+const PARTIAL_SUFFIX: &str = ".partial";
+// attachments are checked by size only, as hashing every blob each hour would be slow
+const ATTACHMENTS_PREFIX: &str = "attachments/";
+// a restore copies the snapshot into the notebook dir, then moves the notebook's files aside
+// and the copied ones into place. Both dirs start with a dot so they are never notebook files.
+const STAGING_DIR: &str = ".restore-staging";
+const REPLACED_DIR: &str = ".restore-replaced";
+// written once the staging dir holds the whole snapshot, from then on an interrupted restore
+// is finished rather than abandoned
+const STAGED_MARKER: &str = ".staged";
+// written once every file of the notebook has been moved aside
+const REPLACED_MARKER: &str = ".replaced";
+const SECS_PER_HOUR: i64 = 60 * 60;
+const SECS_PER_DAY: i64 = 24 * SECS_PER_HOUR;
+
+// maps the creation time of a snapshot to the hour, day or week it was taken in
+type Period = fn(i64) -> i64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupKind {
+    /// Taken on a schedule and pruned by the retention policy
+    Scheduled,
+    /// Taken by the user, kept until deleted
+    Manual,
+    /// Taken before restoring another snapshot, so the restore can be undone. Kept until deleted.
+    PreRestore,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupFile {
+    /// Relative to the snapshot dir, separated by '/'
+    path: String,
+    size: u64,
+    sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupManifest {
+    version: u32,
+    notebook: String,
+    kind: BackupKind,
+    created: i64,
+    schema_version: u32,
+    encrypted: bool,
+    files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+    pub id: String,
+    pub notebook: String,
+    pub kind: BackupKind,
+    pub created: i64,
+    pub schema_version: u32,
+    pub encrypted: bool,
+    pub includes_settings: bool,
+    pub size_bytes: u64,
+}
+
+impl BackupInfo {
+    fn new(id: &str, manifest: &BackupManifest) -> Self {
+        BackupInfo {
+            id: id.to_string(),
+            notebook: manifest.notebook.clone(),
+            kind: manifest.kind,
+            created: manifest.created,
+            schema_version: manifest.schema_version,
+            encrypted: manifest.encrypted,
+            includes_settings: manifest
+                .files
+                .iter()
+                .any(|file| file.path.starts_with(SETTINGS_DIR)),
+            size_bytes: manifest.files.iter().map(|file| file.size).sum(),
+        }
+    }
+}
+
+/// How many scheduled snapshots to keep: the newest one of each of the last `hourly` hours,
+/// `daily` days and `weekly` weeks that have one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            hourly: 24,
+            daily: 7,
+            weekly: 4,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// The ids of the snapshots to keep out of (id, created) pairs
+    fn keep(&self, snapshots: &[(String, i64)]) -> HashSet<String> {
+        let mut newest_first = snapshots.to_vec();
+        newest_first.sort_by_key(|(_, created)| Reverse(*created));
+        let tiers: [(usize, Period); 3] = [
+            (self.hourly, |created| created.div_euclid(SECS_PER_HOUR)),
+            (self.daily, |created| created.div_euclid(SECS_PER_DAY)),
+            // the epoch was a Thursday, weeks start on Monday
+            (self.weekly, |created| {
+                (created.div_euclid(SECS_PER_DAY) + 3).div_euclid(7)
+            }),
+        ];
+        let mut keep = HashSet::new();
+        for (count, period) in tiers {
+            let mut periods = HashSet::new();
+            for (id, created) in &newest_first {
+                if periods.len() == count {
+                    break;
+                }
+                if periods.insert(period(*created)) {
+                    keep.insert(id.clone());
+                }
+            }
+        }
+        keep
+    }
+}
+
+fn backup_error(message: impl std::fmt::Display) -> NotebookError {
+    NotebookError::Backup(message.to_string())
+}
+
+fn file_error(e: std::io::Error) -> NotebookError {
+    NotebookError::FileAccess(e.to_string())
+}
+
+fn sha256_file(path: &Path) -> Result<String, NotebookError> {
+    let mut file = fs::File::open(path).map_err(file_error)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).map_err(file_error)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The files under `dir` as paths relative to `root`
+fn list_files(
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), NotebookError> {
+    for entry in fs::read_dir(dir).map_err(file_error)? {
+        let path = entry.map_err(file_error)?.path();
+        if path.is_dir() {
+            list_files(root, &path, files)?;
+        } else {
+            let relative = path
+                .strip_prefix(root)
+                .expect("listed files are under the root")
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((relative, path));
+        }
+    }
+    Ok(())
+}
+
+/// Snapshots of notebooks under `<app dir>/backups/<notebook>/<id>`. Each snapshot has a
+/// manifest with the size and hash of its files, which is checked before it is restored.
+#[derive(Clone)]
+pub struct Backups {
+    dir: PathBuf,
+}
+
+impl Backups {
+    pub fn new(app_dir: &Path) -> Self {
+        Backups {
+            dir: app_dir.join(BACKUPS_DIR),
+        }
+    }
+
+    fn notebook_dir(&self, notebook: &str) -> PathBuf {
+        self.dir.join(notebook)
+    }
+
+    /// Snapshots an open notebook named `name`, with the settings file when there is one
+    pub async fn create(
+        &self,
+        notebook: &Notebook,
+        name: &str,
+        kind: BackupKind,
+        settings_file: Option<&Path>,
+    ) -> Result<BackupInfo, NotebookError> {
+        let created = Utc::now();
+        let notebook_dir = self.notebook_dir(name);
+        fs::create_dir_all(&notebook_dir).map_err(file_error)?;
+        let mut id = created.format("%Y%m%d-%H%M%S").to_string();
+        let mut suffix = 1;
+        // creating the partial dir fails when it exists, so snapshots taken at the same time
+        // each get their own id
+        let partial = loop {
+            let partial = notebook_dir.join(format!("{}{}", id, PARTIAL_SUFFIX));
+            if !notebook_dir.join(&id).exists() {
+                match fs::create_dir(&partial) {
+                    Ok(()) => break partial,
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                    Err(e) => return Err(file_error(e)),
+                }
+            }
+            id = format!("{}-{}", created.format("%Y%m%d-%H%M%S"), suffix);
+            suffix += 1;
+        };
+        let written = self
+            .write(notebook, name, kind, created, settings_file, &partial)
+            .await;
+        let manifest = match written {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&partial);
+                return Err(e);
+            }
+        };
+        fs::rename(&partial, notebook_dir.join(&id)).map_err(file_error)?;
+        info!("Backed up notebook '{}' to {}", name, id);
+        Ok(BackupInfo::new(&id, &manifest))
+    }
+
+    async fn write(
+        &self,
+        notebook: &Notebook,
+        name: &str,
+        kind: BackupKind,
+        created: DateTime<Utc>,
+        settings_file: Option<&Path>,
+        target: &Path,
+    ) -> Result<BackupManifest, NotebookError> {
+        let schema_version = notebook.write_snapshot(target).await?;
+        if let Some(settings_file) = settings_file.filter(|file| file.is_file()) {
+            let settings_dir = target.join(SETTINGS_DIR);
+            fs::create_dir_all(&settings_dir).map_err(file_error)?;
+            let file_name = settings_file
+                .file_name()
+                .expect("settings files have a name");
+            fs::copy(settings_file, settings_dir.join(file_name)).map_err(file_error)?;
+        }
+        let mut files = Vec::new();
+        list_files(target, target, &mut files)?;
+        let files = files
+            .into_iter()
+            .map(|(relative, path)| {
+                let size = fs::metadata(&path).map_err(file_error)?.len();
+                let sha256 = match relative.starts_with(ATTACHMENTS_PREFIX) {
+                    true => None,
+                    false => Some(sha256_file(&path)?),
+                };
+                Ok(BackupFile {
+                    path: relative,
+                    size,
+                    sha256,
+                })
+            })
+            .collect::<Result<Vec<_>, NotebookError>>()?;
+        let manifest = BackupManifest {
+            version: MANIFEST_VERSION,
+            notebook: name.to_string(),
+            kind,
+            created: created.timestamp(),
+            schema_version,
+            encrypted: notebook.key().is_some(),
+            files,
+        };
+        let json = serde_json::to_string_pretty(&manifest).map_err(backup_error)?;
+        fs::write(target.join(MANIFEST_FILE), json).map_err(file_error)?;
+        Ok(manifest)
+    }
+
+    fn read_manifest(&self, name: &str, id: &str) -> Result<BackupManifest, NotebookError> {
+        // ids are directory names, never paths
+        if id.is_empty() || id.starts_with('.') || id.contains(['/', '\\']) {
+            return Err(backup_error(format!("'{}' is not a backup id", id)));
+        }
+        let path = self.notebook_dir(name).join(id).join(MANIFEST_FILE);
+        let json = fs::read_to_string(&path)
+            .map_err(|_| backup_error(format!("No backup '{}' of notebook '{}'", id, name)))?;
+        let manifest: BackupManifest = serde_json::from_str(&json)
+            .map_err(|e| backup_error(format!("Backup '{}' has an invalid manifest: {}", id, e)))?;
+        if manifest.version != MANIFEST_VERSION || manifest.notebook != name {
+            return Err(backup_error(format!(
+                "Backup '{}' is not a backup of notebook '{}'",
+                id, name
+            )));
+        }
+        Ok(manifest)
+    }
+
+    /// The snapshots of a notebook, newest first
+    pub fn list(&self, name: &str) -> Result<Vec<BackupInfo>, NotebookError> {
+        let dir = self.notebook_dir(name);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut backups = Vec::new();
+        for entry in fs::read_dir(&dir).map_err(file_error)? {
+            let id = entry
+                .map_err(file_error)?
+                .file_name()
+                .to_string_lossy()
+                .into_owned();
+            if id.ends_with(PARTIAL_SUFFIX) {
+                continue;
+            }
+            match self.read_manifest(name, &id) {
+                Ok(manifest) => backups.push(BackupInfo::new(&id, &manifest)),
+                Err(e) => log::warn!("Skipping backup {:?}: {}", dir.join(&id), e),
+            }
+        }
+        backups.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| b.id.cmp(&a.id)));
+        Ok(backups)
+    }
+
+    /// Deletes the scheduled snapshots of a notebook the retention policy doesn't keep and
+    /// snapshots left incomplete, returning the ids of those deleted
+    pub fn prune(&self, name: &str, policy: RetentionPolicy) -> Result<Vec<String>, NotebookError> {
+        let scheduled: Vec<(String, i64)> = self
+            .list(name)?
+            .into_iter()
+            .filter(|backup| backup.kind == BackupKind::Scheduled)
+            .map(|backup| (backup.id, backup.created))
+            .collect();
+        let keep = policy.keep(&scheduled);
+        let mut deleted = Vec::new();
+        for (id, _) in scheduled {
+            if !keep.contains(&id) {
+                fs::remove_dir_all(self.notebook_dir(name).join(&id)).map_err(file_error)?;
+                deleted.push(id);
+            }
+        }
+        // prune runs after a snapshot is complete, any partial one was interrupted
+        for entry in fs::read_dir(self.notebook_dir(name)).map_err(file_error)? {
+            let path = entry.map_err(file_error)?.path();
+            if path.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                fs::remove_dir_all(&path).map_err(file_error)?;
+            }
+        }
+        if !deleted.is_empty() {
+            info!("Pruned [{}] backups of notebook '{}'", deleted.len(), name);
+        }
+        Ok(deleted)
+    }
+
+    pub fn delete(&self, name: &str, id: &str) -> Result<(), NotebookError> {
+        self.read_manifest(name, id)?;
+        info!("Deleting backup {} of notebook '{}'", id, name);
+        fs::remove_dir_all(self.notebook_dir(name).join(id)).map_err(file_error)
+    }
+
+    /// Deletes the snapshots taken before a notebook was encrypted, as they hold its notes in
+    /// the clear. Returns how many were deleted.
+    pub fn delete_plain(&self, name: &str) -> Result<usize, NotebookError> {
+        let plain: Vec<BackupInfo> = self
+            .list(name)?
+            .into_iter()
+            .filter(|backup| !backup.encrypted)
+            .collect();
+        for backup in &plain {
+            self.delete(name, &backup.id)?;
+        }
+        Ok(plain.len())
+    }
+
+    /// Checks a snapshot is complete and can be opened with `key`, the key of the notebook it
+    /// would replace
+    pub fn validate(
+        &self,
+        name: &str,
+        id: &str,
+        key: Option<&NotebookKey>,
+    ) -> Result<BackupInfo, NotebookError> {
+        let manifest = self.read_manifest(name, id)?;
+        match (manifest.encrypted, key.is_some()) {
+            (true, false) => {
+                return Err(backup_error(format!(
+                    "Backup '{}' is encrypted but notebook '{}' is not",
+                    id, name
+                )))
+            }
+            (false, true) => {
+                return Err(backup_error(format!(
+                    "Backup '{}' was taken before notebook '{}' was encrypted",
+                    id, name
+                )))
+            }
+            _ => {}
+        }
+        let dir = self.notebook_dir(name).join(id);
+        for file in &manifest.files {
+            let path = dir.join(&file.path);
+            let size = fs::metadata(&path).map(|metadata| metadata.len());
+            if size.as_ref().ok() != Some(&file.size) {
+                return Err(backup_error(format!(
+                    "Backup '{}' is missing or has a truncated {}",
+                    id, file.path
+                )));
+            }
+            if file
+                .sha256
+                .as_ref()
+                .is_some_and(|sha256| *sha256 != sha256_file(&path).unwrap_or_default())
+            {
+                return Err(backup_error(format!(
+                    "Backup '{}' has a corrupted {}",
+                    id, file.path
+                )));
+            }
+        }
+        Notebook::verify_snapshot(&dir, key)?;
+        Ok(BackupInfo::new(id, &manifest))
+    }
+
+    /// Replaces the notebook in `notebook_dir`, which must not be open, with a validated
+    /// snapshot, and the settings file with the one in the snapshot
+    pub fn restore(
+        &self,
+        name: &str,
+        id: &str,
+        notebook_dir: &Path,
+        settings_file: Option<&Path>,
+    ) -> Result<(), NotebookError> {
+        let manifest = self.read_manifest(name, id)?;
+        let snapshot = self.notebook_dir(name).join(id);
+        let staging = notebook_dir.join(STAGING_DIR);
+        if staging.exists() {
+            finish_restore(notebook_dir)?;
+        }
+        fs::create_dir_all(&staging).map_err(file_error)?;
+        for entry in fs::read_dir(&snapshot).map_err(file_error)? {
+            let entry = entry.map_err(file_error)?;
+            let file_name = entry.file_name();
+            if file_name == MANIFEST_FILE || file_name == SETTINGS_DIR {
+                continue;
+            }
+            let target = staging.join(&file_name);
+            if entry.path().is_dir() {
+                // attachment blobs are never modified in place, embeddings tables may be
+                let link = file_name == crate::notebook::ATTACHMENTS_DIR;
+                copy_dir(&entry.path(), &target, link)
+            } else {
+                fs::copy(entry.path(), &target).map(|_| ())
+            }
+            .map_err(|e| {
+                let _ = fs::remove_dir_all(&staging);
+                file_error(e)
+            })?;
+        }
+        fs::write(staging.join(STAGED_MARKER), id).map_err(file_error)?;
+        finish_restore(notebook_dir)?;
+        info!("Restored notebook '{}' from backup {}", name, id);
+
+        let settings_backup = manifest
+            .files
+            .iter()
+            .find(|file| file.path.starts_with(SETTINGS_DIR))
+            .map(|file| snapshot.join(&file.path));
+        if let (Some(settings_backup), Some(settings_file)) = (settings_backup, settings_file) {
+            // replaced in one step, the app keeps using it
+            let tmp = settings_file.with_extension("restoring");
+            fs::copy(&settings_backup, &tmp)
+                .and_then(|_| fs::rename(&tmp, settings_file))
+                .map_err(file_error)?;
+            info!("Restored the settings from backup {}", id);
+        }
+        Ok(())
+    }
+}
+
+/// Completes a restore into `notebook_dir` that was interrupted, or cleans up after one that
+/// was interrupted before the notebook was touched. Does nothing when there is nothing to do.
+pub fn finish_restore(notebook_dir: &Path) -> Result<(), NotebookError> {
+    let staging = notebook_dir.join(STAGING_DIR);
+    let replaced = notebook_dir.join(REPLACED_DIR);
+    if staging.exists() && !staging.join(STAGED_MARKER).exists() {
+        info!("Discarding a restore interrupted while copying the backup");
+        fs::remove_dir_all(&staging).map_err(file_error)?;
+    }
+    if staging.exists() {
+        if !replaced.join(REPLACED_MARKER).exists() {
+            fs::create_dir_all(&replaced).map_err(file_error)?;
+            for path in Notebook::files(notebook_dir)? {
+                let file_name = path.file_name().expect("notebook files have a name");
+                fs::rename(&path, replaced.join(file_name)).map_err(file_error)?;
+            }
+            fs::write(replaced.join(REPLACED_MARKER), "").map_err(file_error)?;
+        }
+        for entry in fs::read_dir(&staging).map_err(file_error)? {
+            let entry = entry.map_err(file_error)?;
+            if entry.file_name() != STAGED_MARKER {
+                fs::rename(entry.path(), notebook_dir.join(entry.file_name()))
+                    .map_err(file_error)?;
+            }
+        }
+        fs::remove_dir_all(&staging).map_err(file_error)?;
+    }
+    if replaced.exists() {
+        fs::remove_dir_all(&replaced).map_err(file_error)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::notebook::embeddings::EmbeddingModelChoice;
+    use crate::notebook::encryption;
+    use crate::notebook::in_memory_store::InMemoryVectorStore;
+    use crate::notebook::open_test_notebook;
+    use crate::notebook::vector_store::EngineStatus;
+
+    #[test]
+    fn test_retention_policy() {
+        let hour = SECS_PER_HOUR;
+        // a Monday at midnight
+        let monday = 19_723 * SECS_PER_DAY;
+        let snapshots: Vec<(String, i64)> = [
+            ("now", monday + 30 * hour + 50 * 60),
+            ("same hour", monday + 30 * hour + 10 * 60),
+            ("hour before", monday + 29 * hour),
+            ("day before", monday + 5 * hour),
+            ("previous week", monday - hour),
+            ("two weeks ago", monday - 8 * SECS_PER_DAY),
+        ]
+        .into_iter()
+        .map(|(id, created)| (id.to_string(), created))
+        .collect();
+        let keep = |hourly, daily, weekly| {
+            let mut kept: Vec<String> = RetentionPolicy {
+                hourly,
+                daily,
+                weekly,
+            }
+            .keep(&snapshots)
+            .into_iter()
+            .collect();
+            kept.sort();
+            kept
+        };
+        assert_eq!(vec!["hour before", "now"], keep(2, 0, 0));
+        assert_eq!(vec!["day before", "now"], keep(1, 2, 0));
+        assert_eq!(vec!["now", "previous week", "two weeks ago"], keep(1, 0, 3));
+        assert!(keep(0, 0, 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let temp = tempfile::tempdir().unwrap();
+        let app_dir = temp.path();
+        let notebook_dir = app_dir.join("notebook");
+        fs::create_dir_all(&notebook_dir).unwrap();
+        let settings_file = app_dir.join("settings.json");
+        fs::write(&settings_file, r#"{"theme":"dark"}"#).unwrap();
+        let attachment = app_dir.join("diagram.txt");
+        fs::write(&attachment, "boxes and arrows").unwrap();
+        let backups = Backups::new(app_dir);
+
+        let notebook = open_test_notebook(&notebook_dir).await;
+        let kept = notebook
+            .upsert_note(None, "# Kept\nin the backup")
+            .await
+            .unwrap();
+        notebook
+            .add_attachment(kept.get_id(), &attachment)
+            .await
+            .unwrap();
+        let backup = backups
+            .create(
+                &notebook,
+                "notebook",
+                BackupKind::Manual,
+                Some(&settings_file),
+            )
+            .await
+            .unwrap();
+        assert!(backup.includes_settings);
+        let later = notebook
+            .upsert_note(None, "# Later\nafter the backup")
+            .await
+            .unwrap();
+        notebook.delete_note(kept.get_id()).await.unwrap();
+        fs::write(&settings_file, r#"{"theme":"light"}"#).unwrap();
+
+        let listed: Vec<String> = backups
+            .list("notebook")
+            .unwrap()
+            .into_iter()
+            .map(|b| b.id)
+            .collect();
+        assert_eq!(vec![backup.id.clone()], listed);
+        backups.validate("notebook", &backup.id, None).unwrap();
+        notebook.close().await;
+        drop(notebook);
+        backups
+            .restore("notebook", &backup.id, &notebook_dir, Some(&settings_file))
+            .unwrap();
+
+        let notebook = open_test_notebook(&notebook_dir).await;
+        assert!(notebook
+            .get_note_by_id(kept.get_id())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(notebook
+            .get_note_by_id(later.get_id())
+            .await
+            .unwrap()
+            .is_none());
+        let attachments = notebook.list_attachments(kept.get_id()).await.unwrap();
+        let opened = notebook
+            .get_attachment_for_opening(&attachments[0].id)
+            .await
+            .unwrap();
+        assert_eq!("boxes and arrows", fs::read_to_string(opened).unwrap());
+        assert_eq!(
+            r#"{"theme":"dark"}"#,
+            fs::read_to_string(&settings_file).unwrap()
+        );
+        assert!(!notebook_dir.join(STAGING_DIR).exists());
+        assert!(!notebook_dir.join(REPLACED_DIR).exists());
+        notebook.close().await;
+        drop(notebook);
+
+        // a damaged snapshot is refused
+        let db_backup = backups
+            .notebook_dir("notebook")
+            .join(&backup.id)
+            .join("db.sqlite");
+        let mut db = fs::read(&db_backup).unwrap();
+        let last = db.len() - 1;
+        db[last] ^= 0xff;
+        fs::write(&db_backup, db).unwrap();
+        assert!(matches!(
+            backups.validate("notebook", &backup.id, None),
+            Err(NotebookError::Backup(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_backup_encrypted_notebook() {
+        let temp = tempfile::tempdir().unwrap();
+        let app_dir = temp.path();
+        let notebook_dir = app_dir.join("secret");
+        fs::create_dir_all(&notebook_dir).unwrap();
+        let backups = Backups::new(app_dir);
+        let notebook = open_test_notebook(&notebook_dir).await;
+        notebook.upsert_note(None, "# Plan\nCoyote").await.unwrap();
+        let plain = backups
+            .create(&notebook, "secret", BackupKind::Manual, None)
+            .await
+            .unwrap();
+        notebook.close().await;
+        drop(notebook);
+
+        let key = encryption::encrypt_notebook(&notebook_dir, "correct horse battery")
+            .await
+            .unwrap();
+        assert_eq!(1, backups.delete_plain("secret").unwrap());
+        assert!(backups.validate("secret", &plain.id, Some(&key)).is_err());
+        let notebook = Notebook::with_vector_store(
+            EmbeddingModelChoice::default(),
+            Arc::new(InMemoryVectorStore::default()),
+            Arc::new(RwLock::new(EngineStatus::Ready)),
+            &notebook_dir,
+            Some(key.clone()),
+        )
+        .await
+        .unwrap();
+        let backup = backups
+            .create(&notebook, "secret", BackupKind::Manual, None)
+            .await
+            .unwrap();
+        assert!(backup.encrypted);
+        backups.validate("secret", &backup.id, Some(&key)).unwrap();
+        assert!(backups.validate("secret", &backup.id, None).is_err());
+        let db = fs::read(
+            backups
+                .notebook_dir("secret")
+                .join(&backup.id)
+                .join("db.sqlite"),
+        )
+        .unwrap();
+        assert!(!db.windows(6).any(|window| window == b"Coyote"));
+        notebook.close().await;
+        drop(notebook);
+    }
+
+    #[test]
+    fn test_finish_interrupted_restore() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let staging = dir.join(STAGING_DIR);
+        fs::create_dir_all(&staging).unwrap();
+        fs::write(dir.join("db.sqlite"), "current").unwrap();
+        fs::write(dir.join("notebooks.json"), "not part of the notebook").unwrap();
+        fs::write(staging.join("db.sqlite"), "restored").unwrap();
+
+        // interrupted while copying the backup, the notebook is left as it was
+        finish_restore(dir).unwrap();
+        assert_eq!(
+            "current",
+            fs::read_to_string(dir.join("db.sqlite")).unwrap()
+        );
+        assert!(!staging.exists());
+
+        // interrupted while swapping, the restore is finished
+        fs::create_dir_all(&staging).unwrap();
+        fs::write(staging.join("db.sqlite"), "restored").unwrap();
+        fs::write(staging.join(STAGED_MARKER), "id").unwrap();
+        finish_restore(dir).unwrap();
+        assert_eq!(
+            "restored",
+            fs::read_to_string(dir.join("db.sqlite")).unwrap()
+        );
+        assert_eq!(
+            "not part of the notebook",
+            fs::read_to_string(dir.join("notebooks.json")).unwrap()
+        );
+        assert!(!staging.exists());
+        assert!(!dir.join(REPLACED_DIR).exists());
+    }
+}
@@ -1,15 +1,48 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use log::info;
+use chrono::Utc;
+use log::{error, info};
 use serde_json::json;
 use tauri::api::path::download_dir;
-use tauri::State;
+use tauri::{Manager, State};
 use tauri_plugin_store::StoreBuilder;
+use tokio::sync::RwLockReadGuard;
 
 use crate::AppState;
+use crate::backups::{BackupInfo, BackupKind};
 use crate::llm::llm_request;
-use crate::notebook::note::Note;
-use crate::notebook::NotebookError;
+use crate::notebook::note::{
+    Category, CategoryNode, CategorySummary, HybridHit, Note, NoteFlag, NoteRevision, SearchHit,
+    SimilarNote, TrashedNote,
+};
+use crate::notebook::{Notebook, NotebookError};
+use crate::notebook::attachments::Attachment;
+use crate::notebook::encryption::check_passphrase;
+use crate::notebook::embeddings::{
+    EmbeddingCounts, EmbeddingModelChoice, EmbeddingModelInfo, EmbeddingStatus, IndexReport,
+    RebuildProgress, SimilarityResults,
+};
+use crate::notebook::links::NoteLink;
+use crate::notebook::listing::{ArchiveFilter, NoteListQuery, NotePage};
+use crate::notebook::model_cache::{CachedModel, ModelCache, ModelCheck, MODEL_DOWNLOAD_EVENT};
+use crate::notebook::revisions::{DiffChange, DiffMode};
+use crate::notebook::search::RankFusionWeights;
+use crate::notebook::transfer::{self, ImportReport};
+use crate::settings::{
+    auto_lock_timeout, backup_interval, backup_retention, settings_file, trash_retention_days,
+};
+use crate::workspaces::{LockState, NotebookInfo, DEFAULT_NOTEBOOK};
+
+/// Emitted with the ids of notes whose vectors have just been written
+pub const NOTES_EMBEDDED_EVENT: &str = "notes-embedded";
+/// Emitted with the name of a notebook auto-locked after a while without use
+pub const NOTEBOOK_LOCKED_EVENT: &str = "notebook-locked";
+const AUTO_LOCK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// notes written per hold of the notebook during an import
+const IMPORT_BATCH_SIZE: usize = 50;
+const BACKUP_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 const SYS_PROMPT_CONSIDER_NOTE: &str = r#"You are a personal assistant. You advise on notes
  presented to you. Notes presented to you are created by the user.  In your answers to strive
@@ -23,8 +56,12 @@ pub async fn save_note_text(
     id: Option<&str>,
     text: &str,
 ) -> Result<Note, String> {
-    info!("Saving note: '{}'", text);
-    let mut notebook = notebook.notebook.lock().await;
+    // note text never goes to the log, it would defeat encryption at rest
+    info!("Saving note {:?} ({} bytes)", id, text.len());
+    let notebook = notebook
+        .unlocked_notebook()
+        .await
+        .map_err(|e| e.to_string())?;
     match notebook.upsert_note(id, text).await {
         Ok(note) => {
             info!("Note[{}] saved", note.get_id());
@@ -39,8 +76,14 @@ pub async fn delete_all_notes(
     notebook: State<'_, AppState>,
 ) -> Result<(), String> {
     info!("Deleting all notes");
-    let notebook = notebook.notebook.lock().await;
-    notebook.delete_all_notes().await.map_err(|e| e.to_string())?;
+    let notebook = notebook
+        .unlocked_notebook()
+        .await
+        .map_err(|e| e.to_string())?;
+    notebook
+        .delete_all_notes()
+        .await
+        .map_err(|e| e.to_string())?;
     Ok(())
 }
 
@@ -50,9 +93,18 @@ pub async fn add_category_to_note(
     note_id: &str,
     category_label: &str,
 ) -> Result<Note, String> {
-    info!("Adding category: '{}' to note [{}]", category_label, note_id);
-    let notebook = notebook.notebook.lock().await;
-    let note = notebook.add_category_to_note(note_id, category_label).await.map_err(|e| e.to_string())?;
+    info!(
+        "Adding category: '{}' to note [{}]",
+        category_label, note_id
+    );
+    let notebook = notebook
+        .unlocked_notebook()
+        .await
+        .map_err(|e| e.to_string())?;
+    let note = notebook
+        .add_category_to_note(note_id, category_label)
+        .await
+        .map_err(|e| e.to_string())?;
     Ok(note)
 }
 
@@ -63,8 +115,14 @@ pub async fn remove_category_from_note(
     category_id: &str,
 ) -> Result<Note, String> {
     info!("Removing category: '{}' to note [{}]", category_id, note_id);
-    let notebook = notebook.notebook.lock().await;
-    let note = notebook.remove_category_from_note(note_id, category_id).await.map_err(|e| e.to_string())?;
+    let notebook = notebook
+        .unlocked_notebook()
+        .await
+        .map_err(|e| e.to_string())?;
+    let note = notebook
+        .remove_category_from_note(note_id, category_id)
+        .await
+        .map_err(|e| e.to_string())?;
     Ok(note)
 }
 
@@ -73,8 +131,15 @@ pub async fn remove_category_from_note(
 pub async fn prompt_about_note(notebook: State<'_, AppState>, app_handle: tauri::AppHandle,
                                prompt: &str, note_id: &str) -> Result<String, String> {
     info!("running command prompt_about_note");
-    let notebook = notebook.notebook.lock().await;
-    match notebook.get_note_by_id(note_id).await {
+    // the note is all the request needs, the notebook is released before calling the LLM
+    let note = {
+        let notebook = notebook
+            .unlocked_notebook()
+            .await
+            .map_err(|e| e.to_string())?;
+        notebook.get_note_by_id(note_id).await
+    };
+    match note {
         Ok(note) => {
             let mut store = StoreBuilder::new(app_handle, PathBuf::from("settings.json")).build();
             let _ = store.load();
@@ -114,20 +179,41 @@ pub async fn prompt_about_note(notebook: State<'_, AppState>, app_handle: tauri:
 
 
 #[tauri::command]
-pub async fn get_notes(notebook: State<'_, AppState>) -> Result<Vec<Note>, NotebookError> {
-    let notebook = notebook.notebook.lock().await;
-    let notes = notebook.get_notes().await?;
+pub async fn get_notes(
+    notebook: State<'_, AppState>,
+    archived: Option<ArchiveFilter>,
+) -> Result<Vec<Note>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    let notes = notebook.get_notes(archived.unwrap_or_default()).await?;
     info!("Found [{}] existing notes", notes.len());
     Ok(notes)
 }
 
+#[tauri::command]
+pub async fn list_notes(
+    notebook: State<'_, AppState>,
+    query: Option<NoteListQuery>,
+) -> Result<NotePage, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    let page = notebook.list_notes(&query.unwrap_or_default()).await?;
+    info!("Listed [{}] of [{}] notes", page.notes.len(), page.total);
+    Ok(page)
+}
+
 #[tauri::command]
 pub async fn export_notes(notebook: State<'_, AppState>) -> Result<(usize, String), NotebookError> {
-    let notebook = notebook.notebook.lock().await;
     let target_dir = download_dir().ok_or(NotebookError::FileAccess(
         "Failed to resolve path to downloads directory".to_string(),
     ))?;
-    let export_result = notebook.export_notes(target_dir.clone()).await?;
+    // the files are written from a snapshot, so the notebook stays usable during the export
+    let export = notebook
+        .unlocked_notebook()
+        .await?
+        .export_snapshot()
+        .await?;
+    let export_result = tauri::async_runtime::spawn_blocking(move || export.write_to(&target_dir))
+        .await
+        .map_err(|e| NotebookError::FileAccess(e.to_string()))??;
     info!(
         "Exported [{}] existing notes to {}",
         export_result.0,
@@ -140,12 +226,30 @@ pub async fn export_notes(notebook: State<'_, AppState>) -> Result<(usize, Strin
 pub async fn import_notes(
     notebook: State<'_, AppState>,
     path: &str,
-) -> Result<usize, NotebookError> {
+) -> Result<ImportReport, NotebookError> {
     info!("Attempting import of notes from: {}", path);
-    let notebook = notebook.notebook.lock().await;
-    let num_imports = notebook.import_notes(&PathBuf::from(path)).await?;
-    info!("Imported [{}] notes from {}", num_imports, path);
-    Ok(num_imports)
+    let import_path = PathBuf::from(path);
+    let notes = tauri::async_runtime::spawn_blocking(move || transfer::read_import(&import_path))
+        .await
+        .map_err(|e| NotebookError::FileAccess(e.to_string()))??;
+    // the notebook is only held while a batch is written, so it stays usable during the import
+    let dir = notebook.unlocked_notebook().await?.dir().to_path_buf();
+    let mut report = ImportReport::default();
+    for batch in notes.chunks(IMPORT_BATCH_SIZE) {
+        report.merge(
+            notebook_at(&notebook, &dir)
+                .await?
+                .add_imported_notes(batch)
+                .await,
+        );
+    }
+    info!(
+        "Imported [{}] notes from {}, [{}] files failed",
+        report.imported,
+        path,
+        report.failed.len()
+    );
+    Ok(report)
 }
 
 #[tauri::command]
@@ -153,7 +257,7 @@ pub async fn get_note_by_id(
     notebook: State<'_, AppState>,
     id: &str,
 ) -> Result<Option<Note>, NotebookError> {
-    let notebook = notebook.notebook.lock().await;
+    let notebook = notebook.unlocked_notebook().await?;
     match notebook.get_note_by_id(id).await {
         Ok(note) => Ok(note),
         Err(e) => {
@@ -163,9 +267,32 @@ pub async fn get_note_by_id(
     }
 }
 
+#[tauri::command]
+pub async fn set_note_flag(
+    notebook: State<'_, AppState>,
+    id: &str,
+    flag: NoteFlag,
+    value: bool,
+) -> Result<Note, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.set_note_flag(id, flag, value).await
+}
+
+#[tauri::command]
+pub async fn set_notes_flag(
+    notebook: State<'_, AppState>,
+    ids: Vec<String>,
+    flag: NoteFlag,
+    value: bool,
+) -> Result<usize, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+    notebook.set_notes_flag(&ids, flag, value).await
+}
+
 #[tauri::command]
 pub async fn delete_note(notebook: State<'_, AppState>, id: &str) -> Result<(), NotebookError> {
-    let mut notebook = notebook.notebook.lock().await;
+    let notebook = notebook.unlocked_notebook().await?;
     notebook.delete_note(id).await
 }
 
@@ -174,8 +301,8 @@ pub async fn get_note_similarities(
     notebook: State<'_, AppState>,
     id: &str,
     threshold: f32,
-) -> Result<Vec<(Note, f32)>, NotebookError> {
-    let notebook = notebook.notebook.lock().await;
+) -> Result<SimilarityResults<SimilarNote>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
     let note = notebook.get_note_by_id(id).await?;
     match note {
         Some(note) => {
@@ -189,3 +316,856 @@ pub async fn get_note_similarities(
         ))),
     }
 }
+
+#[tauri::command]
+pub async fn search_notes(
+    notebook: State<'_, AppState>,
+    query: &str,
+    limit: Option<usize>,
+    archived: Option<ArchiveFilter>,
+) -> Result<Vec<SearchHit>, NotebookError> {
+    info!(
+        "Searching notes for a {} character query",
+        query.chars().count()
+    );
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook
+        .search_notes(query, limit, archived.unwrap_or_default())
+        .await
+}
+
+#[tauri::command]
+pub async fn hybrid_search(
+    notebook: State<'_, AppState>,
+    query: &str,
+    limit: Option<usize>,
+    weights: Option<RankFusionWeights>,
+    archived: Option<ArchiveFilter>,
+) -> Result<SimilarityResults<HybridHit>, NotebookError> {
+    info!(
+        "Hybrid search for a {} character query",
+        query.chars().count()
+    );
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook
+        .hybrid_search(query, limit, weights, archived.unwrap_or_default())
+        .await
+}
+
+#[tauri::command]
+pub async fn list_note_revisions(
+    notebook: State<'_, AppState>,
+    note_id: &str,
+) -> Result<Vec<NoteRevision>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.list_note_revisions(note_id).await
+}
+
+#[tauri::command]
+pub async fn get_note_revision(
+    notebook: State<'_, AppState>,
+    revision_id: i64,
+) -> Result<NoteRevision, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.get_note_revision(revision_id).await
+}
+
+#[tauri::command]
+pub async fn diff_note_revisions(
+    notebook: State<'_, AppState>,
+    from_revision_id: i64,
+    to_revision_id: Option<i64>,
+    mode: Option<DiffMode>,
+) -> Result<Vec<DiffChange>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook
+        .diff_note_revisions(from_revision_id, to_revision_id, mode.unwrap_or_default())
+        .await
+}
+
+#[tauri::command]
+pub async fn restore_note_revision(
+    notebook: State<'_, AppState>,
+    revision_id: i64,
+) -> Result<Note, NotebookError> {
+    info!("Restoring revision: {}", revision_id);
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.restore_note_revision(revision_id).await
+}
+
+#[tauri::command]
+pub async fn list_trash(notebook: State<'_, AppState>) -> Result<Vec<TrashedNote>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    let trashed = notebook.list_trash().await?;
+    info!("Found [{}] notes in the trash", trashed.len());
+    Ok(trashed)
+}
+
+#[tauri::command]
+pub async fn restore_note(notebook: State<'_, AppState>, id: &str) -> Result<Note, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.restore_note(id).await
+}
+
+#[tauri::command]
+pub async fn empty_trash(notebook: State<'_, AppState>) -> Result<usize, NotebookError> {
+    info!("Emptying the trash");
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.empty_trash().await
+}
+
+#[tauri::command]
+pub async fn list_categories(
+    notebook: State<'_, AppState>,
+) -> Result<Vec<CategorySummary>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.list_categories().await
+}
+
+#[tauri::command]
+pub async fn rename_category(
+    notebook: State<'_, AppState>,
+    category_id: &str,
+    label: &str,
+) -> Result<Category, NotebookError> {
+    info!("Renaming category [{}] to '{}'", category_id, label);
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.rename_category(category_id, label).await
+}
+
+#[tauri::command]
+pub async fn merge_categories(
+    notebook: State<'_, AppState>,
+    source_id: &str,
+    target_id: &str,
+) -> Result<Category, NotebookError> {
+    info!("Merging category [{}] into [{}]", source_id, target_id);
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.merge_categories(source_id, target_id).await
+}
+
+#[tauri::command]
+pub async fn delete_category(
+    notebook: State<'_, AppState>,
+    category_id: &str,
+) -> Result<(), NotebookError> {
+    info!("Deleting category [{}]", category_id);
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.delete_category(category_id).await
+}
+
+#[tauri::command]
+pub async fn get_category_tree(
+    notebook: State<'_, AppState>,
+) -> Result<Vec<CategoryNode>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.get_category_tree().await
+}
+
+#[tauri::command]
+pub async fn get_notes_in_category(
+    notebook: State<'_, AppState>,
+    category_id: &str,
+) -> Result<Vec<Note>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    let notes = notebook.get_notes_in_category(category_id).await?;
+    info!(
+        "Found [{}] notes in category [{}]",
+        notes.len(),
+        category_id
+    );
+    Ok(notes)
+}
+
+#[tauri::command]
+pub async fn move_category(
+    notebook: State<'_, AppState>,
+    category_id: &str,
+    parent_id: Option<&str>,
+) -> Result<Category, NotebookError> {
+    info!("Moving category [{}] under {:?}", category_id, parent_id);
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.move_category(category_id, parent_id).await
+}
+
+#[tauri::command]
+pub async fn get_outgoing_links(
+    notebook: State<'_, AppState>,
+    note_id: &str,
+) -> Result<Vec<NoteLink>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.get_outgoing_links(note_id).await
+}
+
+#[tauri::command]
+pub async fn get_backlinks(
+    notebook: State<'_, AppState>,
+    note_id: &str,
+) -> Result<Vec<NoteLink>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    let backlinks = notebook.get_backlinks(note_id).await?;
+    info!(
+        "Found [{}] backlinks to note [{}]",
+        backlinks.len(),
+        note_id
+    );
+    Ok(backlinks)
+}
+
+#[tauri::command]
+pub async fn get_dangling_links(
+    notebook: State<'_, AppState>,
+) -> Result<Vec<NoteLink>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.get_dangling_links().await
+}
+
+#[tauri::command]
+pub async fn add_attachment(
+    notebook: State<'_, AppState>,
+    note_id: &str,
+    path: &str,
+) -> Result<Attachment, NotebookError> {
+    info!("Attaching {} to note [{}]", path, note_id);
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.add_attachment(note_id, &PathBuf::from(path)).await
+}
+
+#[tauri::command]
+pub async fn list_attachments(
+    notebook: State<'_, AppState>,
+    note_id: &str,
+) -> Result<Vec<Attachment>, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.list_attachments(note_id).await
+}
+
+#[tauri::command]
+pub async fn remove_attachment(
+    notebook: State<'_, AppState>,
+    attachment_id: &str,
+) -> Result<(), NotebookError> {
+    info!("Removing attachment [{}]", attachment_id);
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.remove_attachment(attachment_id).await
+}
+
+/// Opens an attachment with the OS default app for its file type
+#[tauri::command]
+pub async fn open_attachment(
+    notebook: State<'_, AppState>,
+    attachment_id: &str,
+) -> Result<(), NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    let path = notebook.get_attachment_for_opening(attachment_id).await?;
+    info!("Opening attachment [{}] from {:?}", attachment_id, path);
+    open::that(&path).map_err(|e| NotebookError::FileAccess(e.to_string()))
+}
+
+#[tauri::command]
+pub async fn list_notebooks(
+    notebook: State<'_, AppState>,
+) -> Result<Vec<NotebookInfo>, NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    workspaces.list()
+}
+
+#[tauri::command]
+pub async fn create_notebook(
+    notebook: State<'_, AppState>,
+    name: &str,
+) -> Result<NotebookInfo, NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    workspaces.create(name)
+}
+
+/// Switches to another notebook, all other commands then operate on it
+#[tauri::command]
+pub async fn open_notebook(
+    notebook: State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+    name: &str,
+) -> Result<NotebookInfo, NotebookError> {
+    switch_notebook(&notebook, app_handle, name).await
+}
+
+/// Closes the active notebook and goes back to the default notebook
+#[tauri::command]
+pub async fn close_notebook(
+    notebook: State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<NotebookInfo, NotebookError> {
+    switch_notebook(&notebook, app_handle, DEFAULT_NOTEBOOK).await
+}
+
+async fn switch_notebook(
+    app_state: &AppState,
+    app_handle: tauri::AppHandle,
+    name: &str,
+) -> Result<NotebookInfo, NotebookError> {
+    // lock order: workspaces then notebook, so no command sees a half switched state
+    let mut workspaces = app_state.workspaces.lock().await;
+    let mut notebook = app_state.notebook.write().await;
+    if workspaces.active() != name {
+        info!(
+            "Switching from notebook '{}' to '{}'",
+            workspaces.active(),
+            name
+        );
+        // only replace the open notebook once the new one has loaded, an encrypted one stays
+        // locked until it is unlocked
+        let opened = match workspaces.open(name).await {
+            Ok(opened) => Some(opened),
+            Err(NotebookError::NotebookLocked(_)) => None,
+            Err(e) => return Err(e),
+        };
+        workspaces.set_active(name)?;
+        if let Some(previous) = notebook.take() {
+            previous.close().await;
+        }
+        if let Some(opened) = &opened {
+            spawn_embedding_worker(opened, app_handle);
+        }
+        *notebook = opened;
+    }
+    workspaces
+        .list()?
+        .into_iter()
+        .find(|info| info.active)
+        .ok_or(NotebookError::Workspace(format!(
+            "No notebook named '{}'",
+            name
+        )))
+}
+
+#[tauri::command]
+pub async fn get_lock_state(notebook: State<'_, AppState>) -> Result<LockState, NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    let unlocked = notebook.notebook.read().await.is_some();
+    Ok(workspaces.lock_state(unlocked))
+}
+
+/// Opens the active notebook when it is encrypted and locked
+#[tauri::command]
+pub async fn unlock_notebook(
+    notebook: State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+    passphrase: &str,
+) -> Result<LockState, NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    let mut active_notebook = notebook.notebook.write().await;
+    if active_notebook.is_none() {
+        let unlocked = workspaces.unlock(workspaces.active(), passphrase).await?;
+        info!("Unlocked notebook '{}'", workspaces.active());
+        spawn_embedding_worker(&unlocked, app_handle.clone());
+        purge_expired_trash(&unlocked, app_handle).await;
+        *active_notebook = Some(unlocked);
+        *notebook.last_activity.lock().unwrap() = Instant::now();
+    }
+    Ok(workspaces.lock_state(true))
+}
+
+/// Closes the active notebook, which must be encrypted, dropping its key until it is unlocked
+#[tauri::command]
+pub async fn lock_notebook(notebook: State<'_, AppState>) -> Result<LockState, NotebookError> {
+    lock_active_notebook(&notebook).await?;
+    let workspaces = notebook.workspaces.lock().await;
+    Ok(workspaces.lock_state(false))
+}
+
+/// Returns whether an open notebook was locked
+async fn lock_active_notebook(app_state: &AppState) -> Result<bool, NotebookError> {
+    let workspaces = app_state.workspaces.lock().await;
+    let mut notebook = app_state.notebook.write().await;
+    if !workspaces.is_encrypted(workspaces.active()) {
+        return Err(NotebookError::Encryption(format!(
+            "Notebook '{}' is not encrypted, it can't be locked",
+            workspaces.active()
+        )));
+    }
+    match notebook.take() {
+        Some(open) => {
+            open.close().await;
+            info!("Locked notebook '{}'", workspaces.active());
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Encrypts the active notebook with a passphrase, it stays unlocked afterwards
+#[tauri::command]
+pub async fn encrypt_notebook(
+    notebook: State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+    passphrase: &str,
+) -> Result<LockState, NotebookError> {
+    check_passphrase(passphrase)?;
+    let workspaces = notebook.workspaces.lock().await;
+    let mut active_notebook = notebook.notebook.write().await;
+    let active = workspaces.active().to_string();
+    if workspaces.is_encrypted(&active) {
+        return Err(NotebookError::Encryption(format!(
+            "Notebook '{}' is already encrypted",
+            active
+        )));
+    }
+    // the models db is rewritten, nothing may hold it open
+    if let Some(open) = active_notebook.take() {
+        open.close().await;
+    }
+    match workspaces.encrypt(&active, passphrase).await {
+        Ok(encrypted) => {
+            spawn_embedding_worker(&encrypted, app_handle);
+            *active_notebook = Some(encrypted);
+            Ok(workspaces.lock_state(true))
+        }
+        Err(e) => {
+            error!("Unable to encrypt notebook '{}': {}", active, e);
+            // once its key is written the notebook is encrypted and can be unlocked
+            match workspaces.open(&active).await {
+                Ok(reopened) => {
+                    spawn_embedding_worker(&reopened, app_handle);
+                    *active_notebook = Some(reopened);
+                }
+                Err(NotebookError::NotebookLocked(_)) => {}
+                Err(e) => error!("Unable to open notebook '{}' again: {}", active, e),
+            }
+            Err(e)
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn change_notebook_passphrase(
+    notebook: State<'_, AppState>,
+    current: &str,
+    new: &str,
+) -> Result<(), NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    workspaces.change_passphrase(workspaces.active(), current, new)
+}
+
+/// Locks an encrypted notebook once it has not been used for the auto-lock timeout, emitting
+/// `notebook-locked`. Runs as long as the app does.
+pub async fn auto_lock(app_state: AppState, app_handle: tauri::AppHandle) {
+    loop {
+        tokio::time::sleep(AUTO_LOCK_CHECK_INTERVAL).await;
+        let Some(timeout) = auto_lock_timeout(app_handle.clone()) else {
+            continue;
+        };
+        if app_state.last_activity.lock().unwrap().elapsed() < timeout {
+            continue;
+        }
+        let (active, encrypted) = {
+            let workspaces = app_state.workspaces.lock().await;
+            let active = workspaces.active().to_string();
+            let encrypted = workspaces.is_encrypted(&active);
+            (active, encrypted)
+        };
+        if !encrypted {
+            continue;
+        }
+        match lock_active_notebook(&app_state).await {
+            Ok(true) => {
+                if let Err(e) = app_handle.emit_all(NOTEBOOK_LOCKED_EVENT, &active) {
+                    error!("Failed to emit {}: {}", NOTEBOOK_LOCKED_EVENT, e);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => error!("Failed to lock notebook '{}': {}", active, e),
+        }
+    }
+}
+
+/// The snapshots of the active notebook, newest first
+#[tauri::command]
+pub async fn list_backups(notebook: State<'_, AppState>) -> Result<Vec<BackupInfo>, NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    workspaces.backups().list(workspaces.active())
+}
+
+/// Snapshots the active notebook and the settings now
+#[tauri::command]
+pub async fn create_backup(
+    notebook: State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<BackupInfo, NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    let active_notebook = notebook.notebook.read().await;
+    let name = workspaces.active().to_string();
+    let backups = workspaces.backups().clone();
+    // the notebook's read lock keeps it from being switched while it is snapshotted, the other
+    // workspace commands need not wait for the snapshot
+    drop(workspaces);
+    let active_notebook = active_notebook
+        .as_ref()
+        .ok_or(NotebookError::NotebookLocked(name.clone()))?;
+    backups
+        .create(
+            active_notebook,
+            &name,
+            BackupKind::Manual,
+            settings_file(app_handle).as_deref(),
+        )
+        .await
+}
+
+#[tauri::command]
+pub async fn delete_backup(notebook: State<'_, AppState>, id: &str) -> Result<(), NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    workspaces.backups().delete(workspaces.active(), id)
+}
+
+/// Replaces the active notebook and the settings with a snapshot, after checking it is intact.
+/// The notebook is snapshotted first so the restore can be undone.
+#[tauri::command]
+pub async fn restore_backup(
+    notebook: State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+    id: &str,
+) -> Result<BackupInfo, NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    let mut active_notebook = notebook.notebook.write().await;
+    let name = workspaces.active().to_string();
+    let Some(open) = active_notebook.take() else {
+        return Err(NotebookError::NotebookLocked(name));
+    };
+    let settings_file = settings_file(app_handle.clone());
+    let key = open.key().cloned();
+    let prepared: Result<BackupInfo, NotebookError> = async {
+        let restored = workspaces.backups().validate(&name, id, key.as_ref())?;
+        workspaces
+            .backups()
+            .create(
+                &open,
+                &name,
+                BackupKind::PreRestore,
+                settings_file.as_deref(),
+            )
+            .await?;
+        Ok(restored)
+    }
+    .await;
+    let restored = match prepared {
+        Ok(restored) => restored,
+        Err(e) => {
+            *active_notebook = Some(open);
+            return Err(e);
+        }
+    };
+    open.close().await;
+    drop(open);
+    let result = workspaces.restore_backup(&name, id, settings_file.as_deref());
+    if let Err(e) = &result {
+        error!(
+            "Unable to restore backup {} of notebook '{}': {}",
+            id, name, e
+        );
+    }
+    // the notebook as it was when the restore failed before touching it
+    let reopened = workspaces.open_with_key(&name, key).await?;
+    spawn_embedding_worker(&reopened, app_handle);
+    *active_notebook = Some(reopened);
+    result.map(|_| restored)
+}
+
+/// Snapshots the active notebook every backup interval, then prunes its scheduled snapshots to
+/// the retention policy. A locked notebook is not snapshotted. Runs as long as the app does.
+pub async fn scheduled_backups(app_state: AppState, app_handle: tauri::AppHandle) {
+    loop {
+        tokio::time::sleep(BACKUP_CHECK_INTERVAL).await;
+        let Some(interval) = backup_interval(app_handle.clone()) else {
+            continue;
+        };
+        let workspaces = app_state.workspaces.lock().await;
+        let notebook = app_state.notebook.read().await;
+        let Some(notebook) = notebook.as_ref() else {
+            continue;
+        };
+        let name = workspaces.active().to_string();
+        let backups = workspaces.backups().clone();
+        // the notebook's read lock keeps it from being switched while it is snapshotted
+        drop(workspaces);
+        let last = match backups.list(&name) {
+            Ok(listed) => listed
+                .into_iter()
+                .filter(|backup| backup.kind == BackupKind::Scheduled)
+                .map(|backup| backup.created)
+                .max(),
+            Err(e) => {
+                error!("Failed to list the backups of notebook '{}': {}", name, e);
+                continue;
+            }
+        };
+        let due = match last {
+            Some(last) => Utc::now().timestamp() - last >= interval.as_secs() as i64,
+            None => true,
+        };
+        if !due {
+            continue;
+        }
+        let settings_file = settings_file(app_handle.clone());
+        if let Err(e) = backups
+            .create(
+                notebook,
+                &name,
+                BackupKind::Scheduled,
+                settings_file.as_deref(),
+            )
+            .await
+        {
+            error!("Failed to back up notebook '{}': {}", name, e);
+            continue;
+        }
+        if let Err(e) = backups.prune(&name, backup_retention(app_handle.clone())) {
+            error!("Failed to prune the backups of notebook '{}': {}", name, e);
+        }
+    }
+}
+
+/// Permanently deletes the notes that have outlived the trash retention period
+pub async fn purge_expired_trash(notebook: &Notebook, app_handle: tauri::AppHandle) {
+    let retention_days = trash_retention_days(app_handle);
+    match notebook.purge_trash(retention_days).await {
+        Ok(purged) => info!(
+            "Purged [{}] notes older than {} days from the trash",
+            purged, retention_days
+        ),
+        Err(e) => error!("Failed to purge the trash: {}", e),
+    }
+}
+
+/// Whether semantic features are available, with the reason when they are not
+#[tauri::command]
+pub async fn get_embedding_status(
+    notebook: State<'_, AppState>,
+) -> Result<EmbeddingStatus, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.get_embedding_status().await
+}
+
+#[tauri::command]
+pub async fn list_embedding_models(
+    notebook: State<'_, AppState>,
+) -> Result<Vec<EmbeddingModelInfo>, NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    let active = workspaces.embedding_model();
+    Ok(EmbeddingModelChoice::ALL
+        .into_iter()
+        .map(|model| model.info(model == active))
+        .collect())
+}
+
+/// Embeds notes with another model. The active notebook is opened again and re-indexed in the
+/// background, other notebooks are re-indexed when next opened.
+#[tauri::command]
+pub async fn set_embedding_model(
+    notebook: State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+    model: &str,
+) -> Result<EmbeddingModelInfo, NotebookError> {
+    let model = parse_embedding_model(model)?;
+    // download the model before closing the notebook, so it can be used in the meantime
+    let model_cache = notebook.workspaces.lock().await.model_cache().clone();
+    model_cache.download(model).await?;
+    let mut workspaces = notebook.workspaces.lock().await;
+    let mut active_notebook = notebook.notebook.write().await;
+    let previous = workspaces.embedding_model();
+    if previous != model {
+        info!(
+            "Switching embedding model from {} to {}",
+            previous.as_str(),
+            model.as_str()
+        );
+        workspaces.set_embedding_model(model)?;
+        // a locked notebook picks up the model when it is unlocked
+        if let Some(open) = active_notebook.take() {
+            open.close().await;
+            let key = open.key().cloned();
+            drop(open);
+            let active = workspaces.active().to_string();
+            let reopened = match workspaces.open_with_key(&active, key.clone()).await {
+                Ok(reopened) => reopened,
+                Err(e) => {
+                    error!(
+                        "Unable to open notebook '{}' with {}: {}",
+                        active,
+                        model.as_str(),
+                        e
+                    );
+                    workspaces.set_embedding_model(previous)?;
+                    let reopened = workspaces.open_with_key(&active, key).await?;
+                    spawn_embedding_worker(&reopened, app_handle);
+                    *active_notebook = Some(reopened);
+                    return Err(e);
+                }
+            };
+            spawn_embedding_worker(&reopened, app_handle);
+            *active_notebook = Some(reopened);
+        }
+    }
+    Ok(model.info(true))
+}
+
+fn parse_embedding_model(model: &str) -> Result<EmbeddingModelChoice, NotebookError> {
+    EmbeddingModelChoice::parse(model)
+        .ok_or(NotebookError::UnknownEmbeddingModel(model.to_string()))
+}
+
+/// Runs a model cache operation that reads or copies model files off the async runtime
+async fn with_model_cache<T: Send + 'static>(
+    notebook: &State<'_, AppState>,
+    operation: impl FnOnce(ModelCache) -> Result<T, NotebookError> + Send + 'static,
+) -> Result<T, NotebookError> {
+    let model_cache = notebook.workspaces.lock().await.model_cache().clone();
+    tauri::async_runtime::spawn_blocking(move || operation(model_cache))
+        .await
+        .map_err(|e| NotebookError::ModelCache(e.to_string()))?
+}
+
+/// The embedding models in the cache shared by all notebooks
+#[tauri::command]
+pub async fn list_cached_models(
+    notebook: State<'_, AppState>,
+) -> Result<Vec<CachedModel>, NotebookError> {
+    let workspaces = notebook.workspaces.lock().await;
+    workspaces.model_cache().list(workspaces.embedding_model())
+}
+
+/// Downloads a model ahead of using it, emitting `model-download-progress` events
+#[tauri::command]
+pub async fn download_embedding_model(
+    notebook: State<'_, AppState>,
+    model: &str,
+) -> Result<(), NotebookError> {
+    let model = parse_embedding_model(model)?;
+    let model_cache = notebook.workspaces.lock().await.model_cache().clone();
+    model_cache.download(model).await
+}
+
+/// Checks the cached files of a model
+#[tauri::command]
+pub async fn verify_cached_model(
+    notebook: State<'_, AppState>,
+    model: &str,
+) -> Result<ModelCheck, NotebookError> {
+    let model = parse_embedding_model(model)?;
+    with_model_cache(&notebook, move |model_cache| model_cache.verify(model)).await
+}
+
+/// Installs a model from a local file or directory, for machines that can't download it
+#[tauri::command]
+pub async fn install_embedding_model(
+    notebook: State<'_, AppState>,
+    model: &str,
+    path: &str,
+) -> Result<ModelCheck, NotebookError> {
+    let model = parse_embedding_model(model)?;
+    let path = PathBuf::from(path);
+    with_model_cache(&notebook, move |model_cache| {
+        model_cache.install_from(model, &path)
+    })
+    .await
+}
+
+/// Deletes the cached models other than the one notebooks are embedded with, returning the
+/// repositories of those deleted
+#[tauri::command]
+pub async fn delete_unused_models(
+    notebook: State<'_, AppState>,
+) -> Result<Vec<String>, NotebookError> {
+    let keep = notebook.workspaces.lock().await.embedding_model();
+    with_model_cache(&notebook, move |model_cache| {
+        model_cache.delete_unused(keep)
+    })
+    .await
+}
+
+/// Checks the embeddings db against the notes
+#[tauri::command]
+pub async fn verify_index(notebook: State<'_, AppState>) -> Result<IndexReport, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    notebook.verify_index().await
+}
+
+#[tauri::command]
+pub async fn get_embedding_stats(
+    notebook: State<'_, AppState>,
+) -> Result<EmbeddingCounts, NotebookError> {
+    let notebook = notebook.unlocked_notebook().await?;
+    Ok(notebook.get_embedding_stats())
+}
+
+/// Re-embeds every note, emitting a `rebuild-embeddings-progress` event after each batch
+#[tauri::command]
+pub async fn rebuild_embeddings(
+    notebook: State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<usize, NotebookError> {
+    let emit_progress = |progress: RebuildProgress| {
+        if let Err(e) = app_handle.emit_all("rebuild-embeddings-progress", progress) {
+            log::error!("Failed to emit rebuild progress: {}", e);
+        }
+    };
+    let (total, dir) = {
+        let notebook = notebook.unlocked_notebook().await?;
+        (
+            notebook.start_rebuild().await?,
+            notebook.dir().to_path_buf(),
+        )
+    };
+    emit_progress(RebuildProgress { embedded: 0, total });
+    // the notebook is only held for a batch at a time, so it stays usable during the rebuild
+    loop {
+        let progress = notebook_at(&notebook, &dir)
+            .await?
+            .rebuild_batch(total)
+            .await?;
+        match progress {
+            Some(progress) => emit_progress(progress),
+            None => break,
+        }
+    }
+    emit_progress(RebuildProgress {
+        embedded: total,
+        total,
+    });
+    info!("Rebuilt the embeddings of [{}] notes", total);
+    Ok(total)
+}
+
+/// The active notebook for the next step of an operation started on the notebook in `dir` and
+/// released between steps, failing when another notebook was opened in the meantime
+async fn notebook_at<'a>(
+    app_state: &'a AppState,
+    dir: &Path,
+) -> Result<RwLockReadGuard<'a, Notebook>, NotebookError> {
+    let notebook = app_state.unlocked_notebook().await?;
+    if notebook.dir() != dir {
+        return Err(NotebookError::Workspace(
+            "Another notebook was opened before the operation completed".to_string(),
+        ));
+    }
+    Ok(notebook)
+}
+
+/// Starts the background embedding worker of a newly opened notebook
+pub fn spawn_embedding_worker(notebook: &Notebook, app_handle: tauri::AppHandle) {
+    let worker = notebook.embedding_worker(move |note_ids: &[String]| {
+        if let Err(e) = app_handle.emit_all(NOTES_EMBEDDED_EVENT, note_ids.to_vec()) {
+            log::error!("Failed to emit {}: {}", NOTES_EMBEDDED_EVENT, e);
+        }
+    });
+    tauri::async_runtime::spawn(worker);
+}
+
+/// Emits the progress of model downloads, including those made when a notebook's embedding
+/// engine loads
+pub fn report_model_downloads(model_cache: &ModelCache, app_handle: tauri::AppHandle) {
+    model_cache.set_listener(Arc::new(move |progress| {
+        if let Err(e) = app_handle.emit_all(MODEL_DOWNLOAD_EVENT, progress) {
+            log::error!("Failed to emit {}: {}", MODEL_DOWNLOAD_EVENT, e);
+        }
+    }));
+}
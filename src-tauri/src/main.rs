@@ -2,29 +2,70 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use std::sync::Arc;
+use std::time::Instant;
 
 use log::LevelFilter;
+use tauri::api::dialog::blocking::MessageDialogBuilder;
+use tauri::api::dialog::MessageDialogKind;
 use tauri::Manager;
 use tauri_plugin_log::LogTarget;
-use tokio::sync::Mutex;
-use vec_embed_store::EmbeddingEngineOptions;
+use tokio::sync::{Mutex, RwLock, RwLockReadGuard};
 
 use commands::{add_category_to_note, delete_all_notes, delete_note, export_notes, get_note_by_id,
                get_notes, import_notes, prompt_about_note, remove_category_from_note, save_note_text};
 
-use crate::commands::get_note_similarities;
-use crate::notebook::Notebook;
+use crate::commands::{
+    add_attachment, auto_lock, change_notebook_passphrase, close_notebook, create_backup,
+    create_notebook, delete_backup, delete_category, delete_unused_models, diff_note_revisions,
+    download_embedding_model, empty_trash, encrypt_notebook, get_backlinks, get_category_tree,
+    get_dangling_links, get_embedding_stats, get_embedding_status, get_lock_state,
+    get_note_revision, get_note_similarities, get_notes_in_category, get_outgoing_links,
+    hybrid_search, install_embedding_model, list_attachments, list_backups, list_cached_models,
+    list_categories, list_embedding_models, list_note_revisions, list_notebooks, list_notes,
+    list_trash, lock_notebook, merge_categories, move_category, open_attachment, open_notebook,
+    purge_expired_trash, rebuild_embeddings, remove_attachment, rename_category,
+    report_model_downloads, restore_backup, restore_note, restore_note_revision, scheduled_backups,
+    search_notes, set_embedding_model, set_note_flag, set_notes_flag, spawn_embedding_worker,
+    unlock_notebook, verify_cached_model, verify_index,
+};
+use crate::notebook::{Notebook, NotebookError};
 use crate::utils::{get_user_app_dir, set_panic_hook};
+use crate::workspaces::{Workspaces, DEFAULT_NOTEBOOK};
 
+mod backups;
 mod commands;
 mod notebook;
+mod settings;
 mod utils;
 mod llm;
+mod workspaces;
 
 #[derive(Clone)]
 pub struct AppState {
     // See https://github.com/tauri-apps/tauri/discussions/1336#discussioncomment-1936523
-    pub notebook: Arc<Mutex<Notebook>>,
+    // None while the active notebook is encrypted and locked. Commands share the notebook, it
+    // is only locked for writing to be replaced.
+    pub notebook: Arc<RwLock<Option<Notebook>>>,
+    pub workspaces: Arc<Mutex<Workspaces>>,
+    // when a command last used the notebook, it auto-locks after a while without use
+    pub last_activity: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl AppState {
+    /// The active notebook, failing with `NotebookLocked` while it is locked
+    pub async fn unlocked_notebook(&self) -> Result<RwLockReadGuard<'_, Notebook>, NotebookError> {
+        let notebook = self.notebook.read().await;
+        *self.last_activity.lock().unwrap() = Instant::now();
+        match RwLockReadGuard::try_map(notebook, |notebook| notebook.as_ref()) {
+            Ok(notebook) => Ok(notebook),
+            Err(notebook) => {
+                // release the notebook first to keep the workspaces then notebook lock order
+                drop(notebook);
+                let active = self.workspaces.lock().await.active().to_string();
+                Err(NotebookError::NotebookLocked(active))
+            }
+        }
+    }
 }
 
 // adapt log targets based on prod/non-prod
@@ -33,6 +74,15 @@ const LOG_TARGETS: [LogTarget; 2] = [LogTarget::Stdout, LogTarget::Webview];
 #[cfg(not(debug_assertions))]
 const LOG_TARGETS: [LogTarget; 2] = [LogTarget::Stdout, LogTarget::LogDir];
 
+/// Tells the user why the app can't start, as there is no window to show it in yet
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    MessageDialogBuilder::new("Knowling could not start", message)
+        .kind(MessageDialogKind::Error)
+        .show();
+    std::process::exit(1)
+}
+
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 
 fn main() {
@@ -40,30 +90,69 @@ fn main() {
     // log here in the event of a panic
     set_panic_hook(&app_dir);
 
-    let embedding_engine_options = EmbeddingEngineOptions {
-        show_download_progress: true,
-        cache_dir: app_dir.join("llm-cache"),
-        ..Default::default()
-    };
+    let mut workspaces = Workspaces::load(&app_dir);
     // block until we get the Notebook
     let app_state = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async {
-            let notebook = Arc::new(Mutex::new(
-                Notebook::new(embedding_engine_options, app_dir.as_path()).await.unwrap(),
-            ));
-            AppState { notebook }
+            let active = workspaces.active().to_string();
+            // a notebook opens without its embedding engine, so this only fails on its models db
+            let opened = match workspaces.open(&active).await {
+                // an encrypted notebook stays locked until the user unlocks it
+                Err(NotebookError::NotebookLocked(_)) => Ok(None),
+                Err(e) if active != DEFAULT_NOTEBOOK => {
+                    // the logger is not set up yet
+                    eprintln!(
+                        "Unable to open notebook '{}', falling back to the default: {}",
+                        active, e
+                    );
+                    // the default stays active for this run even if it can't be remembered
+                    if let Err(e) = workspaces.set_active(DEFAULT_NOTEBOOK) {
+                        eprintln!("Unable to remember the default notebook as active: {}", e);
+                    }
+                    workspaces.open(DEFAULT_NOTEBOOK).await.map(Some)
+                }
+                opened => opened.map(Some),
+            };
+            let notebook = match opened {
+                Ok(notebook) => notebook,
+                Err(e) => exit_with_error(&format!("Unable to open the notebook: {}", e)),
+            };
+            AppState {
+                notebook: Arc::new(RwLock::new(notebook)),
+                workspaces: Arc::new(Mutex::new(workspaces)),
+                last_activity: Arc::new(std::sync::Mutex::new(Instant::now())),
+            }
         });
 
+    let setup_state = app_state.clone();
     tauri::Builder::default()
-        .setup(|app| {
+        .setup(move |app| {
             #[cfg(debug_assertions)] // only include this code on debug builds
             {
                 let window = app.get_window("main").unwrap();
                 window.open_devtools();
             }
+            let app_handle = app.handle();
+            tauri::async_runtime::spawn(async move {
+                // before the embedding worker first loads the engine, which may download the model
+                report_model_downloads(
+                    setup_state.workspaces.lock().await.model_cache(),
+                    app_handle.clone(),
+                );
+                // a locked notebook is set up when it is unlocked
+                if let Some(notebook) = setup_state.notebook.read().await.as_ref() {
+                    spawn_embedding_worker(notebook, app_handle.clone());
+                    purge_expired_trash(notebook, app_handle.clone()).await;
+                }
+                tauri::async_runtime::spawn(scheduled_backups(
+                    setup_state.clone(),
+                    app_handle.clone(),
+                ));
+                auto_lock(setup_state, app_handle).await;
+            });
             Ok(())
         })
         .plugin(
@@ -80,15 +169,65 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             save_note_text,
             get_notes,
+            list_notes,
             export_notes,
             import_notes,
             get_note_by_id,
             get_note_similarities,
             delete_note,
+            set_note_flag,
+            set_notes_flag,
             prompt_about_note,
             add_category_to_note,
             delete_all_notes,
-            remove_category_from_note
+            remove_category_from_note,
+            search_notes,
+            hybrid_search,
+            list_note_revisions,
+            get_note_revision,
+            diff_note_revisions,
+            restore_note_revision,
+            list_trash,
+            restore_note,
+            empty_trash,
+            list_categories,
+            rename_category,
+            merge_categories,
+            delete_category,
+            get_category_tree,
+            get_notes_in_category,
+            move_category,
+            get_outgoing_links,
+            get_backlinks,
+            get_dangling_links,
+            add_attachment,
+            list_attachments,
+            remove_attachment,
+            open_attachment,
+            list_notebooks,
+            create_notebook,
+            open_notebook,
+            close_notebook,
+            verify_index,
+            rebuild_embeddings,
+            get_embedding_stats,
+            list_embedding_models,
+            set_embedding_model,
+            get_embedding_status,
+            list_cached_models,
+            download_embedding_model,
+            verify_cached_model,
+            install_embedding_model,
+            delete_unused_models,
+            get_lock_state,
+            unlock_notebook,
+            lock_notebook,
+            encrypt_notebook,
+            change_notebook_passphrase,
+            list_backups,
+            create_backup,
+            delete_backup,
+            restore_backup
         ])
         // @TODO see https://blog.moonguard.dev/how-to-use-local-sqlite-database-with-tauri
         // .setup(|_app| {
@@ -1,109 +1,632 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::Write;
+use std::future::Future;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
 use chrono::Utc;
 use log::info;
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
 use serde::{Serialize, Serializer};
 use serde::ser::SerializeStruct;
 use thiserror::Error;
+use fastembed::{InitOptions, TextEmbedding};
 use tokio::sync::Mutex;
 use uuid::Uuid;
-use vec_embed_store::{EmbedDbError, EmbeddingEngineOptions, EmbeddingsDb, TextChunk};
+use vec_embed_store::{EmbedDbError, EmbeddingEngineOptions, EmbeddingsDb};
 
-use crate::notebook::note::{Category, Note};
+use crate::notebook::attachments::{Attachment, AttachmentStore};
+use crate::notebook::chunking::{
+    chunk_note, closest_chunk_per_note, ChunkMatch, Passage, CHUNK_MAX_CHARS, CHUNK_OVERLAP_CHARS,
+};
+use crate::notebook::encryption::NotebookKey;
+use crate::notebook::embedding_queue::{EmbeddingQueue, EMBED_BATCH_SIZE};
+use crate::notebook::embeddings::{
+    EmbeddingCounts, EmbeddingModelChoice, EmbeddingStats, EmbeddingStatus, IndexReport,
+    RebuildProgress, SimilarityResults,
+};
+use crate::notebook::links::{parse_wiki_links, NoteLink};
+use crate::notebook::listing::{ArchiveFilter, NoteListQuery, NotePage};
+use crate::notebook::model_cache::ModelCache;
+use crate::notebook::note::{
+    parse_category_path, Category, CategoryNode, CategorySummary, HybridHit, Note, NoteFlag,
+    NoteRevision, SearchHit, SimilarNote, TrashedNote,
+};
 use crate::notebook::notebook_repository::NotebookRepository;
+use crate::notebook::revisions::{diff_texts, DiffChange, DiffMode};
+use crate::notebook::search::{quote_fts_terms, reciprocal_rank_fusion, RankFusionWeights};
+use crate::notebook::sqlite_vector_store::SqliteVectorStore;
+use crate::notebook::transfer::{ImportReport, ImportedNote, NotesExport};
+use crate::notebook::vector_store::{EngineLoader, EngineStatus, LazyVectorStore, VectorStore};
+use crate::utils::copy_dir;
 
+pub mod attachments;
+pub mod chunking;
+mod connection_pool;
+mod embedding_queue;
+pub mod embeddings;
+pub mod encryption;
+pub mod links;
+pub mod listing;
+mod migrations;
+pub mod model_cache;
 pub mod note;
 mod notebook_repository;
+#[cfg(test)]
+pub(crate) mod in_memory_store;
+pub mod revisions;
+pub mod search;
+mod sqlite_vector_store;
+pub mod transfer;
+pub mod vector_store;
 
+// saves closer together than this are coalesced into a single revision
+const REVISION_COALESCE_WINDOW_SECS: i64 = 5 * 60;
 const SIMILARS_DEFAULT_LIMIT: usize = 3;
 const SIMILARS_DEFAULT_THRESHOLD: f32 = 0.01;
+// similar notes fetched per note wanted, as archived notes are left out
+const SIMILARS_CANDIDATE_FACTOR: usize = 2;
+const SEARCH_DEFAULT_LIMIT: usize = 50;
+const HYBRID_DEFAULT_LIMIT: usize = 20;
+// each retriever contributes this many times the requested limit as fusion candidates
+const HYBRID_CANDIDATE_FACTOR: usize = 3;
+// chunks fetched per note wanted, as a note can have several chunks among the closest
+const CHUNKS_PER_NOTE_FACTOR: usize = 4;
+pub(crate) const ATTACHMENTS_DIR: &str = "attachments";
+pub(crate) const MODELS_DB_FILE: &str = "db.sqlite";
+// vec-embed-store keeps its tables as lance directories in the notebook dir
+pub(crate) const LANCE_TABLE_SUFFIX: &str = ".lance";
+const LIST_DEFAULT_LIMIT: usize = 100;
+const DB_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// read-only connections to the models db, reads only wait for each other beyond this many
+const READ_CONNECTIONS: usize = 4;
 
 pub struct Notebook {
-    embed_store: EmbeddingsDb,
+    // shared with the embedding worker
+    embed_store: Arc<dyn VectorStore>,
     models_store: NotebookRepository,
+    attachments: AttachmentStore,
+    embedding_queue: EmbeddingQueue,
+    embedding_stats: Arc<EmbeddingStats>,
+    embedding_model: EmbeddingModelChoice,
+    engine_status: Arc<RwLock<EngineStatus>>,
+    // None for a notebook that is not encrypted
+    key: Option<NotebookKey>,
+    dir: PathBuf,
+}
+
+/// Opens the models db, decrypting it with `key` for an encrypted notebook. The db is switched
+/// to WAL mode so the read connections of `open_models_db_reader` don't wait for writes.
+fn open_models_db(db_path: &Path, key: Option<&NotebookKey>) -> Result<Connection, NotebookError> {
+    let conn = Connection::open(db_path)?;
+    if let Some(key) = key {
+        key.apply(&conn)?;
+    }
+    // wait for another process holding the db rather than failing straight away
+    conn.busy_timeout(DB_BUSY_TIMEOUT)?;
+    let journal_mode: String =
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+    if !journal_mode.eq_ignore_ascii_case("wal") {
+        log::warn!("The models db is in {} rather than WAL mode", journal_mode);
+    }
+    Ok(conn)
+}
+
+/// Opens a read-only connection to the models db, which must exist
+fn open_models_db_reader(
+    db_path: &Path,
+    key: Option<&NotebookKey>,
+) -> Result<Connection, NotebookError> {
+    let conn = Connection::open_with_flags(
+        db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    if let Some(key) = key {
+        key.apply(&conn)?;
+    }
+    conn.busy_timeout(DB_BUSY_TIMEOUT)?;
+    Ok(conn)
 }
 
 impl Notebook {
+    /// Opens the notebook in `app_dir`. Notebooks open even when the embedding engine can't be
+    /// loaded, semantic features are then unavailable until it can (see `get_embedding_status`).
+    /// The model is downloaded to `model_cache` when the engine is first loaded if it is not
+    /// there yet. An encrypted notebook is opened with its `key`, its vectors are kept in its
+    /// encrypted models db rather than in the embeddings db.
     pub async fn new(
-        embedding_engine_options: EmbeddingEngineOptions,
+        embedding_model: EmbeddingModelChoice,
+        model_cache: ModelCache,
         app_dir: &Path,
+        key: Option<NotebookKey>,
     ) -> Result<Self, NotebookError> {
         // cast app_dir to a &str
-        let app_dir_str = app_dir.to_str().ok_or(NotebookError::FileAccess(
-            "Invalid app directory path".to_string(),
-        ))?;
-        let embed_store = EmbeddingsDb::new(app_dir_str, embedding_engine_options).await?;
-        let db_path = app_dir.join("db.sqlite");
-        let conn = Arc::new(Mutex::new(Connection::open(&db_path)?));
-        let nb_repository = NotebookRepository::new(conn);
-        nb_repository
-            .init_db()
-            .await
-            .expect("Unable it initialize models db");
+        let app_dir_str = app_dir
+            .to_str()
+            .ok_or(NotebookError::FileAccess(
+                "Invalid app directory path".to_string(),
+            ))?
+            .to_string();
+        let db_path = app_dir.join(MODELS_DB_FILE);
+        let loader_key = key.clone();
+        let loader: EngineLoader = Box::new(move || {
+            let app_dir_str = app_dir_str.clone();
+            let db_path = db_path.clone();
+            let key = loader_key.clone();
+            let model_cache = model_cache.clone();
+            Box::pin(async move {
+                model_cache.download(embedding_model).await?;
+                if let Some(key) = key {
+                    let options = InitOptions {
+                        model_name: embedding_model.fastembed_model(),
+                        show_download_progress: false,
+                        cache_dir: model_cache.dir().to_path_buf(),
+                        ..Default::default()
+                    };
+                    let embedder = TextEmbedding::try_new(options)
+                        .map_err(|e| NotebookError::EmbeddingError(e.to_string()))?;
+                    let conn = open_models_db(&db_path, Some(&key))?;
+                    let embed_store = SqliteVectorStore::new(conn, Box::new(embedder));
+                    return Ok(Box::new(embed_store) as Box<dyn VectorStore>);
+                }
+                let options = EmbeddingEngineOptions {
+                    model_name: embedding_model.fastembed_model(),
+                    // downloads are reported by the model cache, fastembed only prints to stdout
+                    show_download_progress: false,
+                    cache_dir: model_cache.dir().to_path_buf(),
+                };
+                let embed_store = EmbeddingsDb::new(&app_dir_str, options).await?;
+                Ok(Box::new(embed_store) as Box<dyn VectorStore>)
+            })
+        });
+        let engine_status = Arc::new(RwLock::new(EngineStatus::Loading));
+        let embed_store = LazyVectorStore::new(loader, engine_status.clone());
+        Self::with_vector_store(
+            embedding_model,
+            Arc::new(embed_store),
+            engine_status,
+            app_dir,
+            key,
+        )
+        .await
+    }
+
+    /// Opens the notebook in `app_dir` with its vectors in `embed_store`, which must embed with
+    /// `embedding_model` and keep `engine_status` up to date
+    pub async fn with_vector_store(
+        embedding_model: EmbeddingModelChoice,
+        embed_store: Arc<dyn VectorStore>,
+        engine_status: Arc<RwLock<EngineStatus>>,
+        app_dir: &Path,
+        key: Option<NotebookKey>,
+    ) -> Result<Self, NotebookError> {
+        if key.is_some() {
+            encryption::finish_encryption(app_dir)?;
+        }
+        let db_path = app_dir.join(MODELS_DB_FILE);
+        let writer = Arc::new(Mutex::new(open_models_db(&db_path, key.as_ref())?));
+        // migrated before the readers open, so they never see an older schema
+        NotebookRepository::new(writer.clone()).init_db().await?;
+        let readers = (0..READ_CONNECTIONS)
+            .map(|_| open_models_db_reader(&db_path, key.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let nb_repository = NotebookRepository::with_readers(writer, readers);
         info!("Connection to models db established: {:?}", db_path);
-        Ok(Notebook {
+        let attachments = AttachmentStore::new(app_dir.join(ATTACHMENTS_DIR), key.clone())?;
+        if key.is_some() {
+            attachments.encrypt_plain_blobs()?;
+            attachments.remove_open_copies()?;
+        }
+        let embedding_stats = Arc::new(EmbeddingStats::default());
+        let embedding_queue = EmbeddingQueue::new(
+            embed_store.clone(),
+            nb_repository.clone(),
+            embedding_stats.clone(),
+        );
+        let notebook = Notebook {
             embed_store,
             models_store: nb_repository,
+            attachments,
+            embedding_queue,
+            embedding_stats,
+            embedding_model,
+            engine_status,
+            key,
+            dir: app_dir.to_path_buf(),
+        };
+        // the engine is loaded by the embedding worker, which may first download the model
+        notebook.sync_embedding_model().await?;
+        Ok(notebook)
+    }
+
+    /// Queues every note to be embedded again when the notebook was last embedded with another
+    /// model, emptying the embeddings db so vectors of both models are never compared. The
+    /// re-index is done by the embedding worker and picks up where it left off after a restart.
+    async fn sync_embedding_model(&self) -> Result<(), NotebookError> {
+        let model = self.embedding_model.as_str();
+        let (recorded, cleared) = match self.models_store.get_embedding_model().await? {
+            Some(recorded) => recorded,
+            None => return self.models_store.record_embedding_model(model).await,
+        };
+        if recorded == model && cleared {
+            return Ok(());
+        }
+        let _batch = self.embedding_queue.pause().await;
+        if recorded != model {
+            info!(
+                "Embedding model changed from {} to {}, re-indexing",
+                recorded, model
+            );
+            self.models_store
+                .queue_full_reindex(model, Self::get_now())
+                .await?;
+        }
+        let emptied = match self.ensure_engine_available() {
+            Ok(()) => self.embed_store.empty().await,
+            Err(e) => Err(e),
+        };
+        match emptied {
+            Ok(()) => self.models_store.record_embeddings_cleared().await,
+            Err(NotebookError::EmbeddingUnavailable(_)) => {
+                info!("The embeddings db will be emptied once the embedding engine is available");
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn get_embedding_status(&self) -> Result<EmbeddingStatus, NotebookError> {
+        let engine = self.engine_status.read().unwrap().clone();
+        Ok(EmbeddingStatus {
+            engine,
+            pending_embeddings: self.models_store.count_pending_embeddings().await?,
         })
     }
 
+    /// Fails with `EmbeddingUnavailable` unless the engine is loaded. Calls made while it loads
+    /// would wait for the load, the model download included.
+    fn ensure_engine_available(&self) -> Result<(), NotebookError> {
+        match &*self.engine_status.read().unwrap() {
+            EngineStatus::Ready => Ok(()),
+            EngineStatus::Loading => Err(NotebookError::EmbeddingUnavailable(
+                "The embedding engine is loading".to_string(),
+            )),
+            EngineStatus::Unavailable { reason } => {
+                Err(NotebookError::EmbeddingUnavailable(reason.clone()))
+            }
+        }
+    }
+
+    /// Stops the embedding worker, waiting for its current batch. Needed before opening the
+    /// same notebook again, as both would write to the embeddings db.
+    pub async fn close(&self) {
+        self.embedding_queue.stop_and_wait().await;
+        if self.key.is_some() {
+            if let Err(e) = self.attachments.remove_open_copies() {
+                log::error!("Failed to delete the attachments copied for opening: {}", e);
+            }
+        }
+    }
+
+    /// The key the notebook was opened with, None when it is not encrypted
+    pub fn key(&self) -> Option<&NotebookKey> {
+        self.key.as_ref()
+    }
+
+    /// The directory the notebook was opened from, which identifies it
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The files and directories making up the notebook in `dir`. The dir of the default
+    /// notebook is the app dir, which holds other notebooks and the model cache as well.
+    pub fn files(dir: &Path) -> Result<Vec<PathBuf>, NotebookError> {
+        let db_files = [
+            MODELS_DB_FILE.to_string(),
+            format!("{}-wal", MODELS_DB_FILE),
+            format!("{}-shm", MODELS_DB_FILE),
+            format!("{}-journal", MODELS_DB_FILE),
+        ];
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).map_err(|e| NotebookError::FileAccess(e.to_string()))? {
+            let entry = entry.map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_dir = entry.path().is_dir();
+            if (!is_dir && (db_files.contains(&name) || name == encryption::ENCRYPTION_FILE))
+                || (is_dir && (name == ATTACHMENTS_DIR || name.ends_with(LANCE_TABLE_SUFFIX)))
+            {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Writes a copy of the notebook into the empty dir `target`: the models db through SQLite's
+    /// online backup, the embeddings db, attachments and the key file of an encrypted notebook.
+    /// Returns the schema version of the copied models db.
+    pub async fn write_snapshot(&self, target: &Path) -> Result<u32, NotebookError> {
+        // no blob is stored or collected until it is linked, so the attachments referenced by
+        // the copied db are all in the snapshot
+        let _writes = self.attachments.lock_writes().await;
+        let schema_version = {
+            // no batch runs between the two copies, so the embeddings ledger of the models db
+            // and the vectors match
+            let _batch = self.embedding_queue.pause().await;
+            let schema_version = self
+                .models_store
+                .backup_to(&target.join(MODELS_DB_FILE), self.key.as_ref())
+                .await?;
+            for path in Self::files(&self.dir)? {
+                let name = path.file_name().expect("notebook files have a name");
+                if name.to_string_lossy().ends_with(LANCE_TABLE_SUFFIX) {
+                    copy_dir(&path, &target.join(name), false)
+                        .map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+                }
+            }
+            schema_version
+        };
+        self.attachments
+            .link_blobs_into(&target.join(ATTACHMENTS_DIR))?;
+        let key_file = self.dir.join(encryption::ENCRYPTION_FILE);
+        if key_file.exists() {
+            fs::copy(&key_file, target.join(encryption::ENCRYPTION_FILE))
+                .map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+        }
+        Ok(schema_version)
+    }
+
+    /// Checks the models db of a snapshot written by `write_snapshot` can be opened by this
+    /// version of the app, returning its schema version
+    pub fn verify_snapshot(dir: &Path, key: Option<&NotebookKey>) -> Result<u32, NotebookError> {
+        let conn = Connection::open_with_flags(
+            dir.join(MODELS_DB_FILE),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        if let Some(key) = key {
+            key.apply(&conn)?;
+        }
+        let integrity: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+        if integrity != "ok" {
+            return Err(NotebookError::Backup(format!(
+                "The models db is damaged: {}",
+                integrity
+            )));
+        }
+        let version = migrations::schema_version(&conn)?;
+        if version > migrations::latest_version() {
+            return Err(NotebookError::SchemaVersion(format!(
+                "models db is at schema version {} but this version of the app only supports up to {}",
+                version,
+                migrations::latest_version()
+            )));
+        }
+        Ok(version)
+    }
+
+    /// The background worker keeping the embeddings db up to date with saved notes, it needs
+    /// spawning once per Notebook and stops when the Notebook is dropped. `on_embedded` is
+    /// called with the ids of notes whose vectors have been written.
+    pub fn embedding_worker<F>(&self, on_embedded: F) -> impl Future<Output = ()> + Send + 'static
+    where
+        F: Fn(&[String]) + Send + 'static,
+    {
+        self.embedding_queue.worker(on_embedded)
+    }
+
     /// Rather than create/update we only have upsert
     /// Update: If id is given
     /// Create: If no id given
     pub async fn upsert_note(
-        &mut self,
+        &self,
         id: Option<&str>,
         content: &str,
     ) -> Result<Note, NotebookError> {
         match id {
             // UPDATE
-            Some(id) => {
-                // get the existing note
-                let existing_note = self.models_store.get_note(&id).await?;
-                match existing_note {
-                    Some(mut note) => {
-                        note.set_modified(Self::get_now());
-                        note.set_text(content);
-                        self.save_note_text(&note).await?;
-                        Ok(note)
-                    }
-                    None => Err(NotebookError::NoteNotFound(id.to_string())),
-                }
-            }
+            Some(id) => self.update_note_text(id, content, None).await,
             // CREATE
             None => {
-                let note = Note::new(&Notebook::generate_id(), &content.to_string());
-                info!("Adding new note[{}] to models database", note.get_id());
-                self.models_store.add_note(&note).await?;
-                info!("Adding new note[{}] to embeddings database", note.get_id());
-                self.embed_store
-                    .upsert_texts(&[TextChunk {
-                        id: note.get_text().to_string(),
-                        text: note.get_text().to_string(),
-                    }])
-                    .await?;
+                let note = Note::new(&Notebook::generate_id(), content);
+                info!("Adding new note[{}]", note.get_id());
+                self.write_note(&note, true, None).await?;
                 Ok(note)
             }
         }
     }
 
-    async fn save_note_text(&self, note: &Note) -> Result<(), NotebookError> {
-        info!("updating note {} to models db", note.get_id());
-        let updated_note = self.models_store.update_note_text(&note).await?;
-        info!("updating note {} in embeddings db", note.get_id());
-        let text_chunk = TextChunk {
-            id: updated_note.get_id().to_string(),
-            text: updated_note.get_text().to_string(),
-        };
-        self.embed_store.upsert_texts(&[text_chunk]).await?;
+    async fn update_note_text(
+        &self,
+        id: &str,
+        content: &str,
+        reason: Option<&str>,
+    ) -> Result<Note, NotebookError> {
+        // get the existing note
+        let existing_note = self.models_store.get_note(id).await?;
+        match existing_note {
+            Some(mut note) => {
+                note.set_modified(Self::get_now());
+                note.set_text(content);
+                info!("updating note {}", note.get_id());
+                self.write_note(&note, false, reason).await?;
+                Ok(note)
+            }
+            None => Err(NotebookError::NoteNotFound(id.to_string())),
+        }
+    }
+
+    /// Saves a note's text, revision and links to the models db in one transaction, queueing
+    /// its vector for the embedding worker
+    async fn write_note(
+        &self,
+        note: &Note,
+        is_new: bool,
+        reason: Option<&str>,
+    ) -> Result<(), NotebookError> {
+        let links = parse_wiki_links(note.get_text());
+        let queued = self
+            .models_store
+            .save_note(note, is_new, reason, REVISION_COALESCE_WINDOW_SECS, &links)
+            .await?;
+        if queued {
+            self.embedding_queue.notify();
+        } else {
+            self.embedding_stats.add_skipped(1);
+            let counts = self.embedding_stats.counts();
+            info!(
+                "Note [{}] content is unchanged, skipping its embedding ([{}] skipped, [{}] performed)",
+                note.get_id(),
+                counts.skipped,
+                counts.performed
+            );
+        }
         Ok(())
     }
 
+    /// Embeddings written versus skipped for unchanged content since the notebook was opened
+    pub fn get_embedding_stats(&self) -> EmbeddingCounts {
+        self.embedding_stats.counts()
+    }
+
+    /// Reports notes missing vectors, vectors left behind by deleted notes and vectors computed
+    /// from older note content
+    pub async fn verify_index(&self) -> Result<IndexReport, NotebookError> {
+        let report = match self.ensure_engine_available() {
+            Ok(()) => {
+                // a batch would change the vectors between listing them and reading the ledger
+                let _batch = self.embedding_queue.pause().await;
+                let vector_ids: Option<HashSet<String>> =
+                    self.embed_store.list_ids().await?.map(HashSet::from_iter);
+                self.models_store
+                    .get_index_report(vector_ids.as_ref())
+                    .await?
+            }
+            Err(e) => {
+                info!("Vectors can't be listed ({}), checking the ledger alone", e);
+                self.models_store.get_index_report(None).await?
+            }
+        };
+        info!(
+            "Checked [{}] notes: [{}] missing, [{}] orphaned, [{}] stale, [{}] unrecorded and \
+            [{}] pending vectors",
+            report.notes_checked,
+            report.missing.len(),
+            report.orphaned.len(),
+            report.stale.len(),
+            report.unrecorded.len(),
+            report.pending
+        );
+        Ok(report)
+    }
+
+    /// Empties the embeddings db and queues every note to be embedded again, returning the
+    /// number of notes. This also drops vectors unknown to the embeddings ledger. The notes are
+    /// embedded by `rebuild_batch`, or by the embedding worker.
+    pub async fn start_rebuild(&self) -> Result<usize, NotebookError> {
+        // keep the worker out until the ledger and the embeddings db are both empty
+        let _batch = self.embedding_queue.pause().await;
+        let note_ids = self
+            .models_store
+            .queue_full_reindex(self.embedding_model.as_str(), Self::get_now())
+            .await?;
+        self.embed_store.empty().await?;
+        self.models_store.record_embeddings_cleared().await?;
+        info!("Rebuilding embeddings for [{}] notes", note_ids.len());
+        Ok(note_ids.len())
+    }
+
+    /// Embeds the next batch of a rebuild of `total` notes, returning the progress made or None
+    /// once every note is embedded. If the batch fails its notes stay queued for the embedding
+    /// worker.
+    pub async fn rebuild_batch(
+        &self,
+        total: usize,
+    ) -> Result<Option<RebuildProgress>, NotebookError> {
+        // the worker takes batches from the same queue, so progress is measured on the queue
+        let outcome = self.embedding_queue.process_batch(EMBED_BATCH_SIZE).await?;
+        let pending = self.models_store.count_pending_embeddings().await?;
+        if outcome.failed > 0 {
+            return Err(NotebookError::EmbeddingError(format!(
+                "[{}] notes could not be embedded, they will be retried",
+                pending
+            )));
+        }
+        if outcome.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(RebuildProgress {
+            embedded: total.saturating_sub(pending),
+            total,
+        }))
+    }
+
+    /// `[[links]]` written in a note. Links whose target matches no note are dangling.
+    pub async fn get_outgoing_links(&self, note_id: &str) -> Result<Vec<NoteLink>, NotebookError> {
+        self.models_store.get_outgoing_links(note_id).await
+    }
+
+    /// Links in other notes that point at this note, by its id or its title
+    pub async fn get_backlinks(&self, note_id: &str) -> Result<Vec<NoteLink>, NotebookError> {
+        self.models_store.get_backlinks(note_id).await
+    }
+
+    /// Links across the notebook pointing at notes that don't exist yet
+    pub async fn get_dangling_links(&self) -> Result<Vec<NoteLink>, NotebookError> {
+        self.models_store.get_dangling_links().await
+    }
+
+    /// Revisions of a Note, newest first
+    pub async fn list_note_revisions(
+        &self,
+        note_id: &str,
+    ) -> Result<Vec<NoteRevision>, NotebookError> {
+        self.models_store.get_note_revisions(note_id).await
+    }
+
+    pub async fn get_note_revision(&self, revision_id: i64) -> Result<NoteRevision, NotebookError> {
+        self.models_store
+            .get_note_revision(revision_id)
+            .await?
+            .ok_or(NotebookError::RevisionNotFound(revision_id.to_string()))
+    }
+
+    /// Diffs revision `from_revision_id` against `to_revision_id`, or against the Note's current
+    /// text when no `to_revision_id` is given.
+    pub async fn diff_note_revisions(
+        &self,
+        from_revision_id: i64,
+        to_revision_id: Option<i64>,
+        mode: DiffMode,
+    ) -> Result<Vec<DiffChange>, NotebookError> {
+        let from = self.get_note_revision(from_revision_id).await?;
+        let to_text = match to_revision_id {
+            Some(to_revision_id) => self.get_note_revision(to_revision_id).await?.text,
+            None => self
+                .get_note_by_id(&from.note_id)
+                .await?
+                .ok_or(NotebookError::NoteNotFound(from.note_id.to_string()))?
+                .get_text()
+                .to_string(),
+        };
+        Ok(diff_texts(&from.text, &to_text, mode))
+    }
+
+    /// Restores a Note to the text of one of its revisions. This is a regular update, so the
+    /// restore itself becomes a new revision and the Note's embedding is refreshed.
+    pub async fn restore_note_revision(&self, revision_id: i64) -> Result<Note, NotebookError> {
+        let revision = self.get_note_revision(revision_id).await?;
+        info!(
+            "Restoring note[{}] to revision {}",
+            revision.note_id, revision_id
+        );
+        self.update_note_text(
+            &revision.note_id,
+            &revision.text,
+            Some(&format!("Restored revision {}", revision_id)),
+        )
+        .await
+    }
+
     pub async fn add_category_to_note(
         &self,
         note_id: &str,
@@ -154,8 +677,26 @@ impl Notebook {
         }
     }
 
-    pub async fn get_or_create_category(&self, cat_label: &str) -> Result<Category, NotebookError> {
-        self.models_store.get_or_create_category(cat_label).await
+    /// Gets or creates the category at the end of a category path, ex: `Programming/Rust/Async`,
+    /// creating any missing ancestors along the way. A plain label is a top level category.
+    pub async fn get_or_create_category(&self, cat_path: &str) -> Result<Category, NotebookError> {
+        let labels = parse_category_path(cat_path);
+        if labels.is_empty() {
+            return Err(NotebookError::InvalidCategory(format!(
+                "'{}' is not a valid category",
+                cat_path
+            )));
+        }
+        let mut category: Option<Category> = None;
+        for label in labels {
+            let parent_id = category.as_ref().map(|parent| parent.get_id());
+            category = Some(
+                self.models_store
+                    .get_or_create_category(label, parent_id)
+                    .await?,
+            );
+        }
+        Ok(category.expect("a non empty category path yields a category"))
     }
 
     pub async fn get_category_by_id(
@@ -165,52 +706,251 @@ impl Notebook {
         self.models_store.get_category_by_id(cat_id).await
     }
 
+    pub async fn list_categories(&self) -> Result<Vec<CategorySummary>, NotebookError> {
+        let categories = self.models_store.get_categories_with_counts().await?;
+        Ok(categories
+            .into_iter()
+            .map(|(category, note_count)| CategorySummary {
+                category,
+                note_count,
+            })
+            .collect())
+    }
+
+    /// All categories arranged as a forest of top level categories, siblings ordered by label
+    pub async fn get_category_tree(&self) -> Result<Vec<CategoryNode>, NotebookError> {
+        let categories = self.models_store.get_categories_with_counts().await?;
+        let mut children_by_parent: HashMap<Option<String>, Vec<(Category, usize)>> =
+            HashMap::new();
+        for (category, note_count) in categories {
+            children_by_parent
+                .entry(category.get_parent_id().map(|id| id.to_string()))
+                .or_default()
+                .push((category, note_count));
+        }
+
+        fn build(
+            parent_id: Option<String>,
+            children_by_parent: &mut HashMap<Option<String>, Vec<(Category, usize)>>,
+        ) -> Vec<CategoryNode> {
+            children_by_parent
+                .remove(&parent_id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(category, note_count)| {
+                    let children = build(Some(category.get_id().to_string()), children_by_parent);
+                    CategoryNode {
+                        category,
+                        note_count,
+                        children,
+                    }
+                })
+                .collect()
+        }
+        Ok(build(None, &mut children_by_parent))
+    }
+
+    /// Notes in a category, including notes in any of its descendant categories
+    pub async fn get_notes_in_category(
+        &self,
+        category_id: &str,
+    ) -> Result<Vec<Note>, NotebookError> {
+        self.models_store.get_notes_in_category(category_id).await
+    }
+
+    /// Moves a category under another category, or to the top level if `parent_id` is None
+    pub async fn move_category(
+        &self,
+        category_id: &str,
+        parent_id: Option<&str>,
+    ) -> Result<Category, NotebookError> {
+        self.models_store
+            .move_category(category_id, parent_id)
+            .await
+    }
+
+    /// Relabels a category, labels remain unique among siblings ignoring case
+    pub async fn rename_category(
+        &self,
+        category_id: &str,
+        new_label: &str,
+    ) -> Result<Category, NotebookError> {
+        self.models_store
+            .rename_category(category_id, new_label)
+            .await
+    }
+
+    /// Re-points every note and child category in the source category to the target category
+    /// and removes the source category. Returns the target category.
+    pub async fn merge_categories(
+        &self,
+        source_id: &str,
+        target_id: &str,
+    ) -> Result<Category, NotebookError> {
+        if source_id == target_id {
+            return Err(NotebookError::InvalidCategory(
+                "Cannot merge a category into itself".to_string(),
+            ));
+        }
+        if self.get_category_by_id(source_id).await?.is_none() {
+            return Err(NotebookError::CategoryNotFound(source_id.to_string()));
+        }
+        let target = self
+            .get_category_by_id(target_id)
+            .await?
+            .ok_or(NotebookError::CategoryNotFound(target_id.to_string()))?;
+        self.models_store
+            .merge_categories(source_id, target_id)
+            .await?;
+        Ok(target)
+    }
+
+    /// Deletes a category, only categories with no notes and no child categories can be deleted
+    pub async fn delete_category(&self, category_id: &str) -> Result<(), NotebookError> {
+        self.models_store.delete_category(category_id).await
+    }
+
+    /// Moves every note to the trash. Nothing is permanently deleted until the trash is emptied
+    /// or purged.
     pub async fn delete_all_notes(&self) -> Result<(), NotebookError> {
-        info!("Deleting all notes from models db");
-        self.models_store.delete_all_notes().await?;
-        info!("Deleting all notes from embed db");
-        self.embed_store.empty_db().await?;
+        let trashed = self.models_store.trash_all_notes(Self::get_now()).await?;
+        info!("Moved [{}] notes to the trash", trashed);
         Ok(())
     }
 
-    pub async fn get_notes(&self) -> Result<Vec<Note>, NotebookError> {
-        self.models_store.get_notes().await
+    pub async fn get_notes(&self, archived: ArchiveFilter) -> Result<Vec<Note>, NotebookError> {
+        self.models_store.get_notes(archived).await
+    }
+
+    /// Pins, favorites or archives a note, or undoes it
+    pub async fn set_note_flag(
+        &self,
+        id: &str,
+        flag: NoteFlag,
+        value: bool,
+    ) -> Result<Note, NotebookError> {
+        if self.models_store.set_note_flag(&[id], flag, value).await? == 0 {
+            return Err(NotebookError::NoteNotFound(id.to_string()));
+        }
+        self.get_note_by_id(id)
+            .await?
+            .ok_or(NotebookError::NoteNotFound(id.to_string()))
+    }
+
+    /// Sets a flag on several notes at once, returning how many notes were found
+    pub async fn set_notes_flag(
+        &self,
+        ids: &[&str],
+        flag: NoteFlag,
+        value: bool,
+    ) -> Result<usize, NotebookError> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let updated = self.models_store.set_note_flag(ids, flag, value).await?;
+        info!("Set {:?} to {} on [{}] notes", flag, value, updated);
+        Ok(updated)
+    }
+
+    /// A page of lightweight note summaries, see NoteListQuery for the sorting and filtering
+    pub async fn list_notes(&self, query: &NoteListQuery) -> Result<NotePage, NotebookError> {
+        let limit = query.limit.unwrap_or(LIST_DEFAULT_LIMIT);
+        let (notes, total) = self.models_store.list_notes(query, limit).await?;
+        let end = query.offset + notes.len();
+        Ok(NotePage {
+            notes,
+            total,
+            offset: query.offset,
+            next_offset: (end < total).then_some(end),
+        })
     }
 
     pub async fn get_note_by_id(&self, id: &str) -> Result<Option<Note>, NotebookError> {
         Ok(self.models_store.get_note(id).await?)
     }
 
-    pub async fn delete_note(&mut self, id: &str) -> Result<(), NotebookError> {
-        info!("Deleting note[{}] from models db", id);
-        self.models_store.delete_note(id).await?;
-        info!("Deleting note[{}] from embeddings db", id);
-        self.embed_store.delete_texts(&[id.to_string()]).await?;
+    /// Moves a note to the trash. The note's embedding is kept so a restore is immediate,
+    /// trashed notes are filtered out of similarity results.
+    pub async fn delete_note(&self, id: &str) -> Result<(), NotebookError> {
+        info!("Moving note[{}] to the trash", id);
+        if !self.models_store.trash_note(id, Self::get_now()).await? {
+            info!("Note[{}] was not found outside the trash", id);
+        }
         Ok(())
     }
 
+    pub async fn list_trash(&self) -> Result<Vec<TrashedNote>, NotebookError> {
+        let trashed = self.models_store.get_trashed_notes().await?;
+        Ok(trashed
+            .into_iter()
+            .map(|(note, deleted)| TrashedNote { note, deleted })
+            .collect())
+    }
+
+    pub async fn restore_note(&self, id: &str) -> Result<Note, NotebookError> {
+        info!("Restoring note[{}] from the trash", id);
+        if !self.models_store.restore_note(id).await? {
+            return Err(NotebookError::NoteNotFound(format!(
+                "Note: {} not found in the trash",
+                id
+            )));
+        }
+        self.get_note_by_id(id)
+            .await?
+            .ok_or(NotebookError::NoteNotFound(id.to_string()))
+    }
+
+    /// Permanently deletes everything in the trash, returns the number of notes deleted
+    pub async fn empty_trash(&self) -> Result<usize, NotebookError> {
+        self.purge_trashed(None).await
+    }
+
+    /// Permanently deletes notes that have been in the trash longer than `retention_days`
+    pub async fn purge_trash(&self, retention_days: u32) -> Result<usize, NotebookError> {
+        let cutoff = Self::get_now() - i64::from(retention_days) * 24 * 60 * 60;
+        self.purge_trashed(Some(cutoff)).await
+    }
+
+    async fn purge_trashed(&self, trashed_before: Option<i64>) -> Result<usize, NotebookError> {
+        let purged_ids = self.models_store.purge_trash(trashed_before).await?;
+        info!(
+            "Permanently deleted [{}] notes from models db",
+            purged_ids.len()
+        );
+        if !purged_ids.is_empty() {
+            // the purge queued the vectors for deletion
+            self.embedding_queue.notify();
+            self.collect_attachment_garbage().await?;
+        }
+        Ok(purged_ids.len())
+    }
+
     pub async fn get_note_similars(
         &self,
         note: Note,
         limit: Option<usize>,
         threshold: Option<f32>,
-    ) -> Result<Vec<(Note, f32)>, NotebookError> {
+    ) -> Result<SimilarityResults<SimilarNote>, NotebookError> {
         info!("Getting related notes for Note[{}]", note.get_id());
         let limit = limit.unwrap_or(SIMILARS_DEFAULT_LIMIT);
         let threshold = threshold.unwrap_or(SIMILARS_DEFAULT_THRESHOLD);
-        let mut result_text_blocks = self
-            .embed_store
-            .get_similar_to(&note.get_text())
-            .limit(limit)
-            .threshold(threshold)
-            .execute()
-            .await
-            .map_err(|e| NotebookError::EmbeddingError(e.to_string()))?;
-        // remove the result_text_blocks where id == note.id
-        result_text_blocks.retain(|item| item.id != note.get_id());
-        let note_ids = result_text_blocks
+        let pending_embeddings = self.models_store.count_pending_embeddings().await?;
+        // a long note is queried passage by passage as the model cuts text at its token limit,
+        // other notes are scored by their closest pair of passages
+        let chunks = chunk_note(
+            note.get_id(),
+            note.get_text(),
+            CHUNK_MAX_CHARS,
+            CHUNK_OVERLAP_CHARS,
+        );
+        let queries: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        let mut closest = self
+            .closest_notes(&queries, limit * SIMILARS_CANDIDATE_FACTOR + 1, threshold)
+            .await?;
+        closest.retain(|(note_id, _, _)| note_id != note.get_id());
+        let note_ids = closest
             .iter()
-            .map(|block| &block.id as &str)
+            .map(|(note_id, _, _)| note_id as &str)
             .collect::<Vec<&str>>();
         let result_notes = self.models_store.get_notes_by_ids(note_ids).await?;
         let notes_map: HashMap<String, Note> = result_notes
@@ -218,147 +958,318 @@ impl Notebook {
             .map(|note| (note.get_id().to_string(), note))
             .collect();
 
-        let combined: Vec<(Note, f32)> = result_text_blocks
-            .iter()
-            .filter_map(|block| {
+        // archived notes are kept out of the working set
+        let combined: Vec<SimilarNote> = closest
+            .into_iter()
+            .filter_map(|(note_id, distance, passage)| {
                 notes_map
-                    .get(&block.id)
-                    .map(|note| (note.clone(), block.distance as f32))
+                    .get(&note_id)
+                    .filter(|note| !note.get_flags().archived)
+                    .map(|note| SimilarNote {
+                        note: note.clone(),
+                        distance,
+                        passage,
+                    })
             })
+            .take(limit)
             .collect();
         if combined.is_empty() {
             info!("No similar note found at threshold: {}", threshold)
         }
-        Ok(combined)
+        Ok(SimilarityResults::new(combined, pending_embeddings))
     }
 
-    pub async fn export_notes(
+    /// Notes with chunks closest to any of the `queries`, scored by their closest chunk and
+    /// returned closest first as (note id, distance, passage of the closest chunk)
+    async fn closest_notes(
         &self,
-        export_path: PathBuf,
-    ) -> Result<(usize, String), NotebookError> {
-        if !self.is_writable(&export_path) {
-            return Err(NotebookError::FileAccess(format!(
-                "{:?} is not writable",
-                export_path
-            )));
+        queries: &[&str],
+        limit: usize,
+        threshold: f32,
+    ) -> Result<Vec<(String, f32, Passage)>, NotebookError> {
+        // fail fast rather than wait for the worker, which may be loading the engine
+        self.ensure_engine_available()?;
+        let mut matches = Vec::new();
+        for query in queries {
+            let similar = self
+                .embed_store
+                .find_similar(query, limit * CHUNKS_PER_NOTE_FACTOR, threshold)
+                .await?;
+            matches.extend(similar.into_iter().map(|similar| ChunkMatch {
+                chunk_id: similar.id,
+                text: similar.text,
+                distance: similar.distance,
+            }));
         }
+        let closest = closest_chunk_per_note(matches);
+        let chunk_ids = closest
+            .iter()
+            .map(|(_, chunk)| &chunk.chunk_id as &str)
+            .collect::<Vec<&str>>();
+        let ranges = self.models_store.get_chunk_ranges(&chunk_ids).await?;
+        Ok(closest
+            .into_iter()
+            .take(limit)
+            .map(|(note_id, chunk)| {
+                // vectors written before notes were chunked cover the whole note
+                let (start, end) = ranges
+                    .get(&chunk.chunk_id)
+                    .copied()
+                    .unwrap_or((0, chunk.text.chars().count()));
+                let passage = Passage {
+                    text: chunk.text,
+                    start,
+                    end,
+                };
+                (note_id, chunk.distance, passage)
+            })
+            .collect())
+    }
 
-        let export_dir = self.create_export_directory(&export_path)?;
-        let notes = self.get_notes().await?;
-
-        for (index, note) in notes.iter().enumerate() {
-            let mut note_title = self.note_title(&note);
-            let mut note_file_path = export_dir.join(format!("{}.md", note_title));
-
-            if note_file_path.exists() {
-                note_title = format!("{}-dupe_{}", note_title, index);
-                note_file_path = export_dir.join(format!("{}.md", note_title));
-            }
-
-            self.write_note_to_file(&note, &note_file_path)?;
+    /// Keyword search over note content using the FTS5 query syntax, ex:
+    /// `"exact phrase"`, `prefix*`, `rust AND (async OR tokio) NOT wasm`
+    pub async fn search_notes(
+        &self,
+        query: &str,
+        limit: Option<usize>,
+        archived: ArchiveFilter,
+    ) -> Result<Vec<SearchHit>, NotebookError> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Ok(vec![]);
         }
+        let limit = limit.unwrap_or(SEARCH_DEFAULT_LIMIT);
+        let matches = self
+            .models_store
+            .search_notes(query, limit, archived)
+            .await?;
+        let note_ids = matches
+            .iter()
+            .map(|(id, _, _)| id as &str)
+            .collect::<Vec<&str>>();
+        let result_notes = self.models_store.get_notes_by_ids(note_ids).await?;
+        let notes_map: HashMap<String, Note> = result_notes
+            .into_iter()
+            .map(|note| (note.get_id().to_string(), note))
+            .collect();
 
-        Ok((notes.len(), export_dir.to_string_lossy().into_owned()))
+        let hits: Vec<SearchHit> = matches
+            .into_iter()
+            .filter_map(|(id, score, snippet)| {
+                notes_map.get(&id).map(|note| SearchHit {
+                    note: note.clone(),
+                    score,
+                    snippet,
+                })
+            })
+            .collect();
+        info!("Found [{}] notes matching the query", hits.len());
+        Ok(hits)
     }
 
-    pub async fn import_notes(&self, import_path: &Path) -> Result<usize, NotebookError> {
-        if !import_path.exists() {
-            return Err(NotebookError::FileAccess(format!(
-                "{:?} does not exist",
-                import_path
-            )));
-        }
-
-        if !import_path.is_dir() {
-            return Err(NotebookError::FileAccess(format!(
-                "{:?} is not a directory",
-                import_path
-            )));
+    /// Runs keyword and semantic search for the same query and merges the two rankings with
+    /// reciprocal rank fusion. Queries that are not valid FTS syntax are searched as plain terms.
+    pub async fn hybrid_search(
+        &self,
+        query: &str,
+        limit: Option<usize>,
+        weights: Option<RankFusionWeights>,
+        archived: ArchiveFilter,
+    ) -> Result<SimilarityResults<HybridHit>, NotebookError> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Ok(SimilarityResults::new(vec![], 0));
         }
+        let limit = limit.unwrap_or(HYBRID_DEFAULT_LIMIT);
+        let weights = weights.unwrap_or_default();
+        let candidates = limit * HYBRID_CANDIDATE_FACTOR;
+        info!("Hybrid search with weights {:?}", weights);
 
-        let mut imported_notes = Vec::new();
-
-        for entry in
-        fs::read_dir(&import_path).map_err(|e| NotebookError::FileAccess(e.to_string()))?
+        let keyword_matches = match self
+            .models_store
+            .search_notes(query, candidates, archived)
+            .await
         {
-            let entry = entry.map_err(|e| NotebookError::FileAccess(e.to_string()))?;
-            let path = entry.path();
-
-            if path.is_file() && path.extension().map_or(false, |ext| ext == "md") {
-                let content = fs::read_to_string(&path)
-                    .map_err(|e| NotebookError::FileAccess(e.to_string()))?;
-                let note = Note::new(&Notebook::generate_id(), &content);
-                // Store the note in the models Db
-                self.models_store.add_note(&note).await?;
-                imported_notes.push(note);
+            // the FTS error quotes the query, so it stays out of the log
+            Err(NotebookError::SearchQuery(_)) => {
+                info!("Query is not valid FTS syntax, searching as plain terms");
+                self.models_store
+                    .search_notes(&quote_fts_terms(query), candidates, archived)
+                    .await?
             }
-        }
-        // store all the embeddings for the notes
-        let text_chunks: Vec<TextChunk> = imported_notes
+            result => result?,
+        };
+        let pending_embeddings = self.models_store.count_pending_embeddings().await?;
+        let mut semantic_unavailable = None;
+        let semantic_matches = match self
+            .closest_notes(&[query], candidates, SIMILARS_DEFAULT_THRESHOLD)
+            .await
+        {
+            Err(NotebookError::EmbeddingUnavailable(reason)) => {
+                info!(
+                    "Semantic search unavailable ({}), ranking by keyword only",
+                    reason
+                );
+                semantic_unavailable = Some(reason);
+                vec![]
+            }
+            result => result?,
+        };
+
+        // notes of both retrievers are fetched before fusing, so semantic matches the archive
+        // filter leaves out don't take a place in the results
+        let candidate_ids = keyword_matches
             .iter()
-            .map(|note| note.to_text_chunk())
+            .map(|(id, _, _)| id as &str)
+            .chain(semantic_matches.iter().map(|(id, _, _)| id as &str))
+            .collect::<HashSet<&str>>();
+        let result_notes = self
+            .models_store
+            .get_notes_by_ids(candidate_ids.into_iter().collect())
+            .await?;
+        let notes_map: HashMap<String, Note> = result_notes
+            .into_iter()
+            .filter(|note| archived.allows(note.get_flags().archived))
+            .map(|note| (note.get_id().to_string(), note))
             .collect();
-        self.embed_store.upsert_texts(&text_chunks).await?;
-
-        Ok(imported_notes.len())
-    }
 
-    fn note_title(&self, note: &Note) -> String {
-        let mut title = note.get_text().lines().next().unwrap_or("").to_string();
-
-        // Strip any leading "#" or spaces from the title
-        while title.starts_with('#') || title.starts_with(' ') {
-            title.remove(0);
-        }
+        let keyword_ranking: Vec<&str> = keyword_matches
+            .iter()
+            .map(|(id, _, _)| id as &str)
+            .collect();
+        let semantic_ranking: Vec<&str> = semantic_matches
+            .iter()
+            .map(|(id, _, _)| id as &str)
+            .filter(|id| notes_map.contains_key(*id))
+            .collect();
+        let mut fused = reciprocal_rank_fusion(&keyword_ranking, &semantic_ranking, weights);
+        fused.truncate(limit);
 
-        // Replace any characters not suitable for a file name with "_"
-        title = title
-            .chars()
-            .map(|c| match c {
-                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
-                _ => '_',
+        let hits: Vec<HybridHit> = fused
+            .into_iter()
+            .filter_map(|(id, score)| {
+                let keyword_match = keyword_matches.iter().find(|(kid, _, _)| *kid == id);
+                let semantic_match = semantic_matches.iter().find(|(sid, _, _)| *sid == id);
+                notes_map.get(&id).map(|note| HybridHit {
+                    note: note.clone(),
+                    score,
+                    keyword_score: keyword_match.map(|(_, bm25, _)| *bm25),
+                    semantic_distance: semantic_match.map(|(_, distance, _)| *distance),
+                    snippet: keyword_match.map(|(_, _, snippet)| snippet.clone()),
+                    passage: semantic_match.map(|(_, _, passage)| passage.clone()),
+                })
             })
             .collect();
+        info!("Hybrid search found [{}] notes", hits.len());
+        Ok(SimilarityResults {
+            semantic_unavailable,
+            ..SimilarityResults::new(hits, pending_embeddings)
+        })
+    }
 
-        // Ensure the final title string does not have multiple "_"'s in a row
-        while title.contains("__") {
-            title = title.replace("__", "_");
+    /// Copies a file into the notebook and attaches it to a note
+    pub async fn add_attachment(
+        &self,
+        note_id: &str,
+        source_path: &Path,
+    ) -> Result<Attachment, NotebookError> {
+        if self.get_note_by_id(note_id).await?.is_none() {
+            return Err(NotebookError::NoteNotFound(note_id.to_string()));
         }
-
-        // If title is longer than 100 characters, take the first 50 and the last 50, combine them with a "..." in the middle
-        if title.len() > 100 {
-            let (start, end) = title.split_at(50);
-            title = format!("{}...{}", start, end.split_at(end.len() - 50).1);
+        if !source_path.is_file() {
+            return Err(NotebookError::FileAccess(format!(
+                "{:?} is not a file",
+                source_path
+            )));
         }
+        let file_name = source_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or(NotebookError::FileAccess(format!(
+                "{:?} has no file name",
+                source_path
+            )))?;
+        let _writes = self.attachments.lock_writes().await;
+        let (hash, size) = self.attachments.store(source_path)?;
+        let attachment = Attachment {
+            id: Notebook::generate_id(),
+            note_id: note_id.to_string(),
+            file_name,
+            hash,
+            size,
+            added: Self::get_now(),
+        };
+        self.models_store.add_attachment(&attachment).await?;
+        Ok(attachment)
+    }
 
-        title
+    pub async fn list_attachments(&self, note_id: &str) -> Result<Vec<Attachment>, NotebookError> {
+        self.models_store.get_attachments(note_id).await
     }
 
-    fn is_writable(&self, path: &PathBuf) -> bool {
-        if let Ok(metadata) = fs::metadata(path) {
-            if metadata.is_dir() {
-                if let Ok(permissions) = fs::metadata(path).map(|m| m.permissions()) {
-                    return permissions.readonly() == false;
-                }
-            }
+    /// Detaches a file from its note, the stored file is deleted once no note uses it
+    pub async fn remove_attachment(&self, attachment_id: &str) -> Result<(), NotebookError> {
+        if !self.models_store.remove_attachment(attachment_id).await? {
+            return Err(NotebookError::AttachmentNotFound(attachment_id.to_string()));
         }
-        false
+        self.collect_attachment_garbage().await?;
+        Ok(())
     }
 
-    fn create_export_directory(&self, export_path: &PathBuf) -> Result<PathBuf, NotebookError> {
-        let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
-        let export_dir_name = format!("knowling_export_{}", timestamp);
-        let export_dir = export_path.join(&export_dir_name);
-        fs::create_dir_all(&export_dir).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
-        Ok(export_dir)
+    /// Copies an attachment out under its original file name, ready to be opened by the OS
+    pub async fn get_attachment_for_opening(
+        &self,
+        attachment_id: &str,
+    ) -> Result<PathBuf, NotebookError> {
+        let attachment = self
+            .models_store
+            .get_attachment(attachment_id)
+            .await?
+            .ok_or(NotebookError::AttachmentNotFound(attachment_id.to_string()))?;
+        self.attachments
+            .copy_out(&attachment, &self.attachments.open_dir(&attachment))
     }
 
-    fn write_note_to_file(&self, note: &Note, file_path: &PathBuf) -> Result<(), NotebookError> {
-        let mut file =
-            fs::File::create(file_path).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
-        file.write_all(note.get_text().as_bytes())
-            .map_err(|e| NotebookError::FileAccess(e.to_string()))?;
-        Ok(())
+    /// Deletes stored files that no note is attached to, returns the number of files deleted
+    pub async fn collect_attachment_garbage(&self) -> Result<usize, NotebookError> {
+        // commands share the notebook, an attachment may be added while this runs
+        let _writes = self.attachments.lock_writes().await;
+        let listed_at = SystemTime::now();
+        let in_use = self.models_store.delete_unreferenced_attachments().await?;
+        let deleted = self.attachments.remove_unused(&in_use, listed_at)?;
+        info!("Deleted [{}] unused attachment files", deleted);
+        Ok(deleted)
+    }
+
+    /// The notes and attachments to export, written by `NotesExport::write_to` once the
+    /// notebook is released
+    pub async fn export_snapshot(&self) -> Result<NotesExport, NotebookError> {
+        let mut notes = Vec::new();
+        for note in self.get_notes(ArchiveFilter::Include).await? {
+            let attachments = self.list_attachments(note.get_id()).await?;
+            notes.push((note, attachments));
+        }
+        Ok(NotesExport::new(notes, self.attachments.clone()))
+    }
+
+    /// Adds the notes read by `transfer::read_import`, with their attachments. A note or
+    /// attachment that can't be added is reported and the import carries on with the others.
+    pub async fn add_imported_notes(&self, notes: &[ImportedNote]) -> ImportReport {
+        let mut report = ImportReport::default();
+        for imported in notes {
+            let note = Note::new(&Notebook::generate_id(), &imported.content);
+            if let Err(e) = self.write_note(&note, true, Some("Imported")).await {
+                report.fail(&imported.file, e);
+                continue;
+            }
+            report.imported += 1;
+            for path in &imported.attachments {
+                if let Err(e) = self.add_attachment(note.get_id(), path).await {
+                    report.fail(path, e);
+                }
+            }
+        }
+        report
     }
 
     /// Generates a random id for a Document.
@@ -371,6 +1282,12 @@ impl Notebook {
     }
 }
 
+impl Drop for Notebook {
+    fn drop(&mut self) {
+        self.embedding_queue.stop();
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum NotebookError {
     #[error("Persistence error: {0}")]
@@ -387,6 +1304,45 @@ pub enum NotebookError {
 
     #[error("Note not found: {0}")]
     NoteNotFound(String),
+
+    #[error("Invalid search query: {0}")]
+    SearchQuery(String),
+
+    #[error("Models db schema error: {0}")]
+    SchemaVersion(String),
+
+    #[error("Revision not found: {0}")]
+    RevisionNotFound(String),
+
+    #[error("Category not found: {0}")]
+    CategoryNotFound(String),
+
+    #[error("Invalid category operation: {0}")]
+    InvalidCategory(String),
+
+    #[error("Attachment not found: {0}")]
+    AttachmentNotFound(String),
+
+    #[error("Notebook error: {0}")]
+    Workspace(String),
+
+    #[error("Unknown embedding model: {0}")]
+    UnknownEmbeddingModel(String),
+
+    #[error("Semantic features are unavailable: {0}")]
+    EmbeddingUnavailable(String),
+
+    #[error("Model cache error: {0}")]
+    ModelCache(String),
+
+    #[error("Notebook '{0}' is locked")]
+    NotebookLocked(String),
+
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Backup error: {0}")]
+    Backup(String),
 }
 
 // rusqlite::Error does not implement Serialize, so we adapt it to a String
@@ -424,15 +1380,748 @@ impl Serialize for NotebookError {
                 state.serialize_field("type", "NoteNotFound")?;
                 state.serialize_field("error", err)?;
             }
+            NotebookError::SearchQuery(err) => {
+                state.serialize_field("type", "SearchQuery")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::SchemaVersion(err) => {
+                state.serialize_field("type", "SchemaVersion")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::RevisionNotFound(err) => {
+                state.serialize_field("type", "RevisionNotFound")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::CategoryNotFound(err) => {
+                state.serialize_field("type", "CategoryNotFound")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::InvalidCategory(err) => {
+                state.serialize_field("type", "InvalidCategory")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::AttachmentNotFound(err) => {
+                state.serialize_field("type", "AttachmentNotFound")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::Workspace(err) => {
+                state.serialize_field("type", "Workspace")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::UnknownEmbeddingModel(err) => {
+                state.serialize_field("type", "UnknownEmbeddingModel")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::EmbeddingUnavailable(err) => {
+                state.serialize_field("type", "EmbeddingUnavailable")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::ModelCache(err) => {
+                state.serialize_field("type", "ModelCache")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::NotebookLocked(err) => {
+                state.serialize_field("type", "NotebookLocked")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::Encryption(err) => {
+                state.serialize_field("type", "Encryption")?;
+                state.serialize_field("error", err)?;
+            }
+            NotebookError::Backup(err) => {
+                state.serialize_field("type", "Backup")?;
+                state.serialize_field("error", err)?;
+            }
         }
         state.end()
     }
 }
 
+/// Opens a notebook in `dir` over an in-memory vector store whose engine is ready
+#[cfg(test)]
+pub(crate) async fn open_test_notebook(dir: &Path) -> Notebook {
+    Notebook::with_vector_store(
+        EmbeddingModelChoice::default(),
+        Arc::new(in_memory_store::InMemoryVectorStore::default()),
+        Arc::new(RwLock::new(EngineStatus::Ready)),
+        dir,
+        None,
+    )
+    .await
+    .unwrap()
+}
+
 #[cfg(test)]
 mod tests {
-    // #[test]
-    // fn test_notebook_new() {
-    //     // todo!("I think i should use dependency injection for the embedding model")
-    // }
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::notebook::in_memory_store::{BagOfWordsEmbedder, InMemoryVectorStore};
+
+    async fn test_notebook() -> (Notebook, TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let notebook = open_test_notebook(dir.path()).await;
+        (notebook, dir)
+    }
+
+    /// Does what the embedding worker would
+    async fn embed_pending(notebook: &Notebook) {
+        while !notebook
+            .embedding_queue
+            .process_batch(EMBED_BATCH_SIZE)
+            .await
+            .unwrap()
+            .is_empty()
+        {}
+    }
+
+    #[tokio::test]
+    async fn test_upsert_and_get_note_similars() {
+        let (notebook, _dir) = test_notebook().await;
+        let rust = notebook
+            .upsert_note(None, "# Rust\nownership borrowing and lifetimes in rust")
+            .await
+            .unwrap();
+        let borrowck = notebook
+            .upsert_note(
+                None,
+                "# Borrow checker\nthe rust borrowing rules, lifetimes",
+            )
+            .await
+            .unwrap();
+        notebook
+            .upsert_note(None, "# Bread\nflour water yeast salt")
+            .await
+            .unwrap();
+        embed_pending(&notebook).await;
+        assert!(notebook.verify_index().await.unwrap().is_consistent());
+
+        let similars = notebook
+            .get_note_similars(rust.clone(), None, Some(0.3))
+            .await
+            .unwrap();
+        assert!(!similars.may_be_stale);
+        let ids: Vec<&str> = similars.results.iter().map(|s| s.note.get_id()).collect();
+        assert_eq!(vec![borrowck.get_id()], ids);
+        assert_eq!(borrowck.get_text(), similars.results[0].passage.text);
+
+        // edits are embedded in the background, until then results are flagged
+        notebook
+            .upsert_note(Some(rust.get_id()), "# Rust\nasync runtimes")
+            .await
+            .unwrap();
+        let similars = notebook
+            .get_note_similars(rust, None, Some(0.3))
+            .await
+            .unwrap();
+        assert!(similars.may_be_stale);
+    }
+
+    /// A month of journal entries, only the last passage of which is about baking
+    fn journal_text() -> String {
+        let mut text = "# Journal\n\n".to_string();
+        for day in 0..30 {
+            text.push_str(&format!(
+                "Day {} spent on meetings and email triage.\n\n",
+                day
+            ));
+        }
+        text.push_str("## Baking\n\nFed the sourdough starter with rye flour.\n");
+        text
+    }
+
+    #[tokio::test]
+    async fn test_long_note_matches_by_passage() {
+        let (notebook, _dir) = test_notebook().await;
+        let text = journal_text();
+        let journal = notebook.upsert_note(None, &text).await.unwrap();
+        embed_pending(&notebook).await;
+
+        let hits = notebook
+            .hybrid_search("sourdough starter rye", None, None, ArchiveFilter::Exclude)
+            .await
+            .unwrap();
+        assert_eq!(journal.get_id(), hits.results[0].note.get_id());
+        let passage = hits.results[0].passage.clone().unwrap();
+        assert!(passage.text.contains("sourdough"));
+        let from_range: String = text
+            .chars()
+            .skip(passage.start)
+            .take(passage.end - passage.start)
+            .collect();
+        assert_eq!(passage.text, from_range);
+    }
+
+    #[tokio::test]
+    async fn test_long_note_similars_match_by_passage() {
+        let (notebook, _dir) = test_notebook().await;
+        let text = journal_text();
+        let journal = notebook.upsert_note(None, &text).await.unwrap();
+        let baking = notebook
+            .upsert_note(None, "# Sourdough\nfeed the starter rye flour")
+            .await
+            .unwrap();
+        embed_pending(&notebook).await;
+
+        // the whole journal is too far from the baking note, its last passage is not
+        let similars = notebook
+            .get_note_similars(journal.clone(), None, Some(0.5))
+            .await
+            .unwrap();
+        let ids: Vec<&str> = similars.results.iter().map(|s| s.note.get_id()).collect();
+        assert_eq!(vec![baking.get_id()], ids);
+        let similars = notebook
+            .get_note_similars(baking, None, Some(0.5))
+            .await
+            .unwrap();
+        assert!(similars.results[0].passage.text.contains("sourdough"));
+    }
+
+    #[tokio::test]
+    async fn test_archived_notes_leave_the_working_set() {
+        let (notebook, _dir) = test_notebook().await;
+        let rust = notebook
+            .upsert_note(None, "# Rust\nownership borrowing and lifetimes in rust")
+            .await
+            .unwrap();
+        let borrowck = notebook
+            .upsert_note(
+                None,
+                "# Borrow checker\nthe rust borrowing rules, lifetimes",
+            )
+            .await
+            .unwrap();
+        embed_pending(&notebook).await;
+
+        let archived = notebook
+            .set_note_flag(borrowck.get_id(), NoteFlag::Archived, true)
+            .await
+            .unwrap();
+        assert!(archived.get_flags().archived);
+        assert_eq!(borrowck.get_modified(), archived.get_modified());
+        assert!(matches!(
+            notebook
+                .set_note_flag("missing", NoteFlag::Pinned, true)
+                .await,
+            Err(NotebookError::NoteNotFound(_))
+        ));
+
+        let similars = notebook
+            .get_note_similars(rust.clone(), None, Some(0.3))
+            .await
+            .unwrap();
+        assert!(similars.results.is_empty());
+        let hits = notebook
+            .hybrid_search("borrowing rules", None, None, ArchiveFilter::Exclude)
+            .await
+            .unwrap();
+        assert!(hits
+            .results
+            .iter()
+            .all(|hit| hit.note.get_id() != borrowck.get_id()));
+        let hits = notebook
+            .hybrid_search("borrowing rules", None, None, ArchiveFilter::Only)
+            .await
+            .unwrap();
+        let ids: Vec<&str> = hits.results.iter().map(|hit| hit.note.get_id()).collect();
+        assert_eq!(vec![borrowck.get_id()], ids);
+
+        let ids = [rust.get_id(), borrowck.get_id()];
+        assert_eq!(
+            2,
+            notebook
+                .set_notes_flag(&ids, NoteFlag::Archived, false)
+                .await
+                .unwrap()
+        );
+        let similars = notebook
+            .get_note_similars(rust, None, Some(0.3))
+            .await
+            .unwrap();
+        assert_eq!(1, similars.results.len());
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_search_falls_back_to_plain_terms() {
+        let (notebook, _dir) = test_notebook().await;
+        let note = notebook
+            .upsert_note(None, "# Build\nerror: E0502 cannot borrow as mutable")
+            .await
+            .unwrap();
+        notebook
+            .upsert_note(None, "# Garden\ntomatoes and basil")
+            .await
+            .unwrap();
+        embed_pending(&notebook).await;
+
+        let hits = notebook
+            .hybrid_search("error: E0502", None, None, ArchiveFilter::Exclude)
+            .await
+            .unwrap();
+        assert_eq!(note.get_id(), hits.results[0].note.get_id());
+    }
+
+    #[tokio::test]
+    async fn test_degraded_until_engine_loads() {
+        tokio::time::pause();
+        let dir = tempfile::tempdir().unwrap();
+        let offline = Arc::new(AtomicBool::new(true));
+        let loader_offline = offline.clone();
+        let loader: EngineLoader = Box::new(move || {
+            let offline = loader_offline.load(Ordering::SeqCst);
+            Box::pin(async move {
+                if offline {
+                    Err(NotebookError::EmbeddingError(
+                        "model download failed".to_string(),
+                    ))
+                } else {
+                    Ok(Box::new(InMemoryVectorStore::default()) as Box<dyn VectorStore>)
+                }
+            })
+        });
+        let engine_status = Arc::new(RwLock::new(EngineStatus::Loading));
+        let embed_store = LazyVectorStore::new(loader, engine_status.clone());
+        let notebook = Notebook::with_vector_store(
+            EmbeddingModelChoice::default(),
+            Arc::new(embed_store),
+            engine_status,
+            dir.path(),
+            None,
+        )
+        .await
+        .unwrap();
+        // left to load to the worker, semantic features fail fast rather than wait for it
+        assert_eq!(
+            EngineStatus::Loading,
+            notebook.get_embedding_status().await.unwrap().engine
+        );
+        let note = notebook
+            .upsert_note(None, "# Loading\nsaved while it loads")
+            .await
+            .unwrap();
+        assert!(matches!(
+            notebook.get_note_similars(note, None, None).await,
+            Err(NotebookError::EmbeddingUnavailable(_))
+        ));
+        assert!(matches!(
+            notebook
+                .embedding_queue
+                .process_batch(EMBED_BATCH_SIZE)
+                .await,
+            Err(NotebookError::EmbeddingUnavailable(_))
+        ));
+        assert!(matches!(
+            notebook.get_embedding_status().await.unwrap().engine,
+            EngineStatus::Unavailable { .. }
+        ));
+
+        let note = notebook
+            .upsert_note(None, "# Offline\nwritten without a model")
+            .await
+            .unwrap();
+        let hits = notebook
+            .search_notes("model", None, ArchiveFilter::Exclude)
+            .await
+            .unwrap();
+        assert_eq!(1, hits.len());
+        assert!(matches!(
+            notebook.get_note_similars(note, None, None).await,
+            Err(NotebookError::EmbeddingUnavailable(_))
+        ));
+        let hits = notebook
+            .hybrid_search("model", None, None, ArchiveFilter::Exclude)
+            .await
+            .unwrap();
+        assert_eq!(1, hits.results.len());
+        assert!(hits.semantic_unavailable.is_some());
+
+        // the queued note is embedded once the engine loads
+        offline.store(false, Ordering::SeqCst);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        embed_pending(&notebook).await;
+        let status = notebook.get_embedding_status().await.unwrap();
+        assert_eq!(EngineStatus::Ready, status.engine);
+        assert_eq!(0, status.pending_embeddings);
+        assert!(notebook.verify_index().await.unwrap().is_consistent());
+    }
+
+    #[tokio::test]
+    async fn test_semantic_features_fail_fast_while_engine_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        // a model download that never finishes
+        let loader: EngineLoader = Box::new(|| Box::pin(futures::future::pending()));
+        let engine_status = Arc::new(RwLock::new(EngineStatus::Loading));
+        let embed_store = LazyVectorStore::new(loader, engine_status.clone());
+        let notebook = Notebook::with_vector_store(
+            EmbeddingModelChoice::default(),
+            Arc::new(embed_store),
+            engine_status,
+            dir.path(),
+            None,
+        )
+        .await
+        .unwrap();
+        let note = notebook
+            .upsert_note(None, "# Waiting\nfor the model")
+            .await
+            .unwrap();
+        let queue = notebook.embedding_queue.clone();
+        let loading = tokio::spawn(async move { queue.process_batch(EMBED_BATCH_SIZE).await });
+        tokio::task::yield_now().await;
+
+        let wait = Duration::from_secs(5);
+        let similars = tokio::time::timeout(wait, notebook.get_note_similars(note, None, None));
+        assert!(matches!(
+            similars.await.unwrap(),
+            Err(NotebookError::EmbeddingUnavailable(_))
+        ));
+        let search = notebook.hybrid_search("model", None, None, ArchiveFilter::Exclude);
+        let hits = tokio::time::timeout(wait, search).await.unwrap().unwrap();
+        assert_eq!(1, hits.results.len());
+        assert!(hits.semantic_unavailable.is_some());
+        let report = tokio::time::timeout(wait, notebook.verify_index())
+            .await
+            .unwrap();
+        assert!(!report.unwrap().vectors_listed);
+        loading.abort();
+    }
+
+    #[tokio::test]
+    async fn test_categories() {
+        let (notebook, _dir) = test_notebook().await;
+        let note = notebook.upsert_note(None, "# Tokio\ntasks").await.unwrap();
+        let note = notebook
+            .add_category_to_note(note.get_id(), "Programming/Rust")
+            .await
+            .unwrap();
+        let category = note.get_categories().iter().next().unwrap().clone();
+        assert_eq!("Rust", category.get_label());
+        let in_category = notebook
+            .get_notes_in_category(category.get_id())
+            .await
+            .unwrap();
+        assert_eq!(1, in_category.len());
+        let summaries = notebook.list_categories().await.unwrap();
+        assert!(summaries
+            .iter()
+            .any(|summary| summary.category == category && summary.note_count == 1));
+
+        let note = notebook
+            .remove_category_from_note(note.get_id(), category.get_id())
+            .await
+            .unwrap();
+        assert!(note.get_categories().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_export_import_round_trip() {
+        let (notebook, dir) = test_notebook().await;
+        let one = notebook.upsert_note(None, "# One\nfirst").await.unwrap();
+        notebook.upsert_note(None, "# Two\nsecond").await.unwrap();
+        // two attachments by the same name are both exported
+        for (subdir, content) in [("draft", "first draft"), ("final", "final version")] {
+            let file = dir.path().join(subdir).join("Report.txt");
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, content).unwrap();
+            notebook.add_attachment(one.get_id(), &file).await.unwrap();
+        }
+        let export = notebook.export_snapshot().await.unwrap();
+        // written as the notes were when the snapshot was taken
+        notebook
+            .upsert_note(None, "# Three\nafter the snapshot")
+            .await
+            .unwrap();
+        let (exported, export_dir) = export.write_to(dir.path()).unwrap();
+        assert_eq!(2, exported);
+
+        let (imported_into, _imported_dir) = test_notebook().await;
+        let notes = transfer::read_import(Path::new(&export_dir)).unwrap();
+        let imported = imported_into.add_imported_notes(&notes).await;
+        assert_eq!(2, imported.imported);
+        assert!(imported.failed.is_empty());
+        let mut texts: Vec<String> = imported_into
+            .get_notes(ArchiveFilter::Exclude)
+            .await
+            .unwrap()
+            .iter()
+            .map(|note| note.get_text().to_string())
+            .collect();
+        texts.sort();
+        assert_eq!(vec!["# One\nfirst", "# Two\nsecond"], texts);
+        let mut attached: Vec<Vec<String>> = notes
+            .iter()
+            .map(|note| {
+                let mut names: Vec<String> = note
+                    .attachments
+                    .iter()
+                    .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                    .collect();
+                names.sort();
+                names
+            })
+            .collect();
+        attached.sort();
+        assert_eq!(
+            vec![
+                vec![],
+                vec!["Report-2.txt".to_string(), "Report.txt".to_string()]
+            ],
+            attached
+        );
+        embed_pending(&imported_into).await;
+        let report = imported_into.verify_index().await.unwrap();
+        assert!(report.is_consistent());
+        assert_eq!(2, report.notes_checked);
+    }
+
+    #[tokio::test]
+    async fn test_export_skips_attachments_removed_meanwhile() {
+        let (notebook, dir) = test_notebook().await;
+        let note = notebook.upsert_note(None, "# One\nfirst").await.unwrap();
+        let file = dir.path().join("Report.txt");
+        fs::write(&file, "first draft").unwrap();
+        let attachment = notebook.add_attachment(note.get_id(), &file).await.unwrap();
+        let export = notebook.export_snapshot().await.unwrap();
+        notebook.remove_attachment(&attachment.id).await.unwrap();
+
+        let (exported, export_dir) = export.write_to(dir.path()).unwrap();
+        assert_eq!(1, exported);
+        let notes = transfer::read_import(Path::new(&export_dir)).unwrap();
+        assert_eq!("# One\nfirst", notes[0].content);
+        assert!(notes[0].attachments.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_import_reports_failed_attachments_and_carries_on() {
+        let (notebook, dir) = test_notebook().await;
+        let attachment = dir.path().join("Report.txt");
+        fs::write(&attachment, "first draft").unwrap();
+        let missing = dir.path().join("Missing.txt");
+        let notes = vec![
+            ImportedNote {
+                file: dir.path().join("One.md"),
+                content: "# One\nfirst".to_string(),
+                attachments: vec![missing.clone(), attachment],
+            },
+            ImportedNote {
+                file: dir.path().join("Two.md"),
+                content: "# Two\nsecond".to_string(),
+                attachments: vec![],
+            },
+        ];
+
+        let report = notebook.add_imported_notes(&notes).await;
+        assert_eq!(2, report.imported);
+        let failed: Vec<&str> = report.failed.iter().map(|f| f.file.as_str()).collect();
+        assert_eq!(vec![missing.to_string_lossy()], failed);
+        assert_eq!(
+            2,
+            notebook
+                .get_notes(ArchiveFilter::Exclude)
+                .await
+                .unwrap()
+                .len()
+        );
+        let one = notebook
+            .get_notes(ArchiveFilter::Exclude)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|note| note.get_text().starts_with("# One"))
+            .unwrap();
+        let attached = notebook.list_attachments(one.get_id()).await.unwrap();
+        let names: Vec<&str> = attached.iter().map(|a| a.file_name.as_str()).collect();
+        assert_eq!(vec!["Report.txt"], names);
+    }
+
+    #[tokio::test]
+    async fn test_purged_notes_leave_the_index() {
+        let (notebook, _dir) = test_notebook().await;
+        let kept = notebook
+            .upsert_note(None, "# Kept\nshared words")
+            .await
+            .unwrap();
+        let purged = notebook
+            .upsert_note(None, "# Purged\nshared words")
+            .await
+            .unwrap();
+        embed_pending(&notebook).await;
+        notebook.delete_note(purged.get_id()).await.unwrap();
+        assert_eq!(1, notebook.empty_trash().await.unwrap());
+        embed_pending(&notebook).await;
+
+        assert!(notebook.verify_index().await.unwrap().is_consistent());
+        let similars = notebook
+            .get_note_similars(kept, None, Some(0.1))
+            .await
+            .unwrap();
+        assert!(similars.results.is_empty());
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_existing_notebook() {
+        let (notebook, dir) = test_notebook().await;
+        let note = notebook
+            .upsert_note(None, "# Customer\nAcme renewal for Wile Coyote")
+            .await
+            .unwrap();
+        let contract = dir.path().join("contract.txt");
+        fs::write(&contract, "confidential pricing terms").unwrap();
+        let attachment = notebook
+            .add_attachment(note.get_id(), &contract)
+            .await
+            .unwrap();
+        fs::remove_file(&contract).unwrap();
+        embed_pending(&notebook).await;
+        notebook.close().await;
+        drop(notebook);
+
+        let key = encryption::encrypt_notebook(dir.path(), "correct horse")
+            .await
+            .unwrap();
+        assert!(encryption::is_encrypted(dir.path()));
+        // the models db can't be opened without the key
+        assert!(Notebook::with_vector_store(
+            EmbeddingModelChoice::default(),
+            Arc::new(InMemoryVectorStore::default()),
+            Arc::new(RwLock::new(EngineStatus::Ready)),
+            dir.path(),
+            None,
+        )
+        .await
+        .is_err());
+
+        let conn = open_models_db(&dir.path().join(MODELS_DB_FILE), Some(&key)).unwrap();
+        let embed_store = SqliteVectorStore::new(conn, Box::new(BagOfWordsEmbedder));
+        let notebook = Notebook::with_vector_store(
+            EmbeddingModelChoice::default(),
+            Arc::new(embed_store),
+            Arc::new(RwLock::new(EngineStatus::Ready)),
+            dir.path(),
+            Some(key),
+        )
+        .await
+        .unwrap();
+        let reopened = notebook
+            .get_note_by_id(note.get_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(note.get_text(), reopened.get_text());
+        let opened = notebook
+            .get_attachment_for_opening(&attachment.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            "confidential pricing terms",
+            fs::read_to_string(opened).unwrap()
+        );
+
+        // notes are embedded again, into the encrypted db
+        embed_pending(&notebook).await;
+        let hits = notebook
+            .hybrid_search("renewal coyote", None, None, ArchiveFilter::Exclude)
+            .await
+            .unwrap();
+        assert!(hits.semantic_unavailable.is_none());
+        assert!(hits.results[0].passage.is_some(), "found by its vector");
+        notebook.close().await;
+
+        let db = fs::read(dir.path().join(MODELS_DB_FILE)).unwrap();
+        assert!(!contains(&db, b"Coyote"));
+        let blob = fs::read(notebook.attachments.blob_path(&attachment.hash)).unwrap();
+        assert!(!contains(&blob, b"confidential"));
+    }
+
+    /// Prints the latency of listing and getting notes while an import writes to the notebook
+    /// and the embedding worker runs. Run with
+    /// `cargo test --release bench_reads_during_import -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn bench_reads_during_import() {
+        const IMPORTED_NOTES: usize = 2000;
+        const IDLE_READS: usize = 500;
+
+        async fn timed_reads(notebook: &Notebook, id: &str, latencies: &mut Vec<Duration>) {
+            let start = std::time::Instant::now();
+            notebook
+                .list_notes(&NoteListQuery::default())
+                .await
+                .unwrap();
+            latencies.push(start.elapsed());
+            let start = std::time::Instant::now();
+            notebook.get_note_by_id(id).await.unwrap().unwrap();
+            latencies.push(start.elapsed());
+        }
+
+        fn report(label: &str, mut latencies: Vec<Duration>) {
+            latencies.sort();
+            let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+            println!(
+                "{}: {} reads, p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+                label,
+                latencies.len(),
+                percentile(50),
+                percentile(95),
+                percentile(99),
+                latencies[latencies.len() - 1]
+            );
+        }
+
+        let (notebook, dir) = test_notebook().await;
+        let notebook = Arc::new(notebook);
+        let known = notebook
+            .upsert_note(None, "# Known\nread while importing")
+            .await
+            .unwrap();
+        let import_dir = dir.path().join("import");
+        fs::create_dir_all(&import_dir).unwrap();
+        for i in 0..IMPORTED_NOTES {
+            let text = format!("# Imported {}\n{}", i, "some imported words ".repeat(100));
+            fs::write(import_dir.join(format!("{}.md", i)), text).unwrap();
+        }
+
+        let mut idle = Vec::new();
+        for _ in 0..IDLE_READS {
+            timed_reads(&notebook, known.get_id(), &mut idle).await;
+        }
+        report("idle", idle);
+
+        tokio::spawn(notebook.embedding_worker(|_| {}));
+        let started = std::time::Instant::now();
+        let import = tokio::spawn({
+            let notebook = notebook.clone();
+            async move {
+                let notes = transfer::read_import(&import_dir).unwrap();
+                notebook.add_imported_notes(&notes).await.imported
+            }
+        });
+        let mut during_import = Vec::new();
+        while !import.is_finished() {
+            timed_reads(&notebook, known.get_id(), &mut during_import).await;
+        }
+        assert_eq!(IMPORTED_NOTES, import.await.unwrap());
+        println!(
+            "imported {} notes in {:?}",
+            IMPORTED_NOTES,
+            started.elapsed()
+        );
+        report("during import", during_import);
+        // the same notebook size without a concurrent import, to tell waiting from a bigger list
+        let mut after_import = Vec::new();
+        for _ in 0..IDLE_READS {
+            timed_reads(&notebook, known.get_id(), &mut after_import).await;
+        }
+        report("after import", after_import);
+
+        notebook.close().await;
+    }
 }
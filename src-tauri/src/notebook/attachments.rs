@@ -0,0 +1,337 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use log::info;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+use crate::notebook::encryption::NotebookKey;
+use crate::notebook::NotebookError;
+
+// attachments are copied here, under their own file name, so the OS can pick an app to open them
+const OPEN_DIR: &str = "open";
+const TMP_PREFIX: &str = "tmp-";
+
+/// A file attached to a Note. The file content is stored once per distinct content (by hash)
+/// no matter how many notes it is attached to.
+#[derive(Debug, Clone, Serialize)]
+pub struct Attachment {
+    pub id: String,
+    pub note_id: String,
+    pub file_name: String,
+    pub hash: String,
+    pub size: u64,
+    pub added: i64,
+}
+
+/// Content addressed blob storage for attachments: `<root>/<first 2 hash chars>/<sha256 hash>`.
+/// The hash is that of the file content, blobs of an encrypted notebook are encrypted with its
+/// key.
+#[derive(Clone)]
+pub struct AttachmentStore {
+    root: PathBuf,
+    key: Option<NotebookKey>,
+    // see `lock_writes`
+    writes: Arc<Mutex<()>>,
+}
+
+impl AttachmentStore {
+    pub fn new(root: PathBuf, key: Option<NotebookKey>) -> Result<Self, NotebookError> {
+        fs::create_dir_all(&root).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+        let store = AttachmentStore {
+            root,
+            key,
+            writes: Arc::new(Mutex::new(())),
+        };
+        store.remove_interrupted_copies()?;
+        Ok(store)
+    }
+
+    /// Held from storing a blob until its attachment row is added, and while unused blobs are
+    /// deleted, so a collection never deletes a blob that is about to be referenced
+    pub async fn lock_writes(&self) -> MutexGuard<'_, ()> {
+        self.writes.lock().await
+    }
+
+    /// Copies `source` to `target`, encrypting it with the notebook key if there is one
+    fn write_blob(&self, source: &Path, target: &Path) -> std::io::Result<()> {
+        match &self.key {
+            Some(key) => {
+                let encrypted = key
+                    .encrypt_blob(&fs::read(source)?)
+                    .map_err(|e| std::io::Error::other(e.to_string()))?;
+                fs::write(target, encrypted)
+            }
+            None => fs::copy(source, target).map(|_| ()),
+        }
+    }
+
+    /// Copies a file into the store, returning its (hash, size). A file whose content is
+    /// already stored is not copied again.
+    pub fn store(&self, source: &Path) -> Result<(String, u64), NotebookError> {
+        let (hash, size) = Self::hash_file(source)?;
+        let blob_path = self.blob_path(&hash);
+        if blob_path.exists() {
+            info!("Attachment blob {} already stored", hash);
+            return Ok((hash, size));
+        }
+        let blob_dir = blob_path
+            .parent()
+            .expect("blob paths have a parent directory");
+        fs::create_dir_all(blob_dir).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+        // copy then rename so a partially copied file never sits at a blob path
+        let tmp_path = blob_dir.join(format!("{}{}", TMP_PREFIX, Uuid::new_v4()));
+        self.write_blob(source, &tmp_path)
+            .and_then(|_| fs::rename(&tmp_path, &blob_path))
+            .map_err(|e| {
+                let _ = fs::remove_file(&tmp_path);
+                NotebookError::FileAccess(format!("Unable to store {:?}: {}", source, e))
+            })?;
+        info!("Stored attachment blob {} ({} bytes)", hash, size);
+        Ok((hash, size))
+    }
+
+    pub fn blob_path(&self, hash: &str) -> PathBuf {
+        self.root.join(&hash[..2]).join(hash)
+    }
+
+    /// Every file in the store as (file name, path). The file name of a blob is its hash, any
+    /// other name is a temporary copy left behind by an interrupted store.
+    pub fn stored_files(&self) -> Result<Vec<(String, PathBuf)>, NotebookError> {
+        let read_dir =
+            |dir: &Path| fs::read_dir(dir).map_err(|e| NotebookError::FileAccess(e.to_string()));
+        let mut files = Vec::new();
+        for shard in read_dir(&self.root)? {
+            let shard = shard.map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            if !shard.path().is_dir() || shard.file_name() == OPEN_DIR {
+                continue;
+            }
+            for blob in read_dir(&shard.path())? {
+                let blob = blob.map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+                files.push((blob.file_name().to_string_lossy().into_owned(), blob.path()));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Deletes the blobs not `in_use`, returns how many were deleted. Blobs modified since
+    /// `listed_at`, when `in_use` was read, and copies still being written are left alone.
+    pub fn remove_unused(
+        &self,
+        in_use: &HashSet<String>,
+        listed_at: SystemTime,
+    ) -> Result<usize, NotebookError> {
+        let mut deleted = 0;
+        for (name, path) in self.stored_files()? {
+            if name.starts_with(TMP_PREFIX) || in_use.contains(&name) {
+                continue;
+            }
+            let modified = fs::metadata(&path).and_then(|metadata| metadata.modified());
+            if !modified.is_ok_and(|modified| modified < listed_at) {
+                continue;
+            }
+            fs::remove_file(&path).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    /// Deletes the temporary copies left behind by a store that was interrupted, which is only
+    /// safe before the store is used
+    fn remove_interrupted_copies(&self) -> Result<(), NotebookError> {
+        for (name, path) in self.stored_files()? {
+            if name.starts_with(TMP_PREFIX) {
+                fs::remove_file(&path).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds every blob to the store rooted at `target_root`, hard linking them where possible as
+    /// blobs are never modified in place
+    pub fn link_blobs_into(&self, target_root: &Path) -> Result<(), NotebookError> {
+        for (name, path) in self.stored_files()? {
+            if name.starts_with(TMP_PREFIX) {
+                continue;
+            }
+            let shard = path
+                .parent()
+                .and_then(|shard| shard.file_name())
+                .expect("blobs are in a shard directory");
+            let target_dir = target_root.join(shard);
+            fs::create_dir_all(&target_dir)
+                .and_then(|_| {
+                    let target = target_dir.join(&name);
+                    fs::hard_link(&path, &target).or_else(|_| fs::copy(&path, &target).map(|_| ()))
+                })
+                .map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Copies an attachment out of the store under its original file name so it can be opened
+    /// by the OS
+    pub fn copy_out(
+        &self,
+        attachment: &Attachment,
+        target_dir: &Path,
+    ) -> Result<PathBuf, NotebookError> {
+        self.copy_out_as(attachment, target_dir, &attachment.file_name)
+    }
+
+    /// Copies an attachment out of the store as `target_dir/file_name`
+    pub fn copy_out_as(
+        &self,
+        attachment: &Attachment,
+        target_dir: &Path,
+        file_name: &str,
+    ) -> Result<PathBuf, NotebookError> {
+        fs::create_dir_all(target_dir).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+        let target = target_dir.join(file_name);
+        let blob_path = self.blob_path(&attachment.hash);
+        match &self.key {
+            Some(key) => {
+                let blob =
+                    fs::read(&blob_path).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+                fs::write(&target, key.decrypt_blob(&blob)?)
+                    .map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            }
+            None => {
+                fs::copy(&blob_path, &target)
+                    .map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            }
+        }
+        Ok(target)
+    }
+
+    /// Where an attachment is copied to for opening
+    pub fn open_dir(&self, attachment: &Attachment) -> PathBuf {
+        self.root.join(OPEN_DIR).join(&attachment.id)
+    }
+
+    /// Deletes the copies made for opening, which are plain even in an encrypted notebook
+    pub fn remove_open_copies(&self) -> Result<(), NotebookError> {
+        let open_dir = self.root.join(OPEN_DIR);
+        if open_dir.exists() {
+            fs::remove_dir_all(&open_dir).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Encrypts the blobs stored before the notebook was encrypted, returns how many there were
+    pub fn encrypt_plain_blobs(&self) -> Result<usize, NotebookError> {
+        let Some(key) = &self.key else {
+            return Ok(0);
+        };
+        let mut encrypted = 0;
+        for (name, path) in self.stored_files()? {
+            if name.starts_with(TMP_PREFIX) {
+                continue;
+            }
+            let blob = fs::read(&path).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            if NotebookKey::is_encrypted_blob(&blob) {
+                continue;
+            }
+            let tmp_path = path.with_file_name(format!("{}{}", TMP_PREFIX, Uuid::new_v4()));
+            fs::write(&tmp_path, key.encrypt_blob(&blob)?)
+                .and_then(|_| fs::rename(&tmp_path, &path))
+                .map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            encrypted += 1;
+        }
+        if encrypted > 0 {
+            info!("Encrypted [{}] attachment blobs", encrypted);
+        }
+        Ok(encrypted)
+    }
+
+    fn hash_file(path: &Path) -> Result<(String, u64), NotebookError> {
+        let mut file = fs::File::open(path)
+            .map_err(|e| NotebookError::FileAccess(format!("Unable to read {:?}: {}", path, e)))?;
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; 64 * 1024];
+        let mut size = 0u64;
+        loop {
+            let read = file
+                .read(&mut buffer)
+                .map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read as u64;
+        }
+        Ok((format!("{:x}", hasher.finalize()), size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_deduplicates_content() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let store = AttachmentStore::new(dir.join("store"), None).unwrap();
+        let first = dir.join("first.txt");
+        let second = dir.join("second.txt");
+        fs::write(&first, "same content").unwrap();
+        fs::write(&second, "same content").unwrap();
+
+        let (hash, size) = store.store(&first).unwrap();
+        assert_eq!((hash.clone(), 12), store.store(&second).unwrap());
+        assert_eq!(12, size);
+        let stored = store.stored_files().unwrap();
+        assert_eq!(
+            vec![hash.clone()],
+            stored.into_iter().map(|(name, _)| name).collect::<Vec<_>>()
+        );
+
+        let attachment = Attachment {
+            id: "1".to_string(),
+            note_id: "1".to_string(),
+            file_name: "second.txt".to_string(),
+            hash,
+            size,
+            added: 0,
+        };
+        let copy = store
+            .copy_out(&attachment, &store.open_dir(&attachment))
+            .unwrap();
+        assert_eq!("same content", fs::read_to_string(copy).unwrap());
+        // copies made for opening are not blobs
+        assert_eq!(1, store.stored_files().unwrap().len());
+    }
+
+    #[test]
+    fn test_remove_unused_spares_recent_and_partial_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let store = AttachmentStore::new(dir.join("store"), None).unwrap();
+        let file = dir.join("file.txt");
+        fs::write(&file, "old content").unwrap();
+        let (old_hash, _) = store.store(&file).unwrap();
+        let listed_at = SystemTime::now();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(&file, "new content").unwrap();
+        let (new_hash, _) = store.store(&file).unwrap();
+        let partial = store.blob_path(&new_hash).with_file_name("tmp-partial");
+        fs::write(&partial, "new con").unwrap();
+
+        // the new blob was stored after the references were read, its row may be on the way
+        assert_eq!(1, store.remove_unused(&HashSet::new(), listed_at).unwrap());
+        assert!(!store.blob_path(&old_hash).exists());
+        assert!(store.blob_path(&new_hash).exists());
+        assert!(partial.exists());
+
+        // a partial copy found on opening was left by an interrupted store
+        AttachmentStore::new(dir.join("store"), None).unwrap();
+        assert!(!partial.exists());
+    }
+}
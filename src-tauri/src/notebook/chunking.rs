@@ -0,0 +1,306 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use serde::Serialize;
+
+/// Longest chunk embedded, in characters. Roughly what fits the embedding model's token limit.
+pub const CHUNK_MAX_CHARS: usize = 1500;
+/// Characters repeated from the end of a chunk at the start of the next one in the same
+/// section, so a passage cut between two chunks is still found whole in one of them
+pub const CHUNK_OVERLAP_CHARS: usize = 200;
+const CHUNK_ID_SEPARATOR: char = '#';
+
+/// A part of a note embedded on its own
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoteChunk {
+    pub id: String,
+    pub note_id: String,
+    /// Character range of the chunk in the note text
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// The part of a note that best matched a similarity query
+#[derive(Debug, Clone, Serialize)]
+pub struct Passage {
+    pub text: String,
+    /// Character range of the passage in the note text
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A chunk returned by a similarity query
+#[derive(Debug, Clone)]
+pub struct ChunkMatch {
+    pub chunk_id: String,
+    pub text: String,
+    pub distance: f32,
+}
+
+pub fn chunk_id(note_id: &str, index: usize) -> String {
+    format!("{}{}{}", note_id, CHUNK_ID_SEPARATOR, index)
+}
+
+/// Splits a note into chunks of at most `max_chars` characters. Chunks never span markdown
+/// headings, are made of whole paragraphs where possible, and consecutive chunks of a section
+/// share up to `overlap_chars` characters of trailing paragraphs. A note always has at least one
+/// chunk, even when empty.
+pub fn chunk_note(
+    note_id: &str,
+    text: &str,
+    max_chars: usize,
+    overlap_chars: usize,
+) -> Vec<NoteChunk> {
+    let mut byte_ranges: Vec<Range<usize>> = Vec::new();
+    for section in sections(text) {
+        let mut units: Vec<Range<usize>> = Vec::new();
+        for paragraph in paragraphs(text, section) {
+            units.extend(split_long(text, paragraph, max_chars, overlap_chars));
+        }
+        pack(text, &units, max_chars, overlap_chars, &mut byte_ranges);
+    }
+    if byte_ranges.is_empty() {
+        byte_ranges.push(0..text.len());
+    }
+
+    byte_ranges
+        .into_iter()
+        .enumerate()
+        .map(|(index, range)| NoteChunk {
+            id: chunk_id(note_id, index),
+            note_id: note_id.to_string(),
+            start: text[..range.start].chars().count(),
+            end: text[..range.end].chars().count(),
+            text: text[range].to_string(),
+        })
+        .collect()
+}
+
+fn char_len(text: &str, range: &Range<usize>) -> usize {
+    text[range.clone()].chars().count()
+}
+
+/// Byte ranges of the text between markdown headings, each heading starting a new section
+fn sections(text: &str) -> Vec<Range<usize>> {
+    let mut sections = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    let mut in_code_block = false;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_code_block = !in_code_block;
+        }
+        let is_heading = !in_code_block
+            && trimmed.starts_with('#')
+            && trimmed
+                .trim_start_matches('#')
+                .starts_with([' ', '\t', '\r', '\n']);
+        if is_heading && offset > start {
+            sections.push(start..offset);
+            start = offset;
+        }
+        offset += line.len();
+    }
+    if offset > start {
+        sections.push(start..offset);
+    }
+    sections
+}
+
+/// Byte ranges of the blank line separated paragraphs of a section, trimmed
+fn paragraphs(text: &str, section: Range<usize>) -> Vec<Range<usize>> {
+    let mut paragraphs = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut end = section.start;
+    let mut offset = section.start;
+    for line in text[section].split_inclusive('\n') {
+        if line.trim().is_empty() {
+            if let Some(start) = start.take() {
+                paragraphs.push(start..end);
+            }
+        } else {
+            let leading = line.len() - line.trim_start().len();
+            start.get_or_insert(offset + leading);
+            end = offset + line.trim_end().len();
+        }
+        offset += line.len();
+    }
+    if let Some(start) = start {
+        paragraphs.push(start..end);
+    }
+    paragraphs
+}
+
+/// Cuts a paragraph longer than `max_chars` into overlapping windows, breaking on whitespace
+/// where there is some
+fn split_long(
+    text: &str,
+    range: Range<usize>,
+    max_chars: usize,
+    overlap_chars: usize,
+) -> Vec<Range<usize>> {
+    if char_len(text, &range) <= max_chars {
+        return vec![range];
+    }
+    // byte offsets of every character, plus the end
+    let offsets: Vec<usize> = text[range.clone()]
+        .char_indices()
+        .map(|(i, _)| range.start + i)
+        .chain(std::iter::once(range.end))
+        .collect();
+    let char_count = offsets.len() - 1;
+    let mut windows = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut end = (start + max_chars).min(char_count);
+        if end < char_count {
+            // back off to the last whitespace in the second half of the window
+            if let Some(space) = (start + max_chars / 2..end)
+                .rev()
+                .find(|&i| text[offsets[i]..offsets[i + 1]].trim().is_empty())
+            {
+                end = space;
+            }
+        }
+        windows.push(offsets[start]..offsets[end]);
+        if end >= char_count {
+            break;
+        }
+        start = end.saturating_sub(overlap_chars).max(start + 1);
+    }
+    windows
+}
+
+/// Packs consecutive units into chunks of at most `max_chars`, starting each new chunk with the
+/// trailing units of the previous one that fit in `overlap_chars`
+fn pack(
+    text: &str,
+    units: &[Range<usize>],
+    max_chars: usize,
+    overlap_chars: usize,
+    chunks: &mut Vec<Range<usize>>,
+) {
+    let mut first = 0;
+    while first < units.len() {
+        let mut last = first;
+        while last + 1 < units.len()
+            && char_len(text, &(units[first].start..units[last + 1].end)) <= max_chars
+        {
+            last += 1;
+        }
+        chunks.push(units[first].start..units[last].end);
+        if last + 1 >= units.len() {
+            break;
+        }
+        let mut next = last + 1;
+        while next > first + 1
+            && char_len(text, &(units[next - 1].start..units[last].end)) <= overlap_chars
+        {
+            next -= 1;
+        }
+        first = next;
+    }
+}
+
+/// The note a vector belongs to. Vectors written before notes were chunked are keyed by the bare
+/// note id.
+pub fn chunk_note_id(chunk_id: &str) -> &str {
+    chunk_id
+        .rsplit_once(CHUNK_ID_SEPARATOR)
+        .map_or(chunk_id, |(note_id, _)| note_id)
+}
+
+/// Scores notes by their closest chunk. Returns (note id, closest chunk) pairs, closest first.
+pub fn closest_chunk_per_note(mut matches: Vec<ChunkMatch>) -> Vec<(String, ChunkMatch)> {
+    matches.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    let mut seen = HashSet::new();
+    matches
+        .into_iter()
+        .filter_map(|chunk| {
+            let note_id = chunk_note_id(&chunk.chunk_id).to_string();
+            seen.insert(note_id.clone()).then_some((note_id, chunk))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_note_is_one_chunk() {
+        let chunks = chunk_note("n", "# Title\n\nBody", 100, 10);
+        assert_eq!(1, chunks.len());
+        assert_eq!("n#0", chunks[0].id);
+        assert_eq!("# Title\n\nBody", chunks[0].text);
+        assert_eq!(1, chunk_note("n", "", 100, 10).len());
+    }
+
+    #[test]
+    fn test_chunks_follow_headings_and_paragraphs() {
+        let text = "# One\n\nfirst paragraph\n\nsecond paragraph\n\n## Two\nthird paragraph";
+        let chunks = chunk_note("n", text, 35, 20);
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(
+            vec![
+                "# One\n\nfirst paragraph",
+                // the overlap repeats the previous paragraph
+                "first paragraph\n\nsecond paragraph",
+                "## Two\nthird paragraph"
+            ],
+            texts
+        );
+        for chunk in &chunks {
+            let from_range: String = text
+                .chars()
+                .skip(chunk.start)
+                .take(chunk.end - chunk.start)
+                .collect();
+            assert_eq!(chunk.text, from_range);
+        }
+    }
+
+    #[test]
+    fn test_long_paragraph_is_split_with_overlap() {
+        let text = "ééé ".repeat(100);
+        let chunks = chunk_note("n", &text, 50, 10);
+        assert!(chunks.len() > 8);
+        for pair in chunks.windows(2) {
+            assert!(pair[0].text.chars().count() <= 50);
+            assert!(pair[1].start < pair[0].end, "consecutive windows overlap");
+        }
+        assert_eq!(text.trim_end().chars().count(), chunks.last().unwrap().end);
+    }
+
+    #[test]
+    fn test_closest_chunk_per_note() {
+        let matches = [("a#0", 0.4), ("b#2", 0.1), ("a#3", 0.2), ("legacy", 0.3)]
+            .into_iter()
+            .map(|(id, distance)| ChunkMatch {
+                chunk_id: id.to_string(),
+                text: String::new(),
+                distance,
+            })
+            .collect();
+        let closest: Vec<(String, String)> = closest_chunk_per_note(matches)
+            .into_iter()
+            .map(|(note_id, chunk)| (note_id, chunk.chunk_id))
+            .collect();
+        assert_eq!(
+            vec![
+                ("b".to_string(), "b#2".to_string()),
+                ("a".to_string(), "a#3".to_string()),
+                ("legacy".to_string(), "legacy".to_string())
+            ],
+            closest
+        );
+    }
+
+    #[test]
+    fn test_chunk_note_id() {
+        assert_eq!("abc", chunk_note_id(&chunk_id("abc", 3)));
+        assert_eq!("legacy-id", chunk_note_id("legacy-id"));
+    }
+}
@@ -0,0 +1,110 @@
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::Connection;
+use tokio::sync::{MutexGuard, OwnedSemaphorePermit, Semaphore};
+
+/// A fixed set of read-only connections to the models db. With the db in WAL mode readers see
+/// the last committed state and neither wait for the writer nor hold it up.
+pub struct ReadPool {
+    idle: Mutex<Vec<Connection>>,
+    // one permit per connection, so a permit holder always finds an idle connection
+    available: Arc<Semaphore>,
+}
+
+impl ReadPool {
+    pub fn new(connections: Vec<Connection>) -> Self {
+        ReadPool {
+            available: Arc::new(Semaphore::new(connections.len())),
+            idle: Mutex::new(connections),
+        }
+    }
+
+    /// A connection from the pool, waiting for one to be returned when they are all in use
+    pub async fn get(self: &Arc<Self>) -> PooledConnection {
+        let permit = self
+            .available
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the pool semaphore is never closed");
+        let conn = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("a permit is held for every connection in use");
+        PooledConnection {
+            conn: Some(conn),
+            pool: self.clone(),
+            _permit: permit,
+        }
+    }
+}
+
+/// A connection borrowed from a `ReadPool`, returned to it when dropped
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    pool: Arc<ReadPool>,
+    // released after the connection is back in the pool
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("only taken on drop")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap().push(conn);
+        }
+    }
+}
+
+/// A connection to read the models db with: one from the read pool, or the writer for a
+/// repository without a pool (an in-memory db can't be shared between connections)
+pub enum ReadConnection<'a> {
+    Pooled(PooledConnection),
+    Writer(MutexGuard<'a, Connection>),
+}
+
+impl Deref for ReadConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            ReadConnection::Pooled(conn) => conn,
+            ReadConnection::Writer(conn) => conn,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connections_return_to_the_pool() {
+        let pool = Arc::new(ReadPool::new(vec![Connection::open_in_memory().unwrap()]));
+        let first = pool.get().await;
+        first.execute_batch("CREATE TABLE t (id INTEGER)").unwrap();
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                let conn = pool.get().await;
+                // the same connection, handed over once the first borrower is done with it
+                conn.query_row("SELECT COUNT(*) FROM t", [], |row| row.get::<_, i64>(0))
+                    .unwrap()
+            }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        drop(first);
+        assert_eq!(0, waiting.await.unwrap());
+    }
+}
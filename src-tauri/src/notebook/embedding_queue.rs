@@ -0,0 +1,323 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{error, info, warn};
+use tokio::sync::{Mutex, MutexGuard, Notify, OwnedMutexGuard};
+
+use crate::notebook::chunking::{chunk_note, NoteChunk, CHUNK_MAX_CHARS, CHUNK_OVERLAP_CHARS};
+use crate::notebook::embeddings::{
+    content_hash, EmbeddedNote, EmbeddingOperation, EmbeddingStats, PendingEmbedding,
+};
+use crate::notebook::notebook_repository::NotebookRepository;
+use crate::notebook::vector_store::VectorStore;
+use crate::notebook::NotebookError;
+
+/// Queued changes applied per call to the embeddings db
+pub const EMBED_BATCH_SIZE: usize = 32;
+// how long the worker waits before retrying changes that failed, unless woken by a new change
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What a batch did with the queued changes it took
+#[derive(Debug, Default)]
+pub struct BatchOutcome {
+    /// Notes whose vectors are now up to date
+    pub embedded: Vec<String>,
+    pub deleted: Vec<String>,
+    /// Changes dropped because their note no longer exists
+    pub dropped: usize,
+    /// Changes left queued for a retry
+    pub failed: usize,
+}
+
+impl BatchOutcome {
+    pub fn is_empty(&self) -> bool {
+        self.embedded.is_empty() && self.deleted.is_empty() && self.dropped == 0 && self.failed == 0
+    }
+}
+
+/// Applies the vector changes queued in the models db (see `NotebookRepository::save_note`) to
+/// the embeddings db. Saving a note only queues its vector, a background worker started with
+/// `worker` does the embedding so saves don't wait on the model.
+#[derive(Clone)]
+pub struct EmbeddingQueue {
+    embed_store: Arc<dyn VectorStore>,
+    // held for the whole of a batch, queries of the embeddings db don't wait for it
+    batch: Arc<Mutex<()>>,
+    // held by the worker until it has dropped its connections to the notebook
+    running: Arc<Mutex<()>>,
+    models_store: NotebookRepository,
+    stats: Arc<EmbeddingStats>,
+    wake: Arc<Notify>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl EmbeddingQueue {
+    pub fn new(
+        embed_store: Arc<dyn VectorStore>,
+        models_store: NotebookRepository,
+        stats: Arc<EmbeddingStats>,
+    ) -> Self {
+        EmbeddingQueue {
+            embed_store,
+            batch: Arc::new(Mutex::new(())),
+            running: Arc::new(Mutex::new(())),
+            models_store,
+            stats,
+            wake: Arc::new(Notify::new()),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Tells the worker there are new changes in the queue
+    pub fn notify(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Stops the worker once its current batch is done
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.wake.notify_one();
+    }
+
+    /// Stops the worker and waits for it to exit and for the current batch, after which
+    /// neither db is written to and the worker's connections are closed
+    pub async fn stop_and_wait(&self) {
+        self.stop();
+        drop(self.running.lock().await);
+        drop(self.pause().await);
+    }
+
+    /// Waits for the current batch and keeps another from starting until the guard is dropped,
+    /// for changes to the embeddings db that must not interleave with a batch
+    pub async fn pause(&self) -> MutexGuard<'_, ()> {
+        self.batch.lock().await
+    }
+
+    /// Applies up to `limit` queued changes. Batches run one at a time so two batches never
+    /// work on the same changes.
+    pub async fn process_batch(&self, limit: usize) -> Result<BatchOutcome, NotebookError> {
+        let _batch = self.batch.lock().await;
+        let embed_store = &*self.embed_store;
+        if self.stopped.load(Ordering::SeqCst) {
+            return Ok(BatchOutcome::default());
+        }
+        // fails with EmbeddingUnavailable until the engine loads, leaving the queue as it is
+        embed_store.ensure_ready().await?;
+        if let Some((model, false)) = self.models_store.get_embedding_model().await? {
+            info!(
+                "Emptying the embeddings db before re-indexing with {}",
+                model
+            );
+            embed_store.empty().await?;
+            self.models_store.record_embeddings_cleared().await?;
+        }
+        let pending = self
+            .models_store
+            .get_pending_embeddings(Some(limit))
+            .await?;
+        let mut outcome = BatchOutcome::default();
+        let (upserts, deletes): (Vec<PendingEmbedding>, Vec<PendingEmbedding>) = pending
+            .into_iter()
+            .partition(|pending| pending.operation == EmbeddingOperation::Upsert);
+
+        if !upserts.is_empty() {
+            let note_ids: Vec<String> = upserts.iter().map(|p| p.note_id.clone()).collect();
+            // the latest text is embedded, however many saves were coalesced into the change
+            let texts: HashMap<String, String> = self
+                .models_store
+                .get_note_texts(&note_ids)
+                .await?
+                .into_iter()
+                .collect();
+            let (present, gone): (Vec<PendingEmbedding>, Vec<PendingEmbedding>) = upserts
+                .into_iter()
+                .partition(|pending| texts.contains_key(&pending.note_id));
+            outcome.dropped += gone.len();
+            self.models_store
+                .clear_pending_embeddings(
+                    &gone
+                        .into_iter()
+                        .map(|p| (p.note_id, p.revision))
+                        .collect::<Vec<_>>(),
+                )
+                .await?;
+
+            if !present.is_empty() {
+                let embedded: Vec<EmbeddedNote> = present
+                    .iter()
+                    .map(|p| {
+                        let text = &texts[&p.note_id];
+                        EmbeddedNote {
+                            note_id: p.note_id.clone(),
+                            content_hash: content_hash(text),
+                            revision: p.revision,
+                            chunks: chunk_note(
+                                &p.note_id,
+                                text,
+                                CHUNK_MAX_CHARS,
+                                CHUNK_OVERLAP_CHARS,
+                            ),
+                        }
+                    })
+                    .collect();
+                let chunks: Vec<NoteChunk> = embedded
+                    .iter()
+                    .flat_map(|note| note.chunks.iter().cloned())
+                    .collect();
+                info!(
+                    "Writing [{}] chunks of [{}] notes to embeddings db",
+                    chunks.len(),
+                    embedded.len()
+                );
+                match embed_store.upsert_chunks(&chunks).await {
+                    Ok(_) => {
+                        self.delete_replaced_chunks(embed_store, &embedded).await?;
+                        self.models_store
+                            .record_embedded(&embedded, Utc::now().timestamp())
+                            .await?;
+                        self.stats.add_performed(embedded.len());
+                        outcome.embedded = present.into_iter().map(|p| p.note_id).collect();
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Embedding [{}] notes failed, they will be retried: {}",
+                            present.len(),
+                            e
+                        );
+                        outcome.failed += present.len();
+                        let ids: Vec<String> = present.into_iter().map(|p| p.note_id).collect();
+                        self.models_store
+                            .record_embedding_failure(&ids, &e.to_string())
+                            .await?;
+                    }
+                }
+            }
+        }
+
+        if !deletes.is_empty() {
+            let note_ids: Vec<String> = deletes.iter().map(|p| p.note_id.clone()).collect();
+            // vectors written before notes were chunked are keyed by the note id
+            let mut vector_ids = self.models_store.get_chunk_ids(&note_ids).await?;
+            vector_ids.extend(note_ids.iter().cloned());
+            info!("Deleting [{}] notes from embeddings db", note_ids.len());
+            match embed_store.delete(&vector_ids).await {
+                Ok(_) => {
+                    self.models_store
+                        .record_vectors_deleted(
+                            &deletes
+                                .into_iter()
+                                .map(|p| (p.note_id, p.revision))
+                                .collect::<Vec<_>>(),
+                        )
+                        .await?;
+                    outcome.deleted = note_ids;
+                }
+                Err(e) => {
+                    warn!(
+                        "Deleting [{}] vectors failed, they will be retried: {}",
+                        note_ids.len(),
+                        e
+                    );
+                    outcome.failed += note_ids.len();
+                    self.models_store
+                        .record_embedding_failure(&note_ids, &e.to_string())
+                        .await?;
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Deletes the chunks a note had before it was embedded again that it no longer has, along
+    /// with its vector from before notes were chunked. A failure only leaves unused vectors
+    /// behind, which are ignored by queries, so the new chunks are still recorded.
+    async fn delete_replaced_chunks(
+        &self,
+        embed_store: &dyn VectorStore,
+        embedded: &[EmbeddedNote],
+    ) -> Result<(), NotebookError> {
+        let note_ids: Vec<String> = embedded.iter().map(|note| note.note_id.clone()).collect();
+        let current: HashSet<&str> = embedded
+            .iter()
+            .flat_map(|note| &note.chunks)
+            .map(|chunk| chunk.id.as_str())
+            .collect();
+        let mut replaced: Vec<String> = self
+            .models_store
+            .get_chunk_ids(&note_ids)
+            .await?
+            .into_iter()
+            .filter(|id| !current.contains(id.as_str()))
+            .collect();
+        replaced.extend(note_ids);
+        if let Err(e) = embed_store.delete(&replaced).await {
+            warn!(
+                "Deleting [{}] replaced chunks failed: {}",
+                replaced.len(),
+                e
+            );
+        }
+        Ok(())
+    }
+
+    /// Works through the queue until stopped, calling `on_embedded` with the ids of notes whose
+    /// vectors are up to date after each batch. Changes queued in an earlier session are picked
+    /// up straight away. There is one worker per queue.
+    pub fn worker<F>(&self, on_embedded: F) -> impl Future<Output = ()> + Send + 'static
+    where
+        F: Fn(&[String]) + Send + 'static,
+    {
+        let running = self
+            .running
+            .clone()
+            .try_lock_owned()
+            .expect("the embedding worker is only started once");
+        self.clone().run(running, on_embedded)
+    }
+
+    async fn run<F>(self, running: OwnedMutexGuard<()>, on_embedded: F)
+    where
+        F: Fn(&[String]) + Send + 'static,
+    {
+        info!("Embedding worker started");
+        while !self.stopped.load(Ordering::SeqCst) {
+            match self.process_batch(EMBED_BATCH_SIZE).await {
+                Ok(outcome) => {
+                    if !outcome.embedded.is_empty() {
+                        on_embedded(&outcome.embedded);
+                    }
+                    if outcome.failed > 0 {
+                        self.wait(Some(RETRY_INTERVAL)).await;
+                    } else if outcome.is_empty() {
+                        self.wait(None).await;
+                    }
+                }
+                // already logged by the engine, changes wait in the queue until it loads
+                Err(NotebookError::EmbeddingUnavailable(_)) => {
+                    self.wait(Some(RETRY_INTERVAL)).await;
+                }
+                Err(e) => {
+                    error!("Embedding worker failed to process the queue: {}", e);
+                    self.wait(Some(RETRY_INTERVAL)).await;
+                }
+            }
+        }
+        info!("Embedding worker stopped");
+        // the queue holds the worker's connections, closed before `stop_and_wait` returns
+        drop(self);
+        drop(running);
+    }
+
+    async fn wait(&self, timeout: Option<Duration>) {
+        match timeout {
+            Some(timeout) => {
+                let _ = tokio::time::timeout(timeout, self.wake.notified()).await;
+            }
+            None => self.wake.notified().await,
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use fastembed::EmbeddingModel;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::notebook::chunking::NoteChunk;
+use crate::notebook::vector_store::EngineStatus;
+
+/// A change to a note's vector that has been committed to the models db but not yet applied to
+/// the embeddings db
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingOperation {
+    Upsert,
+    Delete,
+}
+
+impl EmbeddingOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmbeddingOperation::Upsert => "upsert",
+            EmbeddingOperation::Delete => "delete",
+        }
+    }
+
+    pub fn parse(operation: &str) -> Option<Self> {
+        match operation {
+            "upsert" => Some(EmbeddingOperation::Upsert),
+            "delete" => Some(EmbeddingOperation::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// Embedding models notes can be embedded with. Vectors from different models can't be compared,
+/// so changing the model embeds every note again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmbeddingModelChoice {
+    #[default]
+    BgeSmallEn,
+    BgeBaseEn,
+    BgeLargeEn,
+    MultilingualE5Small,
+    MultilingualE5Base,
+    MultilingualE5Large,
+}
+
+/// An embedding model as listed in the settings
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingModelInfo {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub multilingual: bool,
+    pub active: bool,
+}
+
+impl EmbeddingModelChoice {
+    pub const ALL: [EmbeddingModelChoice; 6] = [
+        EmbeddingModelChoice::BgeSmallEn,
+        EmbeddingModelChoice::BgeBaseEn,
+        EmbeddingModelChoice::BgeLargeEn,
+        EmbeddingModelChoice::MultilingualE5Small,
+        EmbeddingModelChoice::MultilingualE5Base,
+        EmbeddingModelChoice::MultilingualE5Large,
+    ];
+
+    /// Stable id, stored in the app config and in the models db
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmbeddingModelChoice::BgeSmallEn => "bge-small-en-v1.5",
+            EmbeddingModelChoice::BgeBaseEn => "bge-base-en-v1.5",
+            EmbeddingModelChoice::BgeLargeEn => "bge-large-en-v1.5",
+            EmbeddingModelChoice::MultilingualE5Small => "multilingual-e5-small",
+            EmbeddingModelChoice::MultilingualE5Base => "multilingual-e5-base",
+            EmbeddingModelChoice::MultilingualE5Large => "multilingual-e5-large",
+        }
+    }
+
+    pub fn parse(id: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|model| model.as_str() == id)
+    }
+
+    pub fn fastembed_model(&self) -> EmbeddingModel {
+        match self {
+            EmbeddingModelChoice::BgeSmallEn => EmbeddingModel::BGESmallENV15,
+            EmbeddingModelChoice::BgeBaseEn => EmbeddingModel::BGEBaseENV15,
+            EmbeddingModelChoice::BgeLargeEn => EmbeddingModel::BGELargeENV15,
+            EmbeddingModelChoice::MultilingualE5Small => EmbeddingModel::MultilingualE5Small,
+            EmbeddingModelChoice::MultilingualE5Base => EmbeddingModel::MultilingualE5Base,
+            EmbeddingModelChoice::MultilingualE5Large => EmbeddingModel::MultilingualE5Large,
+        }
+    }
+
+    pub fn info(&self, active: bool) -> EmbeddingModelInfo {
+        let (name, description, multilingual) = match self {
+            EmbeddingModelChoice::BgeSmallEn => (
+                "BGE small (English)",
+                "Fast and light, the default. English only.",
+                false,
+            ),
+            EmbeddingModelChoice::BgeBaseEn => (
+                "BGE base (English)",
+                "Better matches than small, slower to embed. English only.",
+                false,
+            ),
+            EmbeddingModelChoice::BgeLargeEn => (
+                "BGE large (English)",
+                "Best English matches, slowest to embed and largest download.",
+                false,
+            ),
+            EmbeddingModelChoice::MultilingualE5Small => (
+                "Multilingual E5 small",
+                "Notes in about 100 languages, fast and light.",
+                true,
+            ),
+            EmbeddingModelChoice::MultilingualE5Base => (
+                "Multilingual E5 base",
+                "Notes in about 100 languages, better matches than small.",
+                true,
+            ),
+            EmbeddingModelChoice::MultilingualE5Large => (
+                "Multilingual E5 large",
+                "Notes in about 100 languages, best matches, slowest to embed.",
+                true,
+            ),
+        };
+        EmbeddingModelInfo {
+            id: self.as_str(),
+            name,
+            description,
+            multilingual,
+            active,
+        }
+    }
+}
+
+/// A note whose vector still has to be written or deleted
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingEmbedding {
+    pub note_id: String,
+    pub operation: EmbeddingOperation,
+    pub queued: i64,
+    /// Failed attempts at applying the operation so far
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Bumped each time the change is queued again before being applied
+    pub revision: i64,
+}
+
+/// A note whose chunks were written to the embeddings db, to be recorded in the ledger
+#[derive(Debug, Clone)]
+pub struct EmbeddedNote {
+    pub note_id: String,
+    pub content_hash: String,
+    /// Revision of the queued change that was applied
+    pub revision: i64,
+    pub chunks: Vec<NoteChunk>,
+}
+
+/// Drift between the notes and their vectors. The vectors in the vector store are compared
+/// with the notes and their chunks, and the embeddings ledger tells which content they were
+/// computed from.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IndexReport {
+    pub notes_checked: usize,
+    /// False when the vector store can't list its vectors (see `VectorStore::list_ids`). The
+    /// report then relies on the ledger alone: vectors stored under any other id than a chunk
+    /// id go unnoticed and whether a note the ledger has no record of has a vector is unknown.
+    pub vectors_listed: bool,
+    /// Notes with no vector
+    pub missing: Vec<String>,
+    /// Ids of vectors whose note no longer exists, or that belong to no note
+    pub orphaned: Vec<String>,
+    /// Notes whose vector was computed from different content
+    pub stale: Vec<String>,
+    /// Notes the ledger has no record of, embedded before it existed or never embedded when
+    /// the vectors could not be listed
+    pub unrecorded: Vec<String>,
+    /// Vector writes and deletes queued for a retry
+    pub pending: usize,
+}
+
+impl IndexReport {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty()
+            && self.orphaned.is_empty()
+            && self.stale.is_empty()
+            && self.unrecorded.is_empty()
+    }
+}
+
+/// Results ranked by note vectors. Vectors are written in the background after a save, so the
+/// results may not reflect the latest edits while changes are pending.
+#[derive(Debug, Clone, Serialize)]
+pub struct SimilarityResults<T> {
+    pub results: Vec<T>,
+    /// Vector changes still queued when the query ran
+    pub pending_embeddings: usize,
+    pub may_be_stale: bool,
+    /// Why vectors were not used, for searches that fall back to keywords without them
+    pub semantic_unavailable: Option<String>,
+}
+
+impl<T> SimilarityResults<T> {
+    pub fn new(results: Vec<T>, pending_embeddings: usize) -> Self {
+        SimilarityResults {
+            results,
+            pending_embeddings,
+            may_be_stale: pending_embeddings > 0,
+            semantic_unavailable: None,
+        }
+    }
+}
+
+/// Whether semantic features can be used, and how many vector changes are waiting for them
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingStatus {
+    pub engine: EngineStatus,
+    pub pending_embeddings: usize,
+}
+
+/// Progress of `rebuild_embeddings`, sent after each batch
+#[derive(Debug, Clone, Serialize)]
+pub struct RebuildProgress {
+    pub embedded: usize,
+    pub total: usize,
+}
+
+/// Counts of note vectors written and of saves that left a note's vector untouched because
+/// its content had not changed, since the notebook was opened
+#[derive(Debug, Default)]
+pub struct EmbeddingStats {
+    performed: AtomicUsize,
+    skipped: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct EmbeddingCounts {
+    pub performed: usize,
+    pub skipped: usize,
+}
+
+impl EmbeddingStats {
+    pub fn add_performed(&self, count: usize) {
+        self.performed.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_skipped(&self, count: usize) {
+        self.skipped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> EmbeddingCounts {
+        EmbeddingCounts {
+            performed: self.performed.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Hash of the content a vector is computed from. Trailing whitespace does not change a note's
+/// vector, so it does not change its hash either.
+pub fn content_hash(text: &str) -> String {
+    let mut hasher = Sha256::new();
+    for line in text.trim().lines() {
+        hasher.update(line.trim_end().as_bytes());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedding_model_ids_round_trip() {
+        for model in EmbeddingModelChoice::ALL {
+            assert_eq!(Some(model), EmbeddingModelChoice::parse(model.as_str()));
+        }
+        assert_eq!(None, EmbeddingModelChoice::parse("unknown"));
+    }
+
+    #[test]
+    fn test_content_hash_ignores_trailing_whitespace() {
+        assert_eq!(
+            content_hash("# Title\nbody"),
+            content_hash("# Title  \r\nbody\n\n")
+        );
+        assert_ne!(
+            content_hash("# Title\nbody"),
+            content_hash("# Title\n body")
+        );
+    }
+}
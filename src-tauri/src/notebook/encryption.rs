@@ -0,0 +1,344 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use chrono::Utc;
+use log::info;
+use rand::RngCore;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use zeroize::Zeroizing;
+
+use crate::notebook::notebook_repository::NotebookRepository;
+use crate::notebook::{NotebookError, LANCE_TABLE_SUFFIX, MODELS_DB_FILE};
+
+/// A notebook is encrypted when this file is in its directory
+pub const ENCRYPTION_FILE: &str = "encryption.json";
+const MIN_PASSPHRASE_CHARS: usize = 8;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KDF_ALGORITHM: &str = "argon2id";
+// encrypted attachment blobs start with this, so blobs an interrupted encryption left plain are
+// told apart
+const ENCRYPTED_BLOB_MAGIC: &[u8] = b"KNOWLING-ENC1";
+const PLAIN_SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+// the encrypted copy of the models db, swapped in once the key is written
+const ENCRYPTED_DB_SUFFIX: &str = ".encrypted";
+
+#[derive(Serialize, Deserialize)]
+struct EncryptionConfig {
+    kdf: KdfParams,
+    /// The notebook key, encrypted with the key derived from the passphrase
+    wrapped_key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KdfParams {
+    algorithm: String,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+    salt: String,
+}
+
+fn encryption_error(e: impl std::fmt::Display) -> NotebookError {
+    NotebookError::Encryption(e.to_string())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, NotebookError> {
+    if !hex.is_ascii() || hex.len() % 2 == 1 {
+        return Err(encryption_error("Invalid hex string"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(encryption_error))
+        .collect()
+}
+
+/// Encrypts with a random nonce, returning the nonce followed by the ciphertext
+fn seal(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, NotebookError> {
+    let cipher = XChaCha20Poly1305::new_from_slice(key).map_err(encryption_error)?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(encryption_error)?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Decrypts what `seal` returned, failing when it was sealed with another key or altered
+fn unseal(key: &[u8], sealed: &[u8]) -> Result<Vec<u8>, NotebookError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(encryption_error("Encrypted data is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let cipher = XChaCha20Poly1305::new_from_slice(key).map_err(encryption_error)?;
+    cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            encryption_error("Unable to decrypt, the data was altered or the key is wrong")
+        })
+}
+
+fn derive_key(
+    passphrase: &str,
+    kdf: &KdfParams,
+) -> Result<Zeroizing<[u8; KEY_LEN]>, NotebookError> {
+    if kdf.algorithm != KDF_ALGORITHM {
+        return Err(encryption_error(format!(
+            "Unsupported key derivation: {}",
+            kdf.algorithm
+        )));
+    }
+    let params = Params::new(
+        kdf.memory_kib,
+        kdf.iterations,
+        kdf.parallelism,
+        Some(KEY_LEN),
+    )
+    .map_err(encryption_error)?;
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &from_hex(&kdf.salt)?, &mut *key)
+        .map_err(encryption_error)?;
+    Ok(key)
+}
+
+/// The key a notebook's models db and attachments are encrypted with. It is generated once, the
+/// passphrase only protects it, so changing the passphrase re-encrypts nothing but this key.
+#[derive(Clone)]
+pub struct NotebookKey(Arc<Zeroizing<[u8; KEY_LEN]>>);
+
+impl NotebookKey {
+    fn generate() -> Self {
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        OsRng.fill_bytes(&mut *key);
+        NotebookKey(Arc::new(key))
+    }
+
+    /// The key in SQLCipher's raw key syntax, so SQLCipher does not derive another key from it
+    fn sqlcipher_key(&self) -> Zeroizing<String> {
+        Zeroizing::new(format!("x'{}'", to_hex(&self.0[..])))
+    }
+
+    /// Makes `conn` read and write its db encrypted with this key
+    pub fn apply(&self, conn: &Connection) -> Result<(), NotebookError> {
+        conn.pragma_update(None, "key", self.sqlcipher_key().as_str())?;
+        // SQLCipher only checks the key on the first read
+        conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| {
+            row.get::<_, i64>(0)
+        })
+        .map_err(|e| encryption_error(format!("Unable to decrypt the models db: {}", e)))?;
+        Ok(())
+    }
+
+    pub fn encrypt_blob(&self, plaintext: &[u8]) -> Result<Vec<u8>, NotebookError> {
+        Ok([ENCRYPTED_BLOB_MAGIC, &seal(&self.0[..], plaintext)?].concat())
+    }
+
+    pub fn decrypt_blob(&self, blob: &[u8]) -> Result<Vec<u8>, NotebookError> {
+        let sealed = blob
+            .strip_prefix(ENCRYPTED_BLOB_MAGIC)
+            .ok_or(encryption_error("Attachment blob is not encrypted"))?;
+        unseal(&self.0[..], sealed)
+    }
+
+    pub fn is_encrypted_blob(blob: &[u8]) -> bool {
+        blob.starts_with(ENCRYPTED_BLOB_MAGIC)
+    }
+}
+
+pub fn is_encrypted(dir: &Path) -> bool {
+    dir.join(ENCRYPTION_FILE).is_file()
+}
+
+/// The key of the encrypted notebook in `dir`
+pub fn unlock(dir: &Path, passphrase: &str) -> Result<NotebookKey, NotebookError> {
+    let path = dir.join(ENCRYPTION_FILE);
+    let json = fs::read_to_string(&path)
+        .map_err(|e| NotebookError::FileAccess(format!("Unable to read {:?}: {}", path, e)))?;
+    let config: EncryptionConfig = serde_json::from_str(&json).map_err(encryption_error)?;
+    let passphrase_key = derive_key(passphrase, &config.kdf)?;
+    let key = unseal(&passphrase_key[..], &from_hex(&config.wrapped_key)?)
+        .map_err(|_| encryption_error("Wrong passphrase"))?;
+    let key: [u8; KEY_LEN] = key
+        .try_into()
+        .map_err(|_| encryption_error("Invalid notebook key"))?;
+    Ok(NotebookKey(Arc::new(Zeroizing::new(key))))
+}
+
+pub fn change_passphrase(dir: &Path, current: &str, new: &str) -> Result<(), NotebookError> {
+    let key = unlock(dir, current)?;
+    write_config(dir, &key, new)?;
+    info!("Changed the passphrase of the notebook in {:?}", dir);
+    Ok(())
+}
+
+/// Rejects passphrases too short to protect a notebook
+pub fn check_passphrase(passphrase: &str) -> Result<(), NotebookError> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_CHARS {
+        return Err(encryption_error(format!(
+            "The passphrase must be at least {} characters long",
+            MIN_PASSPHRASE_CHARS
+        )));
+    }
+    Ok(())
+}
+
+/// Writes the key protected by `passphrase`, replacing the file in one step so an interrupted
+/// write never loses the key
+fn write_config(dir: &Path, key: &NotebookKey, passphrase: &str) -> Result<(), NotebookError> {
+    check_passphrase(passphrase)?;
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let kdf = KdfParams {
+        algorithm: KDF_ALGORITHM.to_string(),
+        memory_kib: Params::DEFAULT_M_COST,
+        iterations: Params::DEFAULT_T_COST,
+        parallelism: Params::DEFAULT_P_COST,
+        salt: to_hex(&salt),
+    };
+    let passphrase_key = derive_key(passphrase, &kdf)?;
+    let config = EncryptionConfig {
+        wrapped_key: to_hex(&seal(&passphrase_key[..], &key.0[..])?),
+        kdf,
+    };
+    let json = serde_json::to_string_pretty(&config).map_err(encryption_error)?;
+    let path = dir.join(ENCRYPTION_FILE);
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, json)
+        .and_then(|_| fs::rename(&tmp_path, &path))
+        .map_err(|e| NotebookError::FileAccess(format!("Unable to write {:?}: {}", path, e)))
+}
+
+fn encrypted_db_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(ENCRYPTED_DB_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Encrypts the plain notebook in `dir`, which must not be open, returning its new key.
+/// The models db is copied encrypted and swapped in once the key is written, its vectors are
+/// computed again into the encrypted db and the plain embeddings db is deleted. Attachments are
+/// encrypted the first time the notebook is opened with the key.
+pub async fn encrypt_notebook(dir: &Path, passphrase: &str) -> Result<NotebookKey, NotebookError> {
+    if is_encrypted(dir) {
+        return Err(encryption_error("The notebook is already encrypted"));
+    }
+    check_passphrase(passphrase)?;
+    info!("Encrypting the notebook in {:?}", dir);
+    let db_path = dir.join(MODELS_DB_FILE);
+    {
+        // brings the schema up to date and queues every note to be embedded again
+        let repository = NotebookRepository::new(Arc::new(Mutex::new(Connection::open(&db_path)?)));
+        repository.init_db().await?;
+        if let Some((model, _)) = repository.get_embedding_model().await? {
+            repository
+                .queue_full_reindex(&model, Utc::now().timestamp())
+                .await?;
+        }
+    }
+
+    let key = NotebookKey::generate();
+    let encrypted_path = encrypted_db_path(&db_path);
+    let _ = fs::remove_file(&encrypted_path);
+    let conn = Connection::open(&db_path)?;
+    let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    conn.execute(
+        "ATTACH DATABASE ?1 AS encrypted KEY ?2",
+        params![
+            encrypted_path.to_string_lossy(),
+            key.sqlcipher_key().as_str()
+        ],
+    )?;
+    conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+    conn.execute_batch(&format!(
+        "PRAGMA encrypted.user_version = {}; DETACH DATABASE encrypted;",
+        version
+    ))?;
+    drop(conn);
+
+    write_config(dir, &key, passphrase)?;
+    finish_encryption(dir)?;
+    info!("Encrypted the notebook in {:?}", dir);
+    Ok(key)
+}
+
+/// Completes an encryption interrupted after the key was written: swaps in the encrypted models
+/// db, then deletes the plain embeddings db and plain backups of the models db. Does nothing
+/// when there is nothing left to do.
+pub fn finish_encryption(dir: &Path) -> Result<(), NotebookError> {
+    let file_error = |e: std::io::Error| NotebookError::FileAccess(e.to_string());
+    let db_path = dir.join(MODELS_DB_FILE);
+    let encrypted_path = encrypted_db_path(&db_path);
+    if encrypted_path.exists() {
+        fs::rename(&encrypted_path, &db_path).map_err(file_error)?;
+        // left by the plain db
+        for suffix in ["-wal", "-shm", "-journal"] {
+            let _ = fs::remove_file(format!("{}{}", db_path.to_string_lossy(), suffix));
+        }
+    }
+    for entry in fs::read_dir(dir).map_err(file_error)? {
+        let entry = entry.map_err(file_error)?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = entry.path();
+        if name.ends_with(LANCE_TABLE_SUFFIX) && path.is_dir() {
+            info!("Deleting the plain embeddings table {:?}", path);
+            fs::remove_dir_all(&path).map_err(file_error)?;
+        } else if name.starts_with(MODELS_DB_FILE) && name.ends_with(".bak") {
+            // backups made before migrations, plain when made before the notebook was encrypted
+            let plain = fs::read(&path)
+                .map(|content| content.starts_with(PLAIN_SQLITE_HEADER))
+                .unwrap_or(false);
+            if plain {
+                info!("Deleting the plain models db backup {:?}", path);
+                fs::remove_file(&path).map_err(file_error)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlock_and_change_passphrase() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let key = NotebookKey::generate();
+        assert!(write_config(dir, &key, "short").is_err());
+        write_config(dir, &key, "correct horse").unwrap();
+        assert!(is_encrypted(dir));
+
+        let blob = key.encrypt_blob(b"attachment").unwrap();
+        assert!(NotebookKey::is_encrypted_blob(&blob));
+        let unlocked = unlock(dir, "correct horse").unwrap();
+        assert_eq!(
+            b"attachment".to_vec(),
+            unlocked.decrypt_blob(&blob).unwrap()
+        );
+        assert!(matches!(
+            unlock(dir, "wrong horse"),
+            Err(NotebookError::Encryption(_))
+        ));
+
+        change_passphrase(dir, "correct horse", "battery staple").unwrap();
+        assert!(unlock(dir, "correct horse").is_err());
+        // the key itself did not change, so existing data still decrypts
+        let unlocked = unlock(dir, "battery staple").unwrap();
+        assert_eq!(
+            b"attachment".to_vec(),
+            unlocked.decrypt_blob(&blob).unwrap()
+        );
+    }
+}
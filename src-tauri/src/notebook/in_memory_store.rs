@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use crate::notebook::chunking::NoteChunk;
+use crate::notebook::sqlite_vector_store::Embedder;
+use crate::notebook::vector_store::{SimilarText, VectorStore};
+use crate::notebook::NotebookError;
+
+const DIMENSIONS: usize = 256;
+
+/// A `VectorStore` that needs no model, for tests. Texts are embedded as hashed bags of words, so
+/// texts sharing words are close and the same texts always give the same results. The distance
+/// is one minus the cosine similarity, results closer than `1 - threshold` are returned.
+#[derive(Default)]
+pub struct InMemoryVectorStore {
+    vectors: Mutex<BTreeMap<String, (String, Vec<f32>)>>,
+}
+
+fn embed(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; DIMENSIONS];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        // FNV-1a, stable across runs unlike the std hasher
+        let hash = word
+            .to_lowercase()
+            .bytes()
+            .fold(0xcbf29ce484222325u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+            });
+        vector[(hash % DIMENSIONS as u64) as usize] += 1.0;
+    }
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+/// Embeds as `InMemoryVectorStore` does, for testing stores that take an `Embedder`
+pub struct BagOfWordsEmbedder;
+
+impl Embedder for BagOfWordsEmbedder {
+    fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, NotebookError> {
+        Ok(texts.iter().map(|text| embed(text)).collect())
+    }
+}
+
+#[async_trait]
+impl VectorStore for InMemoryVectorStore {
+    async fn upsert_chunks(&self, chunks: &[NoteChunk]) -> Result<(), NotebookError> {
+        let mut vectors = self.vectors.lock().unwrap();
+        for chunk in chunks {
+            vectors.insert(chunk.id.clone(), (chunk.text.clone(), embed(&chunk.text)));
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<(), NotebookError> {
+        let mut vectors = self.vectors.lock().unwrap();
+        for id in ids {
+            vectors.remove(id);
+        }
+        Ok(())
+    }
+
+    async fn find_similar(
+        &self,
+        text: &str,
+        limit: usize,
+        threshold: f32,
+    ) -> Result<Vec<SimilarText>, NotebookError> {
+        let query = embed(text);
+        let mut similar: Vec<SimilarText> = self
+            .vectors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, (text, vector))| SimilarText {
+                id: id.clone(),
+                text: text.clone(),
+                distance: 1.0 - query.iter().zip(vector).map(|(a, b)| a * b).sum::<f32>(),
+            })
+            .filter(|similar| 1.0 - similar.distance >= threshold)
+            .collect();
+        similar.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        similar.truncate(limit);
+        Ok(similar)
+    }
+
+    async fn empty(&self) -> Result<(), NotebookError> {
+        self.vectors.lock().unwrap().clear();
+        Ok(())
+    }
+
+    async fn list_ids(&self) -> Result<Option<Vec<String>>, NotebookError> {
+        Ok(Some(self.vectors.lock().unwrap().keys().cloned().collect()))
+    }
+}
@@ -0,0 +1,92 @@
+use serde::Serialize;
+
+const LINK_OPEN: &str = "[[";
+const LINK_CLOSE: &str = "]]";
+const ALIAS_SEPARATOR: char = '|';
+
+/// A `[[target]]` or `[[target|alias]]` link found in a note's text. The target is either a
+/// note id or a note title.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WikiLink {
+    pub target: String,
+    pub alias: Option<String>,
+}
+
+/// A link between two notes, resolved against the notes currently in the notebook
+#[derive(Debug, Clone, Serialize)]
+pub struct NoteLink {
+    pub source_id: String,
+    pub source_title: Option<String>,
+    /// The link target as written in the source note
+    pub target: String,
+    pub alias: Option<String>,
+    /// None when no note matches the target (a dangling link)
+    pub target_id: Option<String>,
+    pub target_title: Option<String>,
+}
+
+impl NoteLink {
+    pub fn is_dangling(&self) -> bool {
+        self.target_id.is_none()
+    }
+}
+
+/// Finds the wiki links in a note's text. Links cannot span lines, empty targets are ignored and
+/// each target is only returned once (the first alias wins).
+pub fn parse_wiki_links(text: &str) -> Vec<WikiLink> {
+    let mut links: Vec<WikiLink> = Vec::new();
+    for line in text.lines() {
+        let mut rest = line;
+        while let Some(start) = rest.find(LINK_OPEN) {
+            let after_open = &rest[start + LINK_OPEN.len()..];
+            let Some(end) = after_open.find(LINK_CLOSE) else {
+                break;
+            };
+            let inner = &after_open[..end];
+            rest = &after_open[end + LINK_CLOSE.len()..];
+
+            let (target, alias) = match inner.split_once(ALIAS_SEPARATOR) {
+                Some((target, alias)) => (target.trim(), Some(alias.trim())),
+                None => (inner.trim(), None),
+            };
+            if target.is_empty() || links.iter().any(|link| link.target == target) {
+                continue;
+            }
+            links.push(WikiLink {
+                target: target.to_string(),
+                alias: alias
+                    .filter(|alias| !alias.is_empty())
+                    .map(|alias| alias.to_string()),
+            });
+        }
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_wiki_links() {
+        let text = "See [[Rust Notes]] and [[ 6f1c|the tokio note ]].\n\
+                    Again [[Rust Notes|dupe]], [[]] and [[unclosed\n]] [[Next]]";
+        assert_eq!(
+            vec![
+                WikiLink {
+                    target: "Rust Notes".to_string(),
+                    alias: None
+                },
+                WikiLink {
+                    target: "6f1c".to_string(),
+                    alias: Some("the tokio note".to_string())
+                },
+                WikiLink {
+                    target: "Next".to_string(),
+                    alias: None
+                },
+            ],
+            parse_wiki_links(text)
+        );
+    }
+}
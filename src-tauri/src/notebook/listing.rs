@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::notebook::note::{Category, NoteFlags};
+
+/// Length of the plain text preview in a NoteSummary
+pub const SNIPPET_MAX_CHARS: usize = 160;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoteSortField {
+    Created,
+    #[default]
+    Modified,
+    Title,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortDirection {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// How a note has to match the category filters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CategoryMatch {
+    /// In at least one of the categories
+    #[default]
+    Any,
+    /// In every one of the categories
+    All,
+}
+
+/// Whether archived notes are returned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFilter {
+    /// Only notes that are not archived
+    #[default]
+    Exclude,
+    Include,
+    /// Only archived notes
+    Only,
+}
+
+impl ArchiveFilter {
+    pub fn allows(&self, archived: bool) -> bool {
+        match self {
+            ArchiveFilter::Exclude => !archived,
+            ArchiveFilter::Include => true,
+            ArchiveFilter::Only => archived,
+        }
+    }
+
+    /// The SQL condition on the archived `column`, None when every note passes
+    pub(crate) fn sql_condition(&self, column: &str) -> Option<String> {
+        match self {
+            ArchiveFilter::Exclude => Some(format!("{} = 0", column)),
+            ArchiveFilter::Include => None,
+            ArchiveFilter::Only => Some(format!("{} = 1", column)),
+        }
+    }
+}
+
+/// Selects a page of notes (outside the trash). Every field is optional, by default the most
+/// recently modified notes come first. Pinned notes always come before the others and archived
+/// notes are left out.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NoteListQuery {
+    pub sort: NoteSortField,
+    pub direction: SortDirection,
+    /// A note is in a category when it is in it or any of its descendants
+    pub category_ids: Vec<String>,
+    pub category_match: CategoryMatch,
+    /// Date ranges are inclusive unix timestamps
+    pub created_from: Option<i64>,
+    pub created_to: Option<i64>,
+    pub modified_from: Option<i64>,
+    pub modified_to: Option<i64>,
+    /// Only pinned (true) or unpinned (false) notes
+    pub pinned: Option<bool>,
+    /// Only favorite (true) or other (false) notes
+    pub favorite: Option<bool>,
+    pub archived: ArchiveFilter,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+/// The parts of a Note needed to list it, without its full text
+#[derive(Debug, Clone, Serialize)]
+pub struct NoteSummary {
+    pub id: String,
+    pub title: String,
+    /// Start of the note text after the title, whitespace collapsed
+    pub snippet: String,
+    pub categories: HashSet<Category>,
+    pub created: i64,
+    pub modified: i64,
+    #[serde(flatten)]
+    pub flags: NoteFlags,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NotePage {
+    pub notes: Vec<NoteSummary>,
+    /// Number of notes matching the query across all pages
+    pub total: usize,
+    pub offset: usize,
+    /// Offset of the next page, None on the last page
+    pub next_offset: Option<usize>,
+}
+
+/// Builds the preview of a note from the start of its content, skipping the title line
+pub fn note_snippet(content: &str, max_chars: usize) -> String {
+    let body = content.split_once('\n').map_or("", |(_, body)| body);
+    let collapsed = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    match collapsed.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", collapsed[..end].trim_end()),
+        None => collapsed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_note_snippet() {
+        assert_eq!(
+            "First line of the body continues",
+            note_snippet("# Title\n\nFirst line   of the\nbody continues\n", 100)
+        );
+        assert_eq!("héllo…", note_snippet("Title\nhéllo world", 5));
+        assert_eq!("", note_snippet("Only a title", 100));
+    }
+}
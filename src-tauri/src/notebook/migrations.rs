@@ -0,0 +1,443 @@
+use std::path::PathBuf;
+
+use chrono::Utc;
+use log::info;
+use rusqlite::{params, Connection, Transaction};
+
+use crate::notebook::chunking::CHUNK_MAX_CHARS;
+use crate::notebook::embeddings::EmbeddingOperation;
+use crate::notebook::links::parse_wiki_links;
+use crate::notebook::NotebookError;
+
+struct Migration {
+    description: &'static str,
+    sql: &'static str,
+    /// Data changes that need more than SQL, run in the same transaction after `sql`
+    backfill: Option<fn(&Transaction) -> rusqlite::Result<()>>,
+}
+
+/// Ordered schema migrations for the models db. The schema version is the number of migrations
+/// applied, stored in `PRAGMA user_version`.
+/// Only ever append to this list, a released migration must never be edited or reordered.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "initial schema",
+        // IF NOT EXISTS as databases created before migrations existed already have these
+        sql: "
+        CREATE TABLE IF NOT EXISTS categories (
+            id CHAR(36) PRIMARY KEY,
+            label  NVARCHAR(128) NOT NULL);
+        CREATE TABLE IF NOT EXISTS notes (
+            id CHAR(36) PRIMARY KEY,
+            content TEXT,
+            created INTEGER NOT NULL,
+            modified INTEGER NOT NULL);
+        CREATE TABLE IF NOT EXISTS note_category (
+            note_id CHAR(36),
+            category_id CHAR(36),
+            PRIMARY KEY (note_id, category_id),
+            FOREIGN KEY (note_id) REFERENCES notes (id) ON DELETE CASCADE,
+            FOREIGN KEY (category_id) REFERENCES categories (id));
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "full text index over note content",
+        // note_id is carried along (unindexed) so a match can be joined back to its Note.
+        // Triggers keep the index in sync with every write to the notes table.
+        sql: "
+        CREATE VIRTUAL TABLE IF NOT EXISTS notes_fts USING fts5(
+            note_id UNINDEXED,
+            content,
+            tokenize = 'unicode61 remove_diacritics 2');
+        CREATE TRIGGER IF NOT EXISTS notes_fts_insert AFTER INSERT ON notes BEGIN
+            INSERT INTO notes_fts (note_id, content) VALUES (new.id, new.content);
+        END;
+        CREATE TRIGGER IF NOT EXISTS notes_fts_update AFTER UPDATE OF content ON notes BEGIN
+            UPDATE notes_fts SET content = new.content WHERE note_id = old.id;
+        END;
+        CREATE TRIGGER IF NOT EXISTS notes_fts_delete AFTER DELETE ON notes BEGIN
+            DELETE FROM notes_fts WHERE note_id = old.id;
+        END;
+        INSERT INTO notes_fts (note_id, content)
+            SELECT id, content FROM notes WHERE id NOT IN (SELECT note_id FROM notes_fts);
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "note revision history",
+        sql: "
+        CREATE TABLE note_revisions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            note_id CHAR(36) NOT NULL,
+            content TEXT,
+            created INTEGER NOT NULL,
+            reason TEXT,
+            FOREIGN KEY (note_id) REFERENCES notes (id) ON DELETE CASCADE);
+        CREATE INDEX idx_note_revisions_note_id ON note_revisions (note_id, created);
+        INSERT INTO note_revisions (note_id, content, created)
+            SELECT id, content, modified FROM notes;
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "trash bin, notes are soft deleted",
+        // deleted holds the time a note was moved to the trash, NULL for live notes
+        sql: "
+        ALTER TABLE notes ADD COLUMN deleted INTEGER;
+        CREATE INDEX idx_notes_deleted ON notes (deleted);
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "nested categories",
+        // existing categories become top level categories
+        sql: "
+        ALTER TABLE categories ADD COLUMN parent_id CHAR(36) REFERENCES categories (id);
+        CREATE INDEX idx_categories_parent_id ON categories (parent_id);
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "note titles and links between notes",
+        // The title is the first line of a note without its markdown heading marks.
+        // Link targets are kept as written and resolved to notes (by id or title) when queried,
+        // so a link to a note that does not exist yet resolves once that note is written.
+        sql: "
+        ALTER TABLE notes ADD COLUMN title TEXT GENERATED ALWAYS AS (
+            trim(ltrim(substr(content, 1, instr(content || char(10), char(10)) - 1), '# '),
+                ' ' || char(9) || char(13))
+        ) VIRTUAL;
+        CREATE INDEX idx_notes_title ON notes (LOWER(title));
+        CREATE TABLE note_links (
+            source_id CHAR(36) NOT NULL,
+            target TEXT NOT NULL,
+            alias TEXT,
+            PRIMARY KEY (source_id, target),
+            FOREIGN KEY (source_id) REFERENCES notes (id) ON DELETE CASCADE);
+        CREATE INDEX idx_note_links_target ON note_links (LOWER(target));
+        ",
+        backfill: Some(backfill_note_links),
+    },
+    Migration {
+        description: "note attachments",
+        // attachments holds one row per stored blob, note_attachments one row per file attached
+        // to a note, several of which can share a blob
+        sql: "
+        CREATE TABLE attachments (
+            hash CHAR(64) PRIMARY KEY,
+            size INTEGER NOT NULL,
+            created INTEGER NOT NULL);
+        CREATE TABLE note_attachments (
+            id CHAR(36) PRIMARY KEY,
+            note_id CHAR(36) NOT NULL,
+            hash CHAR(64) NOT NULL,
+            file_name TEXT NOT NULL,
+            added INTEGER NOT NULL,
+            FOREIGN KEY (note_id) REFERENCES notes (id) ON DELETE CASCADE,
+            FOREIGN KEY (hash) REFERENCES attachments (hash));
+        CREATE INDEX idx_note_attachments_note_id ON note_attachments (note_id);
+        CREATE INDEX idx_note_attachments_hash ON note_attachments (hash);
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "note listing indexes",
+        sql: "
+        CREATE INDEX idx_notes_created ON notes (created);
+        CREATE INDEX idx_notes_modified ON notes (modified);
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "pending embeddings",
+        // Written in the same transaction as the note change it follows, and removed once the
+        // embeddings db has caught up. No foreign key as deletes outlive their note.
+        sql: "
+        CREATE TABLE pending_embeddings (
+            note_id CHAR(36) PRIMARY KEY,
+            operation TEXT NOT NULL,
+            queued INTEGER NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT);
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "embeddings ledger",
+        // One row per vector in the embeddings db with the hash of the content it was computed
+        // from. Vectors written before the ledger existed are unknown to it until a rebuild.
+        sql: "
+        CREATE TABLE note_embeddings (
+            note_id CHAR(36) PRIMARY KEY,
+            content_hash CHAR(64) NOT NULL,
+            embedded INTEGER NOT NULL);
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "pending embedding revisions",
+        sql: "
+        ALTER TABLE pending_embeddings ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "note chunks",
+        // One row per vector in the embeddings db, a note is embedded as one or more chunks.
+        // Ranges are in characters of the note content.
+        sql: "
+        CREATE TABLE note_chunks (
+            chunk_id TEXT PRIMARY KEY,
+            note_id CHAR(36) NOT NULL,
+            start INTEGER NOT NULL,
+            end INTEGER NOT NULL);
+        CREATE INDEX idx_note_chunks_note_id ON note_chunks (note_id);
+        ",
+        backfill: Some(backfill_long_note_embeddings),
+    },
+    Migration {
+        description: "embedding model",
+        // The model the vectors in the embeddings db were computed with, a single row.
+        // `cleared` is 0 from queueing a full re-index until the embeddings db has been emptied,
+        // so a switch interrupted in between is finished on the next open.
+        // Notebooks with notes were embedded with the only model there was.
+        sql: "
+        CREATE TABLE embedding_index (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            model TEXT NOT NULL,
+            cleared INTEGER NOT NULL);
+        INSERT INTO embedding_index (id, model, cleared)
+            SELECT 1, 'bge-small-en-v1.5', 1 WHERE EXISTS (SELECT 1 FROM notes);
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "chunk vectors",
+        // The vectors of an encrypted notebook, kept in its encrypted models db rather than in
+        // the embeddings db. Empty for other notebooks. Vectors are little endian f32s.
+        sql: "
+        CREATE TABLE chunk_vectors (
+            chunk_id TEXT PRIMARY KEY,
+            text TEXT NOT NULL,
+            vector BLOB NOT NULL);
+        ",
+        backfill: None,
+    },
+    Migration {
+        description: "note flags",
+        // Set by the user and left untouched by saves, so they don't change `modified`
+        sql: "
+        ALTER TABLE notes ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE notes ADD COLUMN favorite INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE notes ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+        ",
+        backfill: None,
+    },
+];
+
+/// The schema version this build of the app expects
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+pub fn schema_version(conn: &Connection) -> Result<u32, NotebookError> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+/// Brings the models db up to the latest schema version.
+/// - A db with a newer version than this app knows about is refused rather than risk corrupting it.
+/// - A file backed db is copied aside before any migration runs.
+/// - All pending migrations run in a single transaction, so a failure leaves the db untouched.
+pub fn migrate(conn: &mut Connection) -> Result<(), NotebookError> {
+    let current = schema_version(conn)?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(NotebookError::SchemaVersion(format!(
+            "models db is at schema version {} but this version of the app only supports up to {}",
+            current, latest
+        )));
+    }
+    if current == latest {
+        info!("Models db schema is up to date at version {}", current);
+        return Ok(());
+    }
+
+    if let Some(backup_path) = backup(conn, current)? {
+        info!("Backed up models db to {:?} before migrating", backup_path);
+    }
+
+    let tx = conn.transaction()?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let version = index as u32 + 1;
+        info!(
+            "Migrating models db to version {}: {}",
+            version, migration.description
+        );
+        tx.execute_batch(migration.sql)
+            .and_then(|_| migration.backfill.map_or(Ok(()), |backfill| backfill(&tx)))
+            .map_err(|e| {
+                NotebookError::SchemaVersion(format!(
+                    "migration to version {} ({}) failed: {}",
+                    version, migration.description, e
+                ))
+            })?;
+    }
+    // user_version lives in the db header and is covered by the transaction
+    tx.pragma_update(None, "user_version", latest)?;
+    tx.commit()?;
+    info!("Models db migrated from version {} to {}", current, latest);
+    Ok(())
+}
+
+fn backfill_note_links(tx: &Transaction) -> rusqlite::Result<()> {
+    let mut select = tx.prepare("SELECT id, content FROM notes WHERE content IS NOT NULL")?;
+    let mut insert =
+        tx.prepare("INSERT INTO note_links (source_id, target, alias) VALUES (?1, ?2, ?3)")?;
+    let notes = select
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (id, content) in notes {
+        for link in parse_wiki_links(&content) {
+            insert.execute(params![id, link.target, link.alias])?;
+        }
+    }
+    Ok(())
+}
+
+/// Queues notes too long for a single chunk to be embedded again, shorter notes keep the vector
+/// they were embedded with as their only chunk
+fn backfill_long_note_embeddings(tx: &Transaction) -> rusqlite::Result<()> {
+    let mut select = tx.prepare("SELECT id, content FROM notes WHERE content IS NOT NULL")?;
+    let mut queue = tx.prepare(
+        "INSERT INTO pending_embeddings (note_id, operation, queued, attempts)
+        VALUES (?1, ?2, ?3, 0)
+        ON CONFLICT (note_id) DO NOTHING",
+    )?;
+    let notes = select
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let now = Utc::now().timestamp();
+    for (id, content) in notes {
+        if content.chars().count() > CHUNK_MAX_CHARS {
+            queue.execute(params![id, EmbeddingOperation::Upsert.as_str(), now])?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the db next to itself as `<db>.v<version>.<timestamp>.bak`.
+/// Returns None for in memory dbs or a db that has no tables yet (nothing to lose).
+fn backup(conn: &Connection, version: u32) -> Result<Option<PathBuf>, NotebookError> {
+    let db_path = match conn.path() {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => return Ok(None),
+    };
+    let table_count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'",
+        [],
+        |row| row.get(0),
+    )?;
+    if table_count == 0 {
+        return Ok(None);
+    }
+    let file_name = db_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "db.sqlite".to_string());
+    let backup_path = db_path.with_file_name(format!(
+        "{}.v{}.{}.bak",
+        file_name,
+        version,
+        Utc::now().format("%Y%m%d%H%M%S")
+    ));
+    let backup_path_str = backup_path
+        .to_str()
+        .ok_or(NotebookError::FileAccess(format!(
+            "Invalid backup path {:?}",
+            backup_path
+        )))?;
+    // VACUUM INTO writes a consistent, compacted copy of the db
+    conn.execute("VACUUM INTO ?1", [backup_path_str])?;
+    Ok(Some(backup_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_new_db() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(latest_version(), schema_version(&conn).unwrap());
+        // running again is a no-op
+        migrate(&mut conn).unwrap();
+        assert_eq!(latest_version(), schema_version(&conn).unwrap());
+    }
+
+    #[test]
+    fn test_migrate_pre_migrations_db() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+        conn.execute(
+            "INSERT INTO notes (id, content, created, modified) VALUES ('1', 'kept [[Other]]', 0, 0)",
+            [],
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        let indexed: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM notes_fts WHERE notes_fts MATCH 'kept'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(1, indexed);
+        let target: String = conn
+            .query_row(
+                "SELECT target FROM note_links WHERE source_id = '1'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!("Other", target);
+    }
+
+    #[test]
+    fn test_backup_before_migrating() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let mut conn = Connection::open(dir.join("db.sqlite")).unwrap();
+        // an empty db has nothing worth backing up
+        migrate(&mut conn).unwrap();
+        assert_eq!(1, std::fs::read_dir(dir).unwrap().count());
+
+        let mut conn = Connection::open(dir.join("db-v1.sqlite")).unwrap();
+        conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        migrate(&mut conn).unwrap();
+        let backups: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".bak"))
+            .collect();
+        assert_eq!(1, backups.len());
+        assert!(backups[0].starts_with("db-v1.sqlite.v1."));
+    }
+
+    #[test]
+    fn test_refuse_newer_db() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(NotebookError::SchemaVersion(_))
+        ));
+    }
+}
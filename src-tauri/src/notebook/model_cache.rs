@@ -0,0 +1,577 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use fastembed::TextEmbedding;
+use log::{info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::notebook::embeddings::EmbeddingModelChoice;
+use crate::notebook::NotebookError;
+
+// The cache has the layout fastembed downloads models into (that of the hf-hub crate), so models
+// put there by either are found by both:
+// <cache>/models--<org>--<name>/refs/main holds a revision, files are in snapshots/<revision>/
+const HF_ENDPOINT: &str = "https://huggingface.co";
+const REVISION: &str = "main";
+// revision used when this module creates a model's cache entry
+const LOCAL_REVISION: &str = "local";
+const REPO_DIR_PREFIX: &str = "models--";
+// checksums of the files downloaded or installed by this module, hf-hub names its blobs by theirs
+const CHECKSUMS_FILE: &str = "knowling-checksums.json";
+const PARTIAL_SUFFIX: &str = ".part";
+// files read by fastembed besides the onnx model
+const TOKENIZER_FILES: [&str; 4] = [
+    "tokenizer.json",
+    "config.json",
+    "special_tokens_map.json",
+    "tokenizer_config.json",
+];
+// bytes downloaded between two progress notifications
+const PROGRESS_INTERVAL_BYTES: u64 = 1024 * 1024;
+
+/// Emitted with a `DownloadProgress` while a model is downloaded
+pub const MODEL_DOWNLOAD_EVENT: &str = "model-download-progress";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum DownloadProgress {
+    Downloading {
+        model: String,
+        file: String,
+        downloaded_bytes: u64,
+        /// Unknown when the server does not send it
+        total_bytes: Option<u64>,
+    },
+    Finished {
+        model: String,
+    },
+    Failed {
+        model: String,
+        error: String,
+    },
+}
+
+pub type DownloadListener = Arc<dyn Fn(DownloadProgress) + Send + Sync>;
+
+/// A model found in the cache
+#[derive(Debug, Clone, Serialize)]
+pub struct CachedModel {
+    /// None for models the app does not offer, left by another version
+    pub model: Option<String>,
+    pub repo: String,
+    pub size_bytes: u64,
+    /// Whether every file needed to load the model is there
+    pub complete: bool,
+    pub active: bool,
+}
+
+/// Outcome of checking a cached model's files
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelCheck {
+    pub model: String,
+    pub ok: bool,
+    pub problems: Vec<String>,
+}
+
+/// Where a model is downloaded from and the files it is made of
+struct ModelSource {
+    repo: String,
+    files: Vec<String>,
+}
+
+fn model_source(model: EmbeddingModelChoice) -> Result<ModelSource, NotebookError> {
+    let fastembed_model = model.fastembed_model();
+    let info = TextEmbedding::list_supported_models()
+        .into_iter()
+        .find(|info| info.model == fastembed_model)
+        .ok_or(NotebookError::UnknownEmbeddingModel(
+            model.as_str().to_string(),
+        ))?;
+    let mut files = vec![info.model_file];
+    files.extend(TOKENIZER_FILES.iter().map(|file| file.to_string()));
+    Ok(ModelSource {
+        repo: info.model_code,
+        files,
+    })
+}
+
+fn cache_error(context: impl std::fmt::Display, e: impl std::fmt::Display) -> NotebookError {
+    NotebookError::ModelCache(format!("{}: {}", context, e))
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(PARTIAL_SUFFIX);
+    PathBuf::from(partial)
+}
+
+fn hash_file(path: &Path) -> Result<String, NotebookError> {
+    let mut file = fs::File::open(path).map_err(|e| cache_error(path.display(), e))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .map_err(|e| cache_error(path.display(), e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Size of the files under `path`, links are not followed so blobs are counted once
+fn dir_size(path: &Path) -> u64 {
+    fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            _ => entry.metadata().map_or(0, |metadata| metadata.len()),
+        })
+        .sum()
+}
+
+/// The embedding models downloaded to the app's cache dir. Clones share their download listener
+/// and only one download runs at a time.
+#[derive(Clone)]
+pub struct ModelCache {
+    dir: PathBuf,
+    listener: Arc<RwLock<Option<DownloadListener>>>,
+    download_lock: Arc<Mutex<()>>,
+}
+
+impl ModelCache {
+    pub fn new(dir: PathBuf) -> Self {
+        ModelCache {
+            dir,
+            listener: Arc::new(RwLock::new(None)),
+            download_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Calls `listener` with the progress of every later download
+    pub fn set_listener(&self, listener: DownloadListener) {
+        *self.listener.write().unwrap() = Some(listener);
+    }
+
+    fn notify(&self, progress: DownloadProgress) {
+        if let Some(listener) = self.listener.read().unwrap().as_ref() {
+            listener(progress);
+        }
+    }
+
+    fn repo_dir(&self, repo: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}{}", REPO_DIR_PREFIX, repo.replace('/', "--")))
+    }
+
+    fn snapshot_dir(&self, repo: &str) -> Option<PathBuf> {
+        let repo_dir = self.repo_dir(repo);
+        let revision = fs::read_to_string(repo_dir.join("refs").join(REVISION)).ok()?;
+        Some(repo_dir.join("snapshots").join(revision.trim()))
+    }
+
+    /// The snapshot files are written to, created with its ref if the model has none
+    fn writable_snapshot_dir(&self, repo: &str) -> Result<PathBuf, NotebookError> {
+        let snapshot = match self.snapshot_dir(repo) {
+            Some(snapshot) => snapshot,
+            None => {
+                let refs_dir = self.repo_dir(repo).join("refs");
+                fs::create_dir_all(&refs_dir).map_err(|e| cache_error(refs_dir.display(), e))?;
+                fs::write(refs_dir.join(REVISION), LOCAL_REVISION)
+                    .map_err(|e| cache_error(refs_dir.display(), e))?;
+                self.repo_dir(repo).join("snapshots").join(LOCAL_REVISION)
+            }
+        };
+        fs::create_dir_all(&snapshot).map_err(|e| cache_error(snapshot.display(), e))?;
+        Ok(snapshot)
+    }
+
+    fn read_checksums(&self, repo: &str) -> BTreeMap<String, String> {
+        fs::read_to_string(self.repo_dir(repo).join(CHECKSUMS_FILE))
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    fn record_checksum(
+        &self,
+        repo: &str,
+        file: &str,
+        checksum: String,
+    ) -> Result<(), NotebookError> {
+        let mut checksums = self.read_checksums(repo);
+        checksums.insert(file.to_string(), checksum);
+        let path = self.repo_dir(repo).join(CHECKSUMS_FILE);
+        let json =
+            serde_json::to_string_pretty(&checksums).map_err(|e| cache_error(path.display(), e))?;
+        fs::write(&path, json).map_err(|e| cache_error(path.display(), e))
+    }
+
+    fn missing_files(&self, source: &ModelSource) -> Vec<String> {
+        let snapshot = self.snapshot_dir(&source.repo);
+        source
+            .files
+            .iter()
+            .filter(|file| {
+                let size = snapshot
+                    .as_ref()
+                    .and_then(|snapshot| fs::metadata(snapshot.join(file)).ok())
+                    .map(|metadata| metadata.len());
+                size.unwrap_or(0) == 0
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Whether every file needed to load the model is in the cache
+    pub fn is_cached(&self, model: EmbeddingModelChoice) -> bool {
+        model_source(model).is_ok_and(|source| self.missing_files(&source).is_empty())
+    }
+
+    /// The models in the cache, `active` being the one notebooks are embedded with
+    pub fn list(&self, active: EmbeddingModelChoice) -> Result<Vec<CachedModel>, NotebookError> {
+        let known: Vec<(EmbeddingModelChoice, ModelSource)> = EmbeddingModelChoice::ALL
+            .into_iter()
+            .filter_map(|model| model_source(model).ok().map(|source| (model, source)))
+            .collect();
+        if !self.dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut cached = vec![];
+        for entry in fs::read_dir(&self.dir).map_err(|e| cache_error(self.dir.display(), e))? {
+            let entry = entry.map_err(|e| cache_error(self.dir.display(), e))?;
+            let dir_name = entry.file_name().to_string_lossy().into_owned();
+            let Some(folder) = dir_name.strip_prefix(REPO_DIR_PREFIX) else {
+                continue;
+            };
+            let source = known
+                .iter()
+                .find(|(_, source)| source.repo.replace('/', "--") == folder);
+            cached.push(CachedModel {
+                model: source.map(|(model, _)| model.as_str().to_string()),
+                repo: source.map_or(folder.replacen("--", "/", 1), |(_, source)| {
+                    source.repo.clone()
+                }),
+                size_bytes: dir_size(&entry.path()),
+                complete: source.is_some_and(|(_, source)| self.missing_files(source).is_empty()),
+                active: source.is_some_and(|(model, _)| *model == active),
+            });
+        }
+        cached.sort_by(|a, b| a.repo.cmp(&b.repo));
+        Ok(cached)
+    }
+
+    /// Checks that the model's files are all there, are not truncated and, where their
+    /// checksum is known, have not been altered. This reads every file so it can take a while.
+    pub fn verify(&self, model: EmbeddingModelChoice) -> Result<ModelCheck, NotebookError> {
+        let source = model_source(model)?;
+        let checksums = self.read_checksums(&source.repo);
+        let snapshot = self.snapshot_dir(&source.repo);
+        let mut problems = vec![];
+        for file in &source.files {
+            let path = match &snapshot {
+                Some(snapshot) if snapshot.join(file).exists() => snapshot.join(file),
+                _ => {
+                    problems.push(format!("{} is missing", file));
+                    continue;
+                }
+            };
+            if !fs::metadata(&path).is_ok_and(|metadata| metadata.len() > 0) {
+                problems.push(format!("{} is empty", file));
+                continue;
+            }
+            // hf-hub links snapshot files to blobs named by their sha256 when they are large
+            let blob_checksum = fs::read_link(&path).ok().and_then(|target| {
+                target
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .filter(|name| name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit()))
+            });
+            if let Some(expected) = checksums.get(file).cloned().or(blob_checksum) {
+                if hash_file(&path)? != expected {
+                    problems.push(format!("{} does not match its checksum", file));
+                    continue;
+                }
+            }
+            if file.ends_with(".json") {
+                let parses = fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+                    .is_some();
+                if !parses {
+                    problems.push(format!("{} is not valid JSON", file));
+                }
+            }
+        }
+        Ok(ModelCheck {
+            model: model.as_str().to_string(),
+            ok: problems.is_empty(),
+            problems,
+        })
+    }
+
+    /// Downloads the model's missing files, notifying the listener of the progress. Files are
+    /// written under a temporary name and renamed once complete, so an interrupted download
+    /// never leaves a truncated file to load.
+    pub async fn download(&self, model: EmbeddingModelChoice) -> Result<(), NotebookError> {
+        let _download = self.download_lock.lock().await;
+        let source = model_source(model)?;
+        let missing = self.missing_files(&source);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "Downloading embedding model {} from {}",
+            model.as_str(),
+            source.repo
+        );
+        let downloaded = self.download_files(model, &source.repo, &missing).await;
+        match &downloaded {
+            Ok(()) => self.notify(DownloadProgress::Finished {
+                model: model.as_str().to_string(),
+            }),
+            Err(e) => {
+                warn!(
+                    "Failed to download embedding model {}: {}",
+                    model.as_str(),
+                    e
+                );
+                self.notify(DownloadProgress::Failed {
+                    model: model.as_str().to_string(),
+                    error: e.to_string(),
+                })
+            }
+        }
+        downloaded
+    }
+
+    async fn download_files(
+        &self,
+        model: EmbeddingModelChoice,
+        repo: &str,
+        files: &[String],
+    ) -> Result<(), NotebookError> {
+        let snapshot = self.writable_snapshot_dir(repo)?;
+        let client = reqwest::Client::new();
+        for file in files {
+            let url = format!("{}/{}/resolve/{}/{}", HF_ENDPOINT, repo, REVISION, file);
+            let mut response = client
+                .get(&url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| cache_error(&url, e))?;
+            let total_bytes = response.content_length();
+            let path = snapshot.join(file);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| cache_error(parent.display(), e))?;
+            }
+            let partial = partial_path(&path);
+            let mut out =
+                fs::File::create(&partial).map_err(|e| cache_error(partial.display(), e))?;
+            let mut hasher = Sha256::new();
+            let mut downloaded_bytes = 0u64;
+            let mut notified_bytes = 0u64;
+            while let Some(bytes) = response.chunk().await.map_err(|e| cache_error(&url, e))? {
+                out.write_all(&bytes)
+                    .map_err(|e| cache_error(partial.display(), e))?;
+                hasher.update(&bytes);
+                downloaded_bytes += bytes.len() as u64;
+                if downloaded_bytes - notified_bytes >= PROGRESS_INTERVAL_BYTES {
+                    notified_bytes = downloaded_bytes;
+                    self.notify(DownloadProgress::Downloading {
+                        model: model.as_str().to_string(),
+                        file: file.clone(),
+                        downloaded_bytes,
+                        total_bytes,
+                    });
+                }
+            }
+            out.flush().map_err(|e| cache_error(partial.display(), e))?;
+            drop(out);
+            self.notify(DownloadProgress::Downloading {
+                model: model.as_str().to_string(),
+                file: file.clone(),
+                downloaded_bytes,
+                total_bytes,
+            });
+            // replaces the link to a blob if there was one
+            fs::rename(&partial, &path).map_err(|e| cache_error(path.display(), e))?;
+            self.record_checksum(repo, file, format!("{:x}", hasher.finalize()))?;
+        }
+        Ok(())
+    }
+
+    /// Installs a model from files copied off a machine that could download it. `source` is
+    /// either a directory holding the model's files, laid out as in the model's repository or
+    /// all side by side, or a single one of the model's files.
+    pub fn install_from(
+        &self,
+        model: EmbeddingModelChoice,
+        source: &Path,
+    ) -> Result<ModelCheck, NotebookError> {
+        let model_source = model_source(model)?;
+        let file_name = |file: &str| file.rsplit('/').next().unwrap_or(file).to_string();
+        let mut found: Vec<(String, PathBuf)> = vec![];
+        if source.is_dir() {
+            for file in &model_source.files {
+                let candidates = [source.join(file), source.join(file_name(file))];
+                if let Some(path) = candidates.into_iter().find(|path| path.is_file()) {
+                    found.push((file.clone(), path));
+                }
+            }
+        } else if source.is_file() {
+            let source_name = source
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let file = model_source
+                .files
+                .iter()
+                .find(|file| file_name(file) == source_name)
+                .ok_or(NotebookError::ModelCache(format!(
+                    "{} is not one of the files of {}, expected one of: {}",
+                    source_name,
+                    model.as_str(),
+                    model_source.files.join(", ")
+                )))?;
+            found.push((file.clone(), source.to_path_buf()));
+        } else {
+            return Err(NotebookError::ModelCache(format!(
+                "{:?} does not exist",
+                source
+            )));
+        }
+        if found.is_empty() {
+            return Err(NotebookError::ModelCache(format!(
+                "None of the files of {} are in {:?}, expected: {}",
+                model.as_str(),
+                source,
+                model_source.files.join(", ")
+            )));
+        }
+
+        info!(
+            "Installing embedding model {} from {:?}",
+            model.as_str(),
+            source
+        );
+        let snapshot = self.writable_snapshot_dir(&model_source.repo)?;
+        for (file, from) in found {
+            let path = snapshot.join(&file);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| cache_error(parent.display(), e))?;
+            }
+            let partial = partial_path(&path);
+            fs::copy(&from, &partial).map_err(|e| cache_error(from.display(), e))?;
+            fs::rename(&partial, &path).map_err(|e| cache_error(path.display(), e))?;
+            self.record_checksum(&model_source.repo, &file, hash_file(&path)?)?;
+        }
+        self.verify(model)
+    }
+
+    /// Deletes every cached model but `keep`, returning the repositories of those deleted.
+    /// Waits for a running download, which would otherwise lose its files. Blocks, so it must
+    /// not be called on the async runtime.
+    pub fn delete_unused(&self, keep: EmbeddingModelChoice) -> Result<Vec<String>, NotebookError> {
+        let _download = self.download_lock.blocking_lock();
+        let kept_repo = model_source(keep)?.repo;
+        let mut deleted = vec![];
+        for cached in self.list(keep)? {
+            if cached.repo == kept_repo {
+                continue;
+            }
+            let dir = self.repo_dir(&cached.repo);
+            info!(
+                "Deleting cached embedding model {} ({} bytes)",
+                cached.repo, cached.size_bytes
+            );
+            fs::remove_dir_all(&dir).map_err(|e| cache_error(dir.display(), e))?;
+            deleted.push(cached.repo);
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_model_files(dir: &Path, source: &ModelSource) {
+        for file in &source.files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let content = if file.ends_with(".json") {
+                "{}"
+            } else {
+                "onnx"
+            };
+            fs::write(path, content).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_install_verify_and_delete_unused() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let cache = ModelCache::new(root.join("cache"));
+        let model = EmbeddingModelChoice::BgeSmallEn;
+        let other = EmbeddingModelChoice::MultilingualE5Small;
+        assert!(!cache.is_cached(model));
+        assert!(cache.list(model).unwrap().is_empty());
+
+        // a directory laid out as the model's repository
+        let source = model_source(model).unwrap();
+        write_model_files(&root.join("source"), &source);
+        let check = cache.install_from(model, &root.join("source")).unwrap();
+        assert!(check.ok, "{:?}", check.problems);
+        assert!(cache.is_cached(model));
+
+        // a single file is added to the other model's files
+        let other_source = model_source(other).unwrap();
+        fs::write(root.join("tokenizer.json"), "{}").unwrap();
+        let check = cache
+            .install_from(other, &root.join("tokenizer.json"))
+            .unwrap();
+        assert!(!check.ok);
+        assert_eq!(other_source.files.len() - 1, check.problems.len());
+        assert!(cache
+            .install_from(other, &root.join("source").join("onnx"))
+            .is_ok());
+        assert!(cache
+            .install_from(model, &root.join("nothing-here"))
+            .is_err());
+
+        let listed = cache.list(model).unwrap();
+        assert_eq!(2, listed.len());
+        let installed = listed.iter().find(|cached| cached.active).unwrap();
+        assert_eq!(Some(model.as_str().to_string()), installed.model);
+        assert!(installed.complete);
+        assert!(installed.size_bytes > 0);
+
+        // an altered file no longer matches its checksum
+        let snapshot = cache.snapshot_dir(&source.repo).unwrap();
+        fs::write(snapshot.join("config.json"), "{\"altered\": true}").unwrap();
+        let check = cache.verify(model).unwrap();
+        assert_eq!(
+            vec!["config.json does not match its checksum".to_string()],
+            check.problems
+        );
+
+        assert_eq!(vec![other_source.repo], cache.delete_unused(model).unwrap());
+        assert_eq!(1, cache.list(model).unwrap().len());
+    }
+}
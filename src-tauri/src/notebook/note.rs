@@ -3,12 +3,17 @@ use std::hash::{Hash, Hasher};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use vec_embed_store::TextChunk;
+
+use crate::notebook::chunking::Passage;
+
+/// Separates the labels of nested categories in a category path, ex: `Programming/Rust/Async`
+pub const CATEGORY_PATH_SEPARATOR: char = '/';
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Category {
     id: String,
     label: String,
+    parent_id: Option<String>,
 }
 
 impl PartialEq for Category {
@@ -26,13 +31,14 @@ impl Hash for Category {
 }
 
 impl Category {
-    pub(crate) fn new(label: &str) -> Self {
-        Self::hydrate(&Self::generate_id(), label)
+    pub(crate) fn new(label: &str, parent_id: Option<&str>) -> Self {
+        Self::hydrate(&Self::generate_id(), label, parent_id)
     }
-    pub(crate) fn hydrate(id: &str, label: &str) -> Self {
+    pub(crate) fn hydrate(id: &str, label: &str, parent_id: Option<&str>) -> Self {
         Category {
             id: id.to_string(),
             label: label.to_string(),
+            parent_id: parent_id.map(|id| id.to_string()),
         }
     }
 
@@ -42,11 +48,59 @@ impl Category {
     pub(crate) fn get_label(&self) -> &str {
         &self.label
     }
+    pub(crate) fn get_parent_id(&self) -> Option<&str> {
+        self.parent_id.as_deref()
+    }
     fn generate_id() -> String {
         Uuid::new_v4().to_string()
     }
 }
 
+/// A Category with the number of notes that use it
+#[derive(Debug, Clone, Serialize)]
+pub struct CategorySummary {
+    pub category: Category,
+    pub note_count: usize,
+}
+
+/// A Category and its descendants, as returned by the category tree
+#[derive(Debug, Clone, Serialize)]
+pub struct CategoryNode {
+    pub category: Category,
+    /// Notes directly in this category, not counting descendants
+    pub note_count: usize,
+    pub children: Vec<CategoryNode>,
+}
+
+/// States set on a note to keep the working set small: pinned notes are listed first and
+/// archived notes are left out of listings, search and similar notes unless asked for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct NoteFlags {
+    pub pinned: bool,
+    pub favorite: bool,
+    pub archived: bool,
+}
+
+/// One of the NoteFlags, to set it on notes
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoteFlag {
+    Pinned,
+    Favorite,
+    Archived,
+}
+
+impl NoteFlag {
+    /// The notes column holding the flag
+    pub(crate) fn column(&self) -> &'static str {
+        match self {
+            NoteFlag::Pinned => "pinned",
+            NoteFlag::Favorite => "favorite",
+            NoteFlag::Archived => "archived",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Note {
     id: String,
@@ -54,6 +108,8 @@ pub struct Note {
     categories: HashSet<Category>,
     created: i64,
     modified: i64,
+    #[serde(flatten, default)]
+    flags: NoteFlags,
 }
 
 impl Note {}
@@ -77,9 +133,15 @@ impl Note {
             categories,
             created,
             modified,
+            flags: NoteFlags::default(),
         }
     }
 
+    pub(crate) fn with_flags(mut self, flags: NoteFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
     pub(crate) fn get_id(&self) -> &str {
         &self.id
     }
@@ -97,6 +159,10 @@ impl Note {
         self.modified
     }
 
+    pub(crate) fn get_flags(&self) -> NoteFlags {
+        self.flags
+    }
+
     pub(crate) fn has_category(&self, category: &Category) -> bool {
         self.categories.contains(category)
     }
@@ -121,14 +187,15 @@ impl Note {
     pub(crate) fn set_text(&mut self, text: &str) {
         self.text = text.to_string();
     }
+}
 
-
-    pub(crate) fn to_text_chunk(&self) -> TextChunk {
-        TextChunk {
-            id: self.id.to_string(),
-            text: self.text.to_string(),
-        }
-    }
+/// Splits a category path such as `Programming / Rust/Async` into its labels, ignoring
+/// empty segments
+pub fn parse_category_path(path: &str) -> Vec<&str> {
+    path.split(CATEGORY_PATH_SEPARATOR)
+        .map(|label| label.trim())
+        .filter(|label| !label.is_empty())
+        .collect()
 }
 
 impl PartialEq for Note {
@@ -137,6 +204,35 @@ impl PartialEq for Note {
     }
 }
 
+/// A Note in the trash
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashedNote {
+    pub note: Note,
+    /// When the note was moved to the trash
+    pub deleted: i64,
+}
+
+/// A saved state of a Note's text
+#[derive(Debug, Clone, Serialize)]
+pub struct NoteRevision {
+    pub id: i64,
+    pub note_id: String,
+    pub text: String,
+    pub created: i64,
+    /// Why the revision was taken, None for regular saves
+    pub reason: Option<String>,
+}
+
+/// A Note matched by a full text search
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub note: Note,
+    /// bm25 relevance, lower is more relevant
+    pub score: f64,
+    /// Fragment of the note content around the match, matched terms wrapped in `<mark>`
+    pub snippet: String,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -147,6 +243,16 @@ mod tests {
         assert_eq!("1", note.get_id());
     }
 
+    #[test]
+    fn test_parse_category_path() {
+        assert_eq!(
+            vec!["Programming", "Rust", "Async"],
+            parse_category_path(" Programming / Rust//Async/")
+        );
+        assert_eq!(vec!["rust"], parse_category_path("rust"));
+        assert!(parse_category_path(" / ").is_empty());
+    }
+
     #[test]
     fn test_get_content() {
         let content = "Test Note";
@@ -154,3 +260,28 @@ mod tests {
         assert_eq!(note.get_text(), content);
     }
 }
+
+/// A Note returned by hybrid (keyword + semantic) search, carrying the score from each retriever
+/// that found it
+#[derive(Debug, Clone, Serialize)]
+pub struct HybridHit {
+    pub note: Note,
+    /// Reciprocal rank fusion score, higher is more relevant
+    pub score: f32,
+    /// bm25 score when matched by keyword search
+    pub keyword_score: Option<f64>,
+    /// Embedding distance when matched by semantic search
+    pub semantic_distance: Option<f32>,
+    pub snippet: Option<String>,
+    /// Part of the note closest to the query when matched by semantic search
+    pub passage: Option<Passage>,
+}
+
+/// A Note related to another by their closest chunks
+#[derive(Debug, Clone, Serialize)]
+pub struct SimilarNote {
+    pub note: Note,
+    /// Embedding distance of the closest chunk
+    pub distance: f32,
+    pub passage: Passage,
+}
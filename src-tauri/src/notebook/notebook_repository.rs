@@ -1,93 +1,99 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 use chrono::Utc;
 use log::info;
-use rusqlite::{Connection, params};
+use rusqlite::backup::Backup;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
 use tokio::sync::Mutex;
 
-use crate::notebook::note::{Category, Note};
+use crate::notebook::attachments::Attachment;
+use crate::notebook::connection_pool::{ReadConnection, ReadPool};
+use crate::notebook::embeddings::{
+    content_hash, EmbeddedNote, EmbeddingOperation, IndexReport, PendingEmbedding,
+};
+use crate::notebook::encryption::NotebookKey;
+use crate::notebook::links::{NoteLink, WikiLink};
+use crate::notebook::listing::{
+    note_snippet, ArchiveFilter, CategoryMatch, NoteListQuery, NoteSortField, NoteSummary,
+    SortDirection, SNIPPET_MAX_CHARS,
+};
+use crate::notebook::migrations;
+use crate::notebook::note::{
+    Category, Note, NoteFlag, NoteFlags, NoteRevision, CATEGORY_PATH_SEPARATOR,
+};
 use crate::notebook::NotebookError;
 
+// pages copied per step of an online backup
+const BACKUP_PAGES_PER_STEP: std::os::raw::c_int = 1024;
+
+#[derive(Clone)]
 pub struct NotebookRepository {
-    conn: Arc<Mutex<Connection>>,
+    // every write goes through this one connection
+    writer: Arc<Mutex<Connection>>,
+    // None when reads share the writer
+    readers: Option<Arc<ReadPool>>,
 }
 
 impl NotebookRepository {
-    pub fn new(conn: Arc<Mutex<Connection>>) -> Self {
-        NotebookRepository { conn }
+    /// A repository reading and writing through the one connection `writer`
+    pub fn new(writer: Arc<Mutex<Connection>>) -> Self {
+        NotebookRepository {
+            writer,
+            readers: None,
+        }
+    }
+
+    /// A repository writing through `writer` and reading through `readers`, so reads don't wait
+    /// for a write in progress. Needs the db in WAL mode.
+    pub fn with_readers(writer: Arc<Mutex<Connection>>, readers: Vec<Connection>) -> Self {
+        NotebookRepository {
+            writer,
+            readers: Some(Arc::new(ReadPool::new(readers))),
+        }
+    }
+
+    async fn reader(&self) -> ReadConnection<'_> {
+        match &self.readers {
+            Some(readers) => ReadConnection::Pooled(readers.get().await),
+            None => ReadConnection::Writer(self.writer.lock().await),
+        }
     }
 
     pub async fn get_note(&self, id: &str) -> Result<Option<Note>, NotebookError> {
-        let conn = self.conn.lock().await;
+        let conn = self.reader().await;
         info!("Getting note {} from models db", id);
-        let mut stmt = conn.prepare(
-            "
-        SELECT n.id, n.content, n.created, n.modified, c.id, c.label
+        let sql = "
+        SELECT n.id, n.content, n.created, n.modified, c.id, c.label, c.parent_id,
+            n.pinned, n.favorite, n.archived
         FROM notes n
         LEFT JOIN note_category nc ON n.id = nc.note_id
         LEFT JOIN categories c ON nc.category_id = c.id
-        WHERE n.id = ?1
-    ",
-        )?;
-        let mut rows = stmt.query(params![id])?;
-
-        let mut categories = HashSet::new();
-        let (id, text, created, modified) = if let Some(row) = rows.next()? {
-            // Extract the note details from the first row
-            let id: String = row.get(0)?;
-            let text: String = row.get(1)?;
-            let created: i64 = row.get(2)?;
-            let modified: i64 = row.get(3)?;
-
-            // If the category ID is present, extract the category label and insert a new Category into the HashSet
-            if let Ok(category_id) = row.get::<_, String>(4) {
-                let category_label: String = row.get(5)?;
-                categories.insert(Category::hydrate(&category_id, &category_label));
-            }
-
-            (id, text, created, modified)
-        } else {
-            // If no rows are returned, the note doesn't exist, so return None
-            return Ok(None);
-        };
-
-        // Process any remaining rows to extract additional categories for the note
-        while let Some(row) = rows.next()? {
-            // The `while let` loop continues to iterate as long as there are more rows to process
-            // Each iteration of the loop processes one row at a time
-
-            // If the category ID is present in the current row, extract the category label and insert a new Category into the HashSet
-            if let Ok(category_id) = row.get::<_, String>(4) {
-                let category_label: String = row.get(5)?;
-                categories.insert(Category::hydrate(&category_id, &category_label));
-            }
-        }
-
-        // Create a new Note instance with the extracted details and categories, and return it wrapped in an Option
-        Ok(Some(Note::hydrate(
-            &id, &text, categories, created, modified,
-        )))
+        WHERE n.id = ?1 AND n.deleted IS NULL
+    ";
+        let notes = Self::query_notes(&conn, sql, params![id])?;
+        Ok(notes.into_iter().next())
     }
 
     pub async fn get_category_by_id(&self, id: &str) -> Result<Option<Category>, NotebookError> {
-        let conn = self.conn.lock().await;
+        let conn = self.reader().await;
         info!("Getting category by ID: {}", id);
-
-        let mut stmt = conn.prepare("SELECT id, label FROM categories WHERE id = ?")?;
-        let mut rows = stmt.query(params![id])?;
-
-        if let Some(row) = rows.next()? {
-            let id: String = row.get(0)?;
-            let label: String = row.get(1)?;
-            Ok(Some(Category::hydrate(&id, &label)))
-        } else {
-            Ok(None)
-        }
+        let category = conn
+            .query_row(
+                "SELECT id, label, parent_id FROM categories WHERE id = ?1",
+                params![id],
+                Self::row_to_category,
+            )
+            .optional()?;
+        Ok(category)
     }
 
+    /// Notes by id, trashed notes are excluded
     pub async fn get_notes_by_ids(&self, ids: Vec<&str>) -> Result<Vec<Note>, NotebookError> {
-        let conn = self.conn.lock().await;
+        let conn = self.reader().await;
         log::info!("Getting notes [{:?}] from models db", ids);
 
         // Create a string of placeholders for the SQL query based on the number of IDs
@@ -96,211 +102,1031 @@ impl NotebookRepository {
         // Construct the SQL query with the placeholders
         let sql = format!(
             "
-        SELECT n.id, n.content, n.created, n.modified, c.id, c.label
+        SELECT n.id, n.content, n.created, n.modified, c.id, c.label, c.parent_id,
+            n.pinned, n.favorite, n.archived
         FROM notes n
         LEFT JOIN note_category nc ON n.id = nc.note_id
         LEFT JOIN categories c ON nc.category_id = c.id
-        WHERE n.id IN ({})
+        WHERE n.id IN ({}) AND n.deleted IS NULL
     ",
             placeholders
         );
+        Self::query_notes(&conn, &sql, rusqlite::params_from_iter(ids.iter()))
+    }
 
-        let mut stmt = conn.prepare(&sql)?;
+    /// Finds the category with the given label under `parent_id` (None for a top level category),
+    /// creating it if needed. Labels are unique among siblings, compared case-insensitively.
+    pub async fn get_or_create_category(
+        &self,
+        cat_label: &str,
+        parent_id: Option<&str>,
+    ) -> Result<Category, NotebookError> {
+        let conn = self.writer.lock().await;
+        let cat_label = cat_label.trim();
+        info!("Upserting category '{}' under {:?}", cat_label, parent_id);
 
-        // Execute the query and map the rows to tuples
-        let rows = stmt.query_map(rusqlite::params_from_iter(ids.iter()), |row| {
-            let id: String = row.get(0)?;
-            let content: String = row.get(1)?;
-            let created: i64 = row.get(2)?;
-            let modified: i64 = row.get(3)?;
-            let category_id: Option<String> = row.get(4)?;
-            let category_label: Option<String> = row.get(5)?;
-            Ok((id, content, created, modified, category_id, category_label))
-        })?;
+        // Perform a case-insensitive search for the category label among its siblings
+        let existing = conn
+            .query_row(
+                "SELECT id, label, parent_id FROM categories
+                WHERE LOWER(label) = LOWER(?1) AND parent_id IS ?2",
+                params![cat_label, parent_id],
+                Self::row_to_category,
+            )
+            .optional()?;
+
+        match existing {
+            // Return the category with the label from the database
+            Some(category) => Ok(category),
+            None => {
+                // Category doesn't exist, create a new category
+                let new_category = Category::new(cat_label, parent_id);
+                conn.execute(
+                    "INSERT INTO categories (id, label, parent_id) VALUES (?1, ?2, ?3)",
+                    params![
+                        new_category.get_id(),
+                        new_category.get_label(),
+                        new_category.get_parent_id()
+                    ],
+                )?;
+                Ok(new_category)
+            }
+        }
+    }
 
-        let mut notes = Vec::new();
-        let mut current_note: Option<(String, String, i64, i64, HashSet<Category>)> = None;
+    fn row_to_category(row: &rusqlite::Row) -> rusqlite::Result<Category> {
+        let id: String = row.get(0)?;
+        let label: String = row.get(1)?;
+        let parent_id: Option<String> = row.get(2)?;
+        Ok(Category::hydrate(&id, &label, parent_id.as_deref()))
+    }
 
-        // Iterate over the rows and build the notes with their categories
-        for row in rows {
-            let (id, content, created, modified, category_id, category_label) = row?;
+    /// All categories with the number of notes (outside the trash) using each, ordered by label
+    pub async fn get_categories_with_counts(
+        &self,
+    ) -> Result<Vec<(Category, usize)>, NotebookError> {
+        let conn = self.reader().await;
+        info!("Getting all categories from models db");
+        let mut stmt = conn.prepare(
+            "
+        SELECT c.id, c.label, c.parent_id, COUNT(n.id)
+        FROM categories c
+        LEFT JOIN note_category nc ON c.id = nc.category_id
+        LEFT JOIN notes n ON nc.note_id = n.id AND n.deleted IS NULL
+        GROUP BY c.id, c.label, c.parent_id
+        ORDER BY LOWER(c.label)
+    ",
+        )?;
+        let categories = stmt
+            .query_map([], |row| {
+                let count: i64 = row.get(3)?;
+                Ok((Self::row_to_category(row)?, count as usize))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(categories)
+    }
 
-            // If there is a current note and its ID matches the current row's ID
-            if let Some((curr_id, curr_content, curr_created, curr_modified, curr_categories)) =
-                &mut current_note
-            {
-                if *curr_id == id {
-                    // If the category ID and label are present, create a new Category and insert it into the current note's categories
-                    if let (Some(cat_id), Some(cat_label)) = (category_id, category_label) {
-                        curr_categories.insert(Category::hydrate(&cat_id, &cat_label));
-                    }
-                    continue; // Move to the next row
-                } else {
-                    // If the current row belongs to a different note, push the current note to the notes vector
-                    notes.push(Note::hydrate(
-                        curr_id,
-                        curr_content,
-                        std::mem::take(curr_categories),
-                        *curr_created,
-                        *curr_modified,
-                    ));
+    /// Relabels a category. The label must stay unique among its siblings, compared
+    /// case-insensitively, though a category can be relabelled to a different case of its own label.
+    pub async fn rename_category(
+        &self,
+        id: &str,
+        new_label: &str,
+    ) -> Result<Category, NotebookError> {
+        let conn = self.writer.lock().await;
+        let new_label = new_label.trim();
+        info!("Renaming category [{}] to '{}'", id, new_label);
+        if new_label.is_empty() || new_label.contains(CATEGORY_PATH_SEPARATOR) {
+            return Err(NotebookError::InvalidCategory(format!(
+                "Category label cannot be empty or contain '{}'",
+                CATEGORY_PATH_SEPARATOR
+            )));
+        }
+        let parent_id: Option<String> = conn
+            .query_row(
+                "SELECT parent_id FROM categories WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or(NotebookError::CategoryNotFound(id.to_string()))?;
+        let clashing: Option<String> = conn
+            .query_row(
+                "SELECT label FROM categories
+                WHERE LOWER(label) = LOWER(?1) AND parent_id IS ?2 AND id != ?3",
+                params![new_label, parent_id, id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(label) = clashing {
+            return Err(NotebookError::InvalidCategory(format!(
+                "Category '{}' already exists",
+                label
+            )));
+        }
+        conn.execute(
+            "UPDATE categories SET label = ?1 WHERE id = ?2",
+            params![new_label, id],
+        )?;
+        Ok(Category::hydrate(id, new_label, parent_id.as_deref()))
+    }
+
+    /// Moves every note and child category in the source category to the target category, then
+    /// removes the source. A child labelled like a child of the target is merged into it in turn,
+    /// so labels stay unique among siblings. Merging a category into one of its own descendants
+    /// is rejected.
+    pub async fn merge_categories(
+        &self,
+        source_id: &str,
+        target_id: &str,
+    ) -> Result<(), NotebookError> {
+        let mut conn = self.writer.lock().await;
+        info!("Merging category [{}] into [{}]", source_id, target_id);
+        if Self::is_ancestor_of(&conn, source_id, target_id)? {
+            return Err(NotebookError::InvalidCategory(format!(
+                "Cannot merge category [{}] into its descendant [{}]",
+                source_id, target_id
+            )));
+        }
+        let tx = conn.transaction()?;
+        Self::merge_category_in(&tx, source_id, target_id)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn merge_category_in(
+        conn: &Connection,
+        source_id: &str,
+        target_id: &str,
+    ) -> Result<(), NotebookError> {
+        // notes already in both categories keep their existing target association
+        conn.execute(
+            "INSERT OR IGNORE INTO note_category (note_id, category_id)
+            SELECT note_id, ?2 FROM note_category WHERE category_id = ?1",
+            params![source_id, target_id],
+        )?;
+        conn.execute(
+            "DELETE FROM note_category WHERE category_id = ?1",
+            params![source_id],
+        )?;
+        let children = {
+            let mut stmt = conn.prepare(
+                "SELECT c.id, t.id FROM categories c
+                LEFT JOIN categories t ON t.parent_id = ?2 AND LOWER(t.label) = LOWER(c.label)
+                WHERE c.parent_id = ?1",
+            )?;
+            let children = stmt
+                .query_map(params![source_id, target_id], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            children
+        };
+        for (child_id, same_label_id) in children {
+            match same_label_id {
+                Some(same_label_id) => Self::merge_category_in(conn, &child_id, &same_label_id)?,
+                None => {
+                    conn.execute(
+                        "UPDATE categories SET parent_id = ?2 WHERE id = ?1",
+                        params![child_id, target_id],
+                    )?;
                 }
             }
+        }
+        conn.execute("DELETE FROM categories WHERE id = ?1", params![source_id])?;
+        Ok(())
+    }
 
-            // Create a new HashSet for the categories of the current note
-            let mut categories = HashSet::new();
-            if let (Some(cat_id), Some(cat_label)) = (category_id, category_label) {
-                categories.insert(Category::hydrate(&cat_id, &cat_label));
+    /// Moves a category under a new parent, or to the top level when `parent_id` is None.
+    /// Moves that would make a category its own ancestor are rejected.
+    pub async fn move_category(
+        &self,
+        id: &str,
+        parent_id: Option<&str>,
+    ) -> Result<Category, NotebookError> {
+        let conn = self.writer.lock().await;
+        info!("Moving category [{}] under {:?}", id, parent_id);
+        let category = conn
+            .query_row(
+                "SELECT id, label, parent_id FROM categories WHERE id = ?1",
+                params![id],
+                Self::row_to_category,
+            )
+            .optional()?
+            .ok_or(NotebookError::CategoryNotFound(id.to_string()))?;
+        if let Some(parent_id) = parent_id {
+            let parent_exists: bool = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM categories WHERE id = ?1)",
+                params![parent_id],
+                |row| row.get(0),
+            )?;
+            if !parent_exists {
+                return Err(NotebookError::CategoryNotFound(parent_id.to_string()));
+            }
+            if parent_id == id || Self::is_ancestor_of(&conn, id, parent_id)? {
+                return Err(NotebookError::InvalidCategory(format!(
+                    "Moving category [{}] under [{}] would create a cycle",
+                    id, parent_id
+                )));
             }
+        }
+        let clashing: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM categories
+            WHERE LOWER(label) = LOWER(?1) AND parent_id IS ?2 AND id != ?3)",
+            params![category.get_label(), parent_id, id],
+            |row| row.get(0),
+        )?;
+        if clashing {
+            return Err(NotebookError::InvalidCategory(format!(
+                "Category '{}' already exists there",
+                category.get_label()
+            )));
+        }
+        conn.execute(
+            "UPDATE categories SET parent_id = ?1 WHERE id = ?2",
+            params![parent_id, id],
+        )?;
+        Ok(Category::hydrate(id, category.get_label(), parent_id))
+    }
+
+    /// True when `ancestor_id` is somewhere above `id` in the category tree
+    fn is_ancestor_of(
+        conn: &Connection,
+        ancestor_id: &str,
+        id: &str,
+    ) -> Result<bool, NotebookError> {
+        let found: bool = conn.query_row(
+            "
+        WITH RECURSIVE ancestors(id) AS (
+            SELECT parent_id FROM categories WHERE id = ?2
+            UNION
+            SELECT c.parent_id FROM categories c JOIN ancestors a ON c.id = a.id
+        )
+        SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = ?1)
+    ",
+            params![ancestor_id, id],
+            |row| row.get(0),
+        )?;
+        Ok(found)
+    }
 
-            // Set the current note to the current row's data
-            current_note = Some((id, content, created, modified, categories));
+    /// Deletes a category that has no child categories and that no note (including notes in the
+    /// trash) uses
+    pub async fn delete_category(&self, id: &str) -> Result<(), NotebookError> {
+        let conn = self.writer.lock().await;
+        info!("Deleting category [{}]", id);
+        let usages: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM note_category WHERE category_id = ?1",
+            params![id],
+            |row| row.get(0),
+        )?;
+        if usages > 0 {
+            return Err(NotebookError::InvalidCategory(format!(
+                "Category [{}] is used by {} notes",
+                id, usages
+            )));
+        }
+        let children: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM categories WHERE parent_id = ?1",
+            params![id],
+            |row| row.get(0),
+        )?;
+        if children > 0 {
+            return Err(NotebookError::InvalidCategory(format!(
+                "Category [{}] has {} child categories",
+                id, children
+            )));
+        }
+        let deleted = conn.execute("DELETE FROM categories WHERE id = ?1", params![id])?;
+        if deleted == 0 {
+            return Err(NotebookError::CategoryNotFound(id.to_string()));
         }
+        Ok(())
+    }
+
+    /// All notes that are not in the trash, archived notes as per `archived`
+    pub async fn get_notes(&self, archived: ArchiveFilter) -> Result<Vec<Note>, NotebookError> {
+        let conn = self.reader().await;
+        log::info!("Getting all notes from models db");
 
-        // If there is a remaining current note, push it to the notes vector
-        if let Some((id, content, created, modified, categories)) = current_note {
-            notes.push(Note::hydrate(&id, &content, categories, created, modified));
+        // Prepare the SQL query to fetch all notes with their categories
+        let mut sql = "
+        SELECT n.id, n.content, n.created, n.modified, c.id, c.label, c.parent_id,
+            n.pinned, n.favorite, n.archived
+        FROM notes n
+        LEFT JOIN note_category nc ON n.id = nc.note_id
+        LEFT JOIN categories c ON nc.category_id = c.id
+        WHERE n.deleted IS NULL
+    "
+        .to_string();
+        if let Some(condition) = archived.sql_condition("n.archived") {
+            sql.push_str(&format!(" AND {}", condition));
         }
+        Self::query_notes(&conn, &sql, [])
+    }
 
-        Ok(notes)
+    /// Notes (outside the trash) in a category or any of its descendant categories
+    pub async fn get_notes_in_category(
+        &self,
+        category_id: &str,
+    ) -> Result<Vec<Note>, NotebookError> {
+        let conn = self.reader().await;
+        log::info!(
+            "Getting notes in category [{}] and its descendants",
+            category_id
+        );
+        let sql = "
+        WITH RECURSIVE subtree(id) AS (
+            SELECT ?1
+            UNION
+            SELECT c.id FROM categories c JOIN subtree s ON c.parent_id = s.id
+        )
+        SELECT n.id, n.content, n.created, n.modified, c.id, c.label, c.parent_id,
+            n.pinned, n.favorite, n.archived
+        FROM notes n
+        LEFT JOIN note_category nc ON n.id = nc.note_id
+        LEFT JOIN categories c ON nc.category_id = c.id
+        WHERE n.deleted IS NULL AND n.id IN (
+            SELECT note_id FROM note_category WHERE category_id IN (SELECT id FROM subtree)
+        )
+    ";
+        Self::query_notes(&conn, sql, params![category_id])
     }
 
-    pub async fn get_or_create_category(&self, cat_label: &str) -> Result<Category, NotebookError> {
-        let conn = self.conn.lock().await;
-        let cat_label = cat_label.trim();
-        info!("Upserting category '{}'", cat_label);
+    /// A page of note summaries matching the query, along with the number of matching notes
+    /// across all pages. Only the start of each note's content is read to build its snippet.
+    pub async fn list_notes(
+        &self,
+        query: &NoteListQuery,
+        limit: usize,
+    ) -> Result<(Vec<NoteSummary>, usize), NotebookError> {
+        let conn = self.reader().await;
+        log::info!("Listing notes: {:?}", query);
 
-        // Perform a case-insensitive search for the category label
-        let mut stmt =
-            conn.prepare("SELECT id, label FROM categories WHERE LOWER(label) = LOWER(?1)")?;
-        let mut rows = stmt.query(params![cat_label.to_lowercase()])?;
+        let mut conditions = vec!["n.deleted IS NULL".to_string()];
+        let mut values: Vec<Value> = Vec::new();
+        let mut subtrees = Vec::new();
+        for category_id in &query.category_ids {
+            subtrees.push(Self::category_subtree(&conn, category_id)?);
+        }
+        let category_groups = match query.category_match {
+            CategoryMatch::Any if !subtrees.is_empty() => vec![subtrees.concat()],
+            CategoryMatch::Any => vec![],
+            CategoryMatch::All => subtrees,
+        };
+        for ids in category_groups {
+            conditions.push(format!(
+                "n.id IN (SELECT note_id FROM note_category WHERE category_id IN ({}))",
+                vec!["?"; ids.len()].join(", ")
+            ));
+            values.extend(ids.into_iter().map(Value::from));
+        }
+        for (condition, flag) in [
+            ("n.pinned = ?", query.pinned),
+            ("n.favorite = ?", query.favorite),
+        ] {
+            if let Some(flag) = flag {
+                conditions.push(condition.to_string());
+                values.push(Value::from(flag));
+            }
+        }
+        if let Some(condition) = query.archived.sql_condition("n.archived") {
+            conditions.push(condition);
+        }
+        for (condition, timestamp) in [
+            ("n.created >= ?", query.created_from),
+            ("n.created <= ?", query.created_to),
+            ("n.modified >= ?", query.modified_from),
+            ("n.modified <= ?", query.modified_to),
+        ] {
+            if let Some(timestamp) = timestamp {
+                conditions.push(condition.to_string());
+                values.push(Value::from(timestamp));
+            }
+        }
+        let where_clause = conditions.join(" AND ");
 
-        if let Some(row) = rows.next()? {
-            let id: String = row.get(0)?;
-            let db_label: String = row.get(1)?;
-            // Return the category with the label from the database
-            Ok(Category::hydrate(&id, &db_label))
-        } else {
-            // Category doesn't exist, create a new category
-            let new_category = Category::new(cat_label);
-            conn.execute(
-                "INSERT INTO categories (id, label) VALUES (?1, ?2)",
-                params![new_category.get_id(), new_category.get_label()],
-            )?;
-            Ok(new_category)
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM notes n WHERE {}", where_clause),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        )?;
+
+        let sort_column = match query.sort {
+            NoteSortField::Created => "n.created",
+            NoteSortField::Modified => "n.modified",
+            NoteSortField::Title => "LOWER(n.title)",
+        };
+        let direction = match query.direction {
+            SortDirection::Asc => "ASC",
+            SortDirection::Desc => "DESC",
+        };
+        // the id tie breaker keeps pages stable when sort values are equal
+        let sql = format!(
+            "SELECT n.id, n.title, substr(n.content, 1, 2048), n.created, n.modified,
+                n.pinned, n.favorite, n.archived
+            FROM notes n
+            WHERE {}
+            ORDER BY n.pinned DESC, {} {}, n.id {}
+            LIMIT ? OFFSET ?",
+            where_clause, sort_column, direction, direction
+        );
+        values.push(Value::from(limit as i64));
+        values.push(Value::from(query.offset as i64));
+        let mut stmt = conn.prepare(&sql)?;
+        let mut summaries = stmt
+            .query_map(params_from_iter(values.iter()), |row| {
+                let content: String = row.get(2)?;
+                Ok(NoteSummary {
+                    id: row.get(0)?,
+                    title: row.get(1)?,
+                    snippet: note_snippet(&content, SNIPPET_MAX_CHARS),
+                    categories: HashSet::new(),
+                    created: row.get(3)?,
+                    modified: row.get(4)?,
+                    flags: Self::row_to_flags(row, 5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if !summaries.is_empty() {
+            let positions: HashMap<String, usize> = summaries
+                .iter()
+                .enumerate()
+                .map(|(position, summary)| (summary.id.clone(), position))
+                .collect();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT nc.note_id, c.id, c.label, c.parent_id
+                FROM note_category nc
+                JOIN categories c ON c.id = nc.category_id
+                WHERE nc.note_id IN ({})",
+                vec!["?"; summaries.len()].join(", ")
+            ))?;
+            let rows = stmt.query_map(params_from_iter(positions.keys()), |row| {
+                let note_id: String = row.get(0)?;
+                let category_id: String = row.get(1)?;
+                let label: String = row.get(2)?;
+                let parent_id: Option<String> = row.get(3)?;
+                Ok((
+                    note_id,
+                    Category::hydrate(&category_id, &label, parent_id.as_deref()),
+                ))
+            })?;
+            for row in rows {
+                let (note_id, category) = row?;
+                if let Some(&position) = positions.get(&note_id) {
+                    summaries[position].categories.insert(category);
+                }
+            }
         }
+        Ok((summaries, total as usize))
     }
 
-    pub async fn get_notes(&self) -> Result<Vec<Note>, NotebookError> {
-        let conn = self.conn.lock().await;
-        log::info!("Getting all notes from models db");
-
-        // Prepare the SQL query to fetch all notes with their categories
+    /// Ids of a category and all its descendants
+    fn category_subtree(
+        conn: &Connection,
+        category_id: &str,
+    ) -> Result<Vec<String>, NotebookError> {
         let mut stmt = conn.prepare(
             "
-        SELECT n.id, n.content, n.created, n.modified, c.id, c.label
+        WITH RECURSIVE subtree(id) AS (
+            SELECT ?1
+            UNION
+            SELECT c.id FROM categories c JOIN subtree s ON c.parent_id = s.id
+        )
+        SELECT id FROM subtree
+    ",
+        )?;
+        let ids = stmt
+            .query_map(params![category_id], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(ids)
+    }
+
+    /// Notes in the trash along with the time they were trashed, most recently trashed first
+    pub async fn get_trashed_notes(&self) -> Result<Vec<(Note, i64)>, NotebookError> {
+        let conn = self.reader().await;
+        log::info!("Getting trashed notes from models db");
+        let sql = "
+        SELECT n.id, n.content, n.created, n.modified, c.id, c.label, c.parent_id,
+            n.pinned, n.favorite, n.archived
         FROM notes n
         LEFT JOIN note_category nc ON n.id = nc.note_id
         LEFT JOIN categories c ON nc.category_id = c.id
-    ",
-        )?;
+        WHERE n.deleted IS NOT NULL
+        ORDER BY n.deleted DESC
+    ";
+        let notes = Self::query_notes(&conn, sql, [])?;
+        let mut stmt = conn.prepare("SELECT id, deleted FROM notes WHERE deleted IS NOT NULL")?;
+        let deleted: HashMap<String, i64> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(notes
+            .into_iter()
+            .map(|note| {
+                let trashed_at = deleted.get(note.get_id()).copied().unwrap_or_default();
+                (note, trashed_at)
+            })
+            .collect())
+    }
+
+    /// Runs a query selecting (note id, content, created, modified, category id, category label,
+    /// category parent id) and folds the one-row-per-category result into Notes, keeping the order notes first appear.
+    fn query_notes<P: rusqlite::Params>(
+        conn: &Connection,
+        sql: &str,
+        params: P,
+    ) -> Result<Vec<Note>, NotebookError> {
+        let mut stmt = conn.prepare(sql)?;
 
         // Execute the query and map the rows to tuples
-        let rows = stmt.query_map([], |row| {
+        let rows = stmt.query_map(params, |row| {
             let id: String = row.get(0)?;
             let content: String = row.get(1)?;
             let created: i64 = row.get(2)?;
             let modified: i64 = row.get(3)?;
             let category_id: Option<String> = row.get(4)?;
             let category_label: Option<String> = row.get(5)?;
-            Ok((id, content, created, modified, category_id, category_label))
+            let category_parent_id: Option<String> = row.get(6)?;
+            let flags = Self::row_to_flags(row, 7)?;
+            Ok((
+                id,
+                content,
+                created,
+                modified,
+                category_id,
+                category_label,
+                category_parent_id,
+                flags,
+            ))
         })?;
 
-        let mut notes = Vec::new();
-        let mut current_note: Option<(String, String, i64, i64, HashSet<Category>)> = None;
-
-        // Iterate over the rows and build the notes with their categories
+        let mut notes: Vec<(String, String, i64, i64, HashSet<Category>, NoteFlags)> = Vec::new();
+        // rows of the same note are not guaranteed to be adjacent, so track each note's position
+        let mut note_positions: HashMap<String, usize> = HashMap::new();
         for row in rows {
-            let (id, content, created, modified, category_id, category_label) = row?;
+            let (
+                id,
+                content,
+                created,
+                modified,
+                category_id,
+                category_label,
+                category_parent_id,
+                flags,
+            ) = row?;
+            let position = *note_positions.entry(id.clone()).or_insert_with(|| {
+                notes.push((id, content, created, modified, HashSet::new(), flags));
+                notes.len() - 1
+            });
+            // If the category ID and label are present, add the Category to the note
+            if let (Some(cat_id), Some(cat_label)) = (category_id, category_label) {
+                notes[position].4.insert(Category::hydrate(
+                    &cat_id,
+                    &cat_label,
+                    category_parent_id.as_deref(),
+                ));
+            }
+        }
 
-            // If there is a current note and its ID matches the current row's ID
-            if let Some((curr_id, curr_content, curr_created, curr_modified, curr_categories)) =
-                &mut current_note
-            {
-                if *curr_id == id {
-                    // If the category ID and label are present, create a new Category and insert it into the current note's categories
-                    if let (Some(cat_id), Some(cat_label)) = (category_id, category_label) {
-                        curr_categories.insert(Category::hydrate(&cat_id, &cat_label));
-                    }
-                    continue; // Move to the next row
-                } else {
-                    // If the current row belongs to a different note, push the current note to the notes vector
-                    notes.push(Note::hydrate(
-                        curr_id,
-                        curr_content,
-                        std::mem::take(curr_categories),
-                        *curr_created,
-                        *curr_modified,
-                    ));
+        Ok(notes
+            .into_iter()
+            .map(|(id, content, created, modified, categories, flags)| {
+                Note::hydrate(&id, &content, categories, created, modified).with_flags(flags)
+            })
+            .collect())
+    }
+
+    /// The pinned, favorite and archived columns starting at `first`
+    fn row_to_flags(row: &rusqlite::Row, first: usize) -> rusqlite::Result<NoteFlags> {
+        Ok(NoteFlags {
+            pinned: row.get(first)?,
+            favorite: row.get(first + 1)?,
+            archived: row.get(first + 2)?,
+        })
+    }
+
+    /// Sets `flag` on the notes (outside the trash) with the given ids, returning how many
+    /// notes were found. A note's modified time is left as it is.
+    pub async fn set_note_flag(
+        &self,
+        ids: &[&str],
+        flag: NoteFlag,
+        value: bool,
+    ) -> Result<usize, NotebookError> {
+        let conn = self.writer.lock().await;
+        info!("Setting {} to {} on notes {:?}", flag.column(), value, ids);
+        let sql = format!(
+            "UPDATE notes SET {} = ? WHERE deleted IS NULL AND id IN ({})",
+            flag.column(),
+            vec!["?"; ids.len()].join(", ")
+        );
+        let mut values = vec![Value::from(value)];
+        values.extend(ids.iter().map(|id| Value::from(id.to_string())));
+        Ok(conn.execute(&sql, params_from_iter(values.iter()))?)
+    }
+
+    /// Runs an FTS5 query (phrases, prefix `term*`, AND/OR/NOT) against note content.
+    /// Returns (note_id, bm25 score, snippet) ordered best match first. Note that bm25 scores
+    /// are negative, the lower the score the more relevant the match.
+    pub async fn search_notes(
+        &self,
+        query: &str,
+        limit: usize,
+        archived: ArchiveFilter,
+    ) -> Result<Vec<(String, f64, String)>, NotebookError> {
+        let conn = self.reader().await;
+        info!(
+            "Searching notes for a {} character query",
+            query.chars().count()
+        );
+        let archived_condition = archived
+            .sql_condition("n.archived")
+            .map(|condition| format!("AND {}", condition))
+            .unwrap_or_default();
+        let mut stmt = conn.prepare(&format!(
+            "
+        SELECT notes_fts.note_id, bm25(notes_fts),
+            snippet(notes_fts, 1, '<mark>', '</mark>', '…', 16)
+        FROM notes_fts
+        JOIN notes n ON n.id = notes_fts.note_id
+        WHERE notes_fts MATCH ?1 AND n.deleted IS NULL {}
+        ORDER BY bm25(notes_fts)
+        LIMIT ?2
+    ",
+            archived_condition
+        ))?;
+        let rows = stmt.query_map(params![query, limit as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        });
+        // A malformed MATCH expression is reported when the query is stepped, so both the
+        // query and the row collection are checked. FTS5 reports it as a plain SQLITE_ERROR
+        // whose message varies ("fts5: syntax error", "no such column: b", "unterminated
+        // string"), while a failing db reports a more specific code.
+        rows.and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| match e {
+                rusqlite::Error::SqliteFailure(err, msg)
+                    if err.extended_code == rusqlite::ffi::SQLITE_ERROR =>
+                {
+                    NotebookError::SearchQuery(msg.unwrap_or_else(|| err.to_string()))
                 }
-            }
+                e => NotebookError::ModelPersistence(e.to_string()),
+            })
+    }
 
-            // Create a new HashSet for the categories of the current note
-            let mut categories = HashSet::new();
-            if let (Some(cat_id), Some(cat_label)) = (category_id, category_label) {
-                categories.insert(Category::hydrate(&cat_id, &cat_label));
+    /// Writes a new or updated Note's text along with its revision and links in a single
+    /// transaction, and queues its vector to be (re)written unless the vector in the embeddings
+    /// ledger was computed from the same content. Either all of it is saved or none of it is.
+    /// Returns whether the vector was queued.
+    pub async fn save_note(
+        &self,
+        note: &Note,
+        is_new: bool,
+        reason: Option<&str>,
+        coalesce_window: i64,
+        links: &[WikiLink],
+    ) -> Result<bool, NotebookError> {
+        let mut conn = self.writer.lock().await;
+        info!("Saving note {} to models db", note.get_id());
+        let tx = conn.transaction()?;
+        if is_new {
+            tx.execute(
+                "INSERT INTO notes (id, content, created, modified) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    note.get_id(),
+                    note.get_text(),
+                    note.get_created(),
+                    note.get_modified()
+                ],
+            )?;
+        } else {
+            let updated = tx.execute(
+                "UPDATE notes SET content = ?1, modified = ?2 WHERE id = ?3",
+                params![note.get_text(), note.get_modified(), note.get_id()],
+            )?;
+            if updated == 0 {
+                return Err(NotebookError::NoteNotFound(note.get_id().to_string()));
             }
-
-            // Set the current note to the current row's data
-            current_note = Some((id, content, created, modified, categories));
         }
+        Self::record_revision_in(
+            &tx,
+            note.get_id(),
+            note.get_text(),
+            note.get_modified(),
+            reason,
+            coalesce_window,
+        )?;
+        Self::replace_links_in(&tx, note.get_id(), links)?;
+        let embedded_hash: Option<String> = tx
+            .query_row(
+                "SELECT content_hash FROM note_embeddings WHERE note_id = ?1",
+                params![note.get_id()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        // back to the embedded content, a change queued since may already be embedding and would
+        // record its vector over the embedded one, so it is queued again rather than dropped
+        let queued: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM pending_embeddings WHERE note_id = ?1 AND operation = ?2)",
+            params![note.get_id(), EmbeddingOperation::Upsert.as_str()],
+            |row| row.get(0),
+        )?;
+        let needs_embedding = queued || embedded_hash != Some(content_hash(note.get_text()));
+        if needs_embedding {
+            Self::queue_embedding_in(
+                &tx,
+                note.get_id(),
+                EmbeddingOperation::Upsert,
+                note.get_modified(),
+            )?;
+        }
+        tx.commit()?;
+        Ok(needs_embedding)
+    }
 
-        // If there is a remaining current note, push it to the notes vector
-        if let Some((id, content, created, modified, categories)) = current_note {
-            notes.push(Note::hydrate(&id, &content, categories, created, modified));
+    /// Records `content` as the latest revision of a Note.
+    /// Plain saves (no reason) landing within `coalesce_window` seconds of the previous plain save
+    /// replace that revision's text rather than adding a new one, so autosave produces at most
+    /// one revision per window. A revision with a reason always starts a new revision.
+    fn record_revision_in(
+        conn: &Connection,
+        note_id: &str,
+        content: &str,
+        timestamp: i64,
+        reason: Option<&str>,
+        coalesce_window: i64,
+    ) -> Result<(), NotebookError> {
+        let latest: Option<(i64, i64, Option<String>)> = conn
+            .query_row(
+                "SELECT id, created, reason FROM note_revisions
+                WHERE note_id = ?1 ORDER BY created DESC, id DESC LIMIT 1",
+                params![note_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        match latest {
+            Some((revision_id, created, None))
+                if reason.is_none() && timestamp - created < coalesce_window =>
+            {
+                info!(
+                    "Coalescing Note [{}] save into revision {}",
+                    note_id, revision_id
+                );
+                conn.execute(
+                    "UPDATE note_revisions SET content = ?1 WHERE id = ?2",
+                    params![content, revision_id],
+                )?;
+            }
+            _ => {
+                info!("Recording new revision for Note [{}]", note_id);
+                conn.execute(
+                    "INSERT INTO note_revisions (note_id, content, created, reason)
+                    VALUES (?1, ?2, ?3, ?4)",
+                    params![note_id, content, timestamp, reason],
+                )?;
+            }
         }
+        Ok(())
+    }
+
+    /// All revisions of a Note, newest first
+    pub async fn get_note_revisions(
+        &self,
+        note_id: &str,
+    ) -> Result<Vec<NoteRevision>, NotebookError> {
+        let conn = self.reader().await;
+        info!("Getting revisions of Note [{}] from models db", note_id);
+        let mut stmt = conn.prepare(
+            "SELECT id, note_id, content, created, reason FROM note_revisions
+            WHERE note_id = ?1 ORDER BY created DESC, id DESC",
+        )?;
+        let revisions = stmt
+            .query_map(params![note_id], Self::row_to_revision)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(revisions)
+    }
+
+    pub async fn get_note_revision(
+        &self,
+        revision_id: i64,
+    ) -> Result<Option<NoteRevision>, NotebookError> {
+        let conn = self.reader().await;
+        info!("Getting revision {} from models db", revision_id);
+        let revision = conn
+            .query_row(
+                "SELECT id, note_id, content, created, reason FROM note_revisions WHERE id = ?1",
+                params![revision_id],
+                Self::row_to_revision,
+            )
+            .optional()?;
+        Ok(revision)
+    }
 
-        Ok(notes)
+    fn row_to_revision(row: &rusqlite::Row) -> rusqlite::Result<NoteRevision> {
+        Ok(NoteRevision {
+            id: row.get(0)?,
+            note_id: row.get(1)?,
+            text: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            created: row.get(3)?,
+            reason: row.get(4)?,
+        })
     }
 
-    pub async fn add_note(&self, note: &Note) -> Result<(), NotebookError> {
-        let conn = self.conn.lock().await;
-        log::info!("Adding note {} to models db", note.get_id());
+    /// Replaces the links recorded for a Note with the links now in its text
+    fn replace_links_in(
+        conn: &Connection,
+        note_id: &str,
+        links: &[WikiLink],
+    ) -> Result<(), NotebookError> {
+        info!("Recording [{}] links from Note [{}]", links.len(), note_id);
         conn.execute(
-            "INSERT INTO notes (id, content, created, modified) VALUES (?1, ?2, ?3, ?4)",
-            (
-                &note.get_id(),
-                &note.get_text(),
-                &note.get_created(),
-                &note.get_modified(),
-            ),
+            "DELETE FROM note_links WHERE source_id = ?1",
+            params![note_id],
         )?;
+        let mut insert =
+            conn.prepare("INSERT INTO note_links (source_id, target, alias) VALUES (?1, ?2, ?3)")?;
+        for link in links {
+            insert.execute(params![note_id, link.target, link.alias])?;
+        }
         Ok(())
     }
 
-    /// Updating a Note's text is quite frequent so there is a method just for that
-    /// Updating anything associated with a note is separate (ex: add_category_to_note)
-    pub async fn update_note_text(&self, note: &Note) -> Result<Note, NotebookError> {
-        let conn = self.conn.lock().await;
-        info!("Updating note {} in models db", note.get_id());
-        // Update the note content and modified timestamp
-        conn.execute(
-            "UPDATE notes SET content = ?1, modified = ?2 WHERE id = ?3",
-            (&note.get_text(), &note.get_modified(), &note.get_id()),
+    /// Links written in a note, resolved to their target notes where possible
+    pub async fn get_outgoing_links(&self, note_id: &str) -> Result<Vec<NoteLink>, NotebookError> {
+        let conn = self.reader().await;
+        info!("Getting links from Note [{}]", note_id);
+        Self::query_links(&conn, "l.source_id = ?1", params![note_id])
+    }
+
+    /// Links from other notes that resolve to this note, by its id or its title
+    pub async fn get_backlinks(&self, note_id: &str) -> Result<Vec<NoteLink>, NotebookError> {
+        let conn = self.reader().await;
+        info!("Getting links to Note [{}]", note_id);
+        Self::query_links(&conn, "target_note.id = ?1", params![note_id])
+    }
+
+    /// Links, across all notes, whose target does not match any note
+    pub async fn get_dangling_links(&self) -> Result<Vec<NoteLink>, NotebookError> {
+        let conn = self.reader().await;
+        info!("Getting dangling links");
+        Self::query_links(&conn, "target_note.id IS NULL", [])
+    }
+
+    /// Selects links from notes outside the trash, resolving each target to a note outside the
+    /// trash. A target matching a note id wins over title matches, and among notes sharing a
+    /// title the oldest wins.
+    fn query_links<P: rusqlite::Params>(
+        conn: &Connection,
+        condition: &str,
+        params: P,
+    ) -> Result<Vec<NoteLink>, NotebookError> {
+        let sql = format!(
+            "
+        SELECT l.source_id, source.title, l.target, l.alias, target_note.id, target_note.title
+        FROM (
+            SELECT source_id, target, alias, COALESCE(
+                (SELECT id FROM notes WHERE id = target AND deleted IS NULL),
+                (SELECT id FROM notes WHERE LOWER(title) = LOWER(target) AND deleted IS NULL
+                    ORDER BY created LIMIT 1)
+            ) AS target_id
+            FROM note_links
+        ) l
+        JOIN notes source ON source.id = l.source_id AND source.deleted IS NULL
+        LEFT JOIN notes target_note ON target_note.id = l.target_id
+        WHERE {}
+        ORDER BY source.modified DESC, l.target
+    ",
+            condition
+        );
+        let mut stmt = conn.prepare(&sql)?;
+        let links = stmt
+            .query_map(params, |row| {
+                Ok(NoteLink {
+                    source_id: row.get(0)?,
+                    source_title: row.get(1)?,
+                    target: row.get(2)?,
+                    alias: row.get(3)?,
+                    target_id: row.get(4)?,
+                    target_title: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(links)
+    }
+
+    pub async fn add_attachment(&self, attachment: &Attachment) -> Result<(), NotebookError> {
+        let mut conn = self.writer.lock().await;
+        info!(
+            "Attaching {} ({}) to Note [{}]",
+            attachment.file_name, attachment.hash, attachment.note_id
+        );
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO attachments (hash, size, created) VALUES (?1, ?2, ?3)",
+            params![attachment.hash, attachment.size as i64, attachment.added],
+        )?;
+        tx.execute(
+            "INSERT INTO note_attachments (id, note_id, hash, file_name, added)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                attachment.id,
+                attachment.note_id,
+                attachment.hash,
+                attachment.file_name,
+                attachment.added
+            ],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Attachments of a note, in the order they were added
+    pub async fn get_attachments(&self, note_id: &str) -> Result<Vec<Attachment>, NotebookError> {
+        let conn = self.reader().await;
+        info!("Getting attachments of Note [{}]", note_id);
+        let mut stmt = conn.prepare(
+            "SELECT na.id, na.note_id, na.file_name, na.hash, a.size, na.added
+            FROM note_attachments na
+            JOIN attachments a ON a.hash = na.hash
+            WHERE na.note_id = ?1
+            ORDER BY na.added, na.file_name",
+        )?;
+        let attachments = stmt
+            .query_map(params![note_id], Self::row_to_attachment)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(attachments)
+    }
+
+    pub async fn get_attachment(&self, id: &str) -> Result<Option<Attachment>, NotebookError> {
+        let conn = self.reader().await;
+        info!("Getting attachment [{}]", id);
+        let attachment = conn
+            .query_row(
+                "SELECT na.id, na.note_id, na.file_name, na.hash, a.size, na.added
+                FROM note_attachments na
+                JOIN attachments a ON a.hash = na.hash
+                WHERE na.id = ?1",
+                params![id],
+                Self::row_to_attachment,
+            )
+            .optional()?;
+        Ok(attachment)
+    }
+
+    fn row_to_attachment(row: &rusqlite::Row) -> rusqlite::Result<Attachment> {
+        Ok(Attachment {
+            id: row.get(0)?,
+            note_id: row.get(1)?,
+            file_name: row.get(2)?,
+            hash: row.get(3)?,
+            size: row.get::<_, i64>(4)? as u64,
+            added: row.get(5)?,
+        })
+    }
+
+    /// Detaches a file from its note. Returns false if there was no such attachment
+    pub async fn remove_attachment(&self, id: &str) -> Result<bool, NotebookError> {
+        let conn = self.writer.lock().await;
+        info!("Removing attachment [{}]", id);
+        let deleted = conn.execute("DELETE FROM note_attachments WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
+    /// Forgets blobs no note is attached to any more, returns the hashes of all blobs still in use
+    pub async fn delete_unreferenced_attachments(&self) -> Result<HashSet<String>, NotebookError> {
+        let mut conn = self.writer.lock().await;
+        let tx = conn.transaction()?;
+        let deleted = tx.execute(
+            "DELETE FROM attachments WHERE hash NOT IN (SELECT hash FROM note_attachments)",
+            [],
         )?;
-        Ok(note.clone())
+        info!(
+            "Deleted [{}] unreferenced attachments from models db",
+            deleted
+        );
+        let hashes = {
+            let mut stmt = tx.prepare("SELECT hash FROM attachments")?;
+            let hashes = stmt
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<HashSet<String>, _>>()?;
+            hashes
+        };
+        tx.commit()?;
+        Ok(hashes)
     }
 
     pub async fn reconcile_note_categories(&self, note: &Note) -> Result<(), NotebookError> {
-        let conn = self.conn.lock().await;
+        let mut conn = self.writer.lock().await;
         info!(
             "Reconciling Note [{}] categories {:?} in models db",
             note.get_id(),
             note.get_categories()
         );
 
+        // the old associations are only replaced once the new ones are written
+        let tx = conn.transaction()?;
         // Delete existing category associations for the note
-        conn.execute(
+        tx.execute(
             "DELETE FROM note_category WHERE note_id = ?1",
             params![note.get_id()],
         )?;
@@ -310,6 +1136,10 @@ impl NotebookRepository {
             .iter()
             .map(|category| (note.get_id(), category.get_id()))
             .collect();
+        if categories.is_empty() {
+            tx.commit()?;
+            return Ok(());
+        }
         // Build and execute a single INSERT query
         let placeholders = categories
             .iter()
@@ -328,48 +1158,1304 @@ impl NotebookRepository {
                 .flat_map(|(note_id, category_id)| vec![note_id, category_id]),
         );
 
-        conn.execute(&sql, params)?;
+        tx.execute(&sql, params)?;
+        tx.commit()?;
         Ok(())
     }
 
-    pub async fn delete_note(&self, id: &str) -> Result<(), NotebookError> {
-        let conn = self.conn.lock().await;
-        log::info!("Deleting note {} in models db", id);
-        conn.execute("DELETE FROM notes WHERE id = ?1", params![id])?;
+    /// Moves a note to the trash. Returns false if there was no such note outside the trash
+    pub async fn trash_note(&self, id: &str, timestamp: i64) -> Result<bool, NotebookError> {
+        let conn = self.writer.lock().await;
+        log::info!("Moving note {} to the trash in models db", id);
+        let updated = conn.execute(
+            "UPDATE notes SET deleted = ?2 WHERE id = ?1 AND deleted IS NULL",
+            params![id, timestamp],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Moves every note to the trash, returns the number of notes trashed
+    pub async fn trash_all_notes(&self, timestamp: i64) -> Result<usize, NotebookError> {
+        let conn = self.writer.lock().await;
+        log::info!("Moving all notes to the trash");
+        let updated = conn.execute(
+            "UPDATE notes SET deleted = ?1 WHERE deleted IS NULL",
+            params![timestamp],
+        )?;
+        Ok(updated)
+    }
+
+    /// Takes a note back out of the trash. Returns false if the note was not in the trash
+    pub async fn restore_note(&self, id: &str) -> Result<bool, NotebookError> {
+        let conn = self.writer.lock().await;
+        log::info!("Restoring note {} from the trash", id);
+        let updated = conn.execute(
+            "UPDATE notes SET deleted = NULL WHERE id = ?1 AND deleted IS NOT NULL",
+            params![id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Permanently deletes trashed notes trashed before `trashed_before`, or all trashed notes
+    /// when None. Returns the ids of the deleted notes.
+    pub async fn purge_trash(
+        &self,
+        trashed_before: Option<i64>,
+    ) -> Result<Vec<String>, NotebookError> {
+        let mut conn = self.writer.lock().await;
+        let cutoff = trashed_before.unwrap_or(i64::MAX);
+        log::info!("Purging notes trashed before {} from models db", cutoff);
+        let tx = conn.transaction()?;
+        let ids = {
+            let mut stmt =
+                tx.prepare("SELECT id FROM notes WHERE deleted IS NOT NULL AND deleted < ?1")?;
+            let ids = stmt
+                .query_map(params![cutoff], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            ids
+        };
+        tx.execute(
+            "DELETE FROM notes WHERE deleted IS NOT NULL AND deleted < ?1",
+            params![cutoff],
+        )?;
+        let now = Utc::now().timestamp();
+        for id in &ids {
+            Self::queue_embedding_in(&tx, id, EmbeddingOperation::Delete, now)?;
+        }
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    /// Queues a change to a note's vector, replacing any change already queued for the note.
+    /// Replacing bumps the revision, so a batch working on the replaced change leaves this one
+    /// queued.
+    fn queue_embedding_in(
+        conn: &Connection,
+        note_id: &str,
+        operation: EmbeddingOperation,
+        timestamp: i64,
+    ) -> Result<(), NotebookError> {
+        conn.execute(
+            "INSERT INTO pending_embeddings (note_id, operation, queued, attempts)
+            VALUES (?1, ?2, ?3, 0)
+            ON CONFLICT (note_id) DO UPDATE SET
+                operation = excluded.operation,
+                queued = excluded.queued,
+                attempts = 0,
+                last_error = NULL,
+                revision = pending_embeddings.revision + 1",
+            params![note_id, operation.as_str(), timestamp],
+        )?;
         Ok(())
     }
 
-    pub async fn delete_all_notes(&self) -> Result<(), NotebookError> {
-        let conn = self.conn.lock().await;
-        log::info!("Deleting all notes");
-        conn.execute("DELETE FROM notes", params![])?;
+    /// Vector changes not yet applied to the embeddings db, changes that failed the fewest
+    /// times first then oldest first
+    pub async fn get_pending_embeddings(
+        &self,
+        limit: Option<usize>,
+    ) -> Result<Vec<PendingEmbedding>, NotebookError> {
+        let conn = self.reader().await;
+        let mut stmt = conn.prepare(
+            "SELECT note_id, operation, queued, attempts, last_error, revision
+            FROM pending_embeddings ORDER BY attempts, queued, note_id
+            LIMIT ?1",
+        )?;
+        let limit = limit.map_or(-1, |limit| limit as i64);
+        let pending = stmt
+            .query_map(params![limit], |row| {
+                let operation: String = row.get(1)?;
+                Ok((
+                    operation,
+                    PendingEmbedding {
+                        note_id: row.get(0)?,
+                        operation: EmbeddingOperation::Upsert,
+                        queued: row.get(2)?,
+                        attempts: row.get(3)?,
+                        last_error: row.get(4)?,
+                        revision: row.get(5)?,
+                    },
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(pending
+            .into_iter()
+            .filter_map(|(operation, mut pending)| {
+                pending.operation = EmbeddingOperation::parse(&operation)?;
+                Some(pending)
+            })
+            .collect())
+    }
+
+    pub async fn count_pending_embeddings(&self) -> Result<usize, NotebookError> {
+        let conn = self.reader().await;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM pending_embeddings", [], |row| {
+            row.get(0)
+        })?;
+        Ok(count as usize)
+    }
+
+    /// Marks queued changes, (note id, revision) pairs, as applied. A change queued again since
+    /// it was read has a new revision and stays queued.
+    pub async fn clear_pending_embeddings(
+        &self,
+        applied: &[(String, i64)],
+    ) -> Result<(), NotebookError> {
+        let conn = self.writer.lock().await;
+        let mut stmt =
+            conn.prepare("DELETE FROM pending_embeddings WHERE note_id = ?1 AND revision = ?2")?;
+        for (note_id, revision) in applied {
+            stmt.execute(params![note_id, revision])?;
+        }
         Ok(())
     }
 
-    pub async fn init_db(&self) -> Result<(), NotebookError> {
-        log::info!("Initializing models Db");
-        // Execute a SQL statement to create a new table named "person".
-        // The table includes an id (integer primary key), name (text), and data (blob) fields.
-        let conn = self.conn.lock().await;
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS categories (
-            id CHAR(36) PRIMARY KEY,
-            label  NVARCHAR(128) NOT NULL)", ())?;
+    /// Records notes whose chunks were written to the embeddings db, replacing their previous
+    /// chunks and clearing the queued changes they applied
+    pub async fn record_embedded(
+        &self,
+        embedded: &[EmbeddedNote],
+        timestamp: i64,
+    ) -> Result<(), NotebookError> {
+        let mut conn = self.writer.lock().await;
+        let tx = conn.transaction()?;
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO note_embeddings (note_id, content_hash, embedded) VALUES (?1, ?2, ?3)
+                ON CONFLICT (note_id) DO UPDATE SET
+                    content_hash = excluded.content_hash,
+                    embedded = excluded.embedded",
+            )?;
+            let mut forget_chunks = tx.prepare("DELETE FROM note_chunks WHERE note_id = ?1")?;
+            let mut insert_chunk = tx.prepare(
+                "INSERT INTO note_chunks (chunk_id, note_id, start, end) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            let mut clear =
+                tx.prepare("DELETE FROM pending_embeddings WHERE note_id = ?1 AND revision = ?2")?;
+            for note in embedded {
+                upsert.execute(params![note.note_id, note.content_hash, timestamp])?;
+                forget_chunks.execute(params![note.note_id])?;
+                for chunk in &note.chunks {
+                    insert_chunk.execute(params![
+                        chunk.id,
+                        note.note_id,
+                        chunk.start as i64,
+                        chunk.end as i64
+                    ])?;
+                }
+                clear.execute(params![note.note_id, note.revision])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Records vectors deleted from the embeddings db, (note id, queued revision) pairs,
+    /// clearing their queued deletes
+    pub async fn record_vectors_deleted(
+        &self,
+        deleted: &[(String, i64)],
+    ) -> Result<(), NotebookError> {
+        let mut conn = self.writer.lock().await;
+        let tx = conn.transaction()?;
+        {
+            let mut forget = tx.prepare("DELETE FROM note_embeddings WHERE note_id = ?1")?;
+            let mut forget_chunks = tx.prepare("DELETE FROM note_chunks WHERE note_id = ?1")?;
+            let mut clear =
+                tx.prepare("DELETE FROM pending_embeddings WHERE note_id = ?1 AND revision = ?2")?;
+            for (note_id, revision) in deleted {
+                forget.execute(params![note_id])?;
+                forget_chunks.execute(params![note_id])?;
+                clear.execute(params![note_id, revision])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Ids of the chunks currently embedded for these notes
+    pub async fn get_chunk_ids(&self, note_ids: &[String]) -> Result<Vec<String>, NotebookError> {
+        let conn = self.reader().await;
+        let mut stmt =
+            conn.prepare("SELECT chunk_id FROM note_chunks WHERE note_id = ?1 ORDER BY chunk_id")?;
+        let mut chunk_ids = Vec::new();
+        for note_id in note_ids {
+            let ids = stmt
+                .query_map(params![note_id], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            chunk_ids.extend(ids);
+        }
+        Ok(chunk_ids)
+    }
+
+    /// Character ranges of chunks in their note, by chunk id
+    pub async fn get_chunk_ranges(
+        &self,
+        chunk_ids: &[&str],
+    ) -> Result<HashMap<String, (usize, usize)>, NotebookError> {
+        let conn = self.reader().await;
+        let mut stmt = conn.prepare("SELECT start, end FROM note_chunks WHERE chunk_id = ?1")?;
+        let mut ranges = HashMap::new();
+        for chunk_id in chunk_ids {
+            if let Some((start, end)) = stmt
+                .query_row(params![chunk_id], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+                })
+                .optional()?
+            {
+                ranges.insert(chunk_id.to_string(), (start as usize, end as usize));
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Compares every note, trashed ones included as they keep their vectors, with
+    /// `vector_ids`, the ids of the vectors in the vector store, and with the embeddings ledger.
+    /// Without `vector_ids` only the ledger is compared, see `IndexReport::vectors_listed`.
+    pub async fn get_index_report(
+        &self,
+        vector_ids: Option<&HashSet<String>>,
+    ) -> Result<IndexReport, NotebookError> {
+        let conn = self.reader().await;
+        let mut report = IndexReport {
+            vectors_listed: vector_ids.is_some(),
+            ..Default::default()
+        };
+        let mut chunk_ids: HashMap<String, Vec<String>> = HashMap::new();
+        let mut stmt =
+            conn.prepare("SELECT note_id, chunk_id FROM note_chunks ORDER BY chunk_id")?;
+        for row in stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            let (note_id, chunk_id): (String, String) = row?;
+            chunk_ids.entry(note_id).or_default().push(chunk_id);
+        }
+        // a note embedded before notes were chunked has a single vector stored under its id
+        let note_vector_ids = |note_id: &str| {
+            chunk_ids
+                .get(note_id)
+                .cloned()
+                .unwrap_or_else(|| vec![note_id.to_string()])
+        };
+        let mut stmt = conn.prepare("SELECT note_id FROM pending_embeddings")?;
+        let pending = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT n.id, n.content, e.content_hash
+            FROM notes n
+            LEFT JOIN note_embeddings e ON e.note_id = n.id
+            ORDER BY n.created, n.id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?;
+        let mut expected_ids = HashSet::new();
+        for row in rows {
+            let (note_id, content, hash) = row?;
+            report.notes_checked += 1;
+            let ids = note_vector_ids(&note_id);
+            let stored = vector_ids.map(|vector_ids| ids.iter().all(|id| vector_ids.contains(id)));
+            expected_ids.extend(ids);
+            match (stored, hash) {
+                (Some(false), _) => report.missing.push(note_id),
+                (_, Some(hash)) if hash != content_hash(&content) => report.stale.push(note_id),
+                (_, Some(_)) => {}
+                // still queued, so not embedded since it was saved
+                (None, None) if pending.contains(&note_id) => report.missing.push(note_id),
+                (_, None) => report.unrecorded.push(note_id),
+            }
+        }
+
+        report.orphaned = match vector_ids {
+            Some(vector_ids) => {
+                let mut orphaned: Vec<String> =
+                    vector_ids.difference(&expected_ids).cloned().collect();
+                orphaned.sort();
+                orphaned
+            }
+            None => {
+                let mut stmt = conn.prepare(
+                    "SELECT e.note_id FROM note_embeddings e
+                    LEFT JOIN notes n ON n.id = e.note_id
+                    WHERE n.id IS NULL
+                    ORDER BY e.note_id",
+                )?;
+                let note_ids = stmt
+                    .query_map([], |row| row.get(0))?
+                    .collect::<Result<Vec<String>, _>>()?;
+                note_ids.iter().flat_map(|id| note_vector_ids(id)).collect()
+            }
+        };
+        report.pending = pending.len();
+        Ok(report)
+    }
+
+    /// Forgets every vector and queues every note, trashed ones included, to be embedded again
+    /// with `model`. The embeddings db must then be emptied and `record_embeddings_cleared`
+    /// called. Returns the ids of the queued notes.
+    pub async fn queue_full_reindex(
+        &self,
+        model: &str,
+        timestamp: i64,
+    ) -> Result<Vec<String>, NotebookError> {
+        let mut conn = self.writer.lock().await;
+        info!("Queueing every note to be embedded again with {}", model);
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO embedding_index (id, model, cleared) VALUES (1, ?1, 0)
+            ON CONFLICT (id) DO UPDATE SET model = excluded.model, cleared = 0",
+            params![model],
+        )?;
+        tx.execute("DELETE FROM note_embeddings", [])?;
+        tx.execute("DELETE FROM note_chunks", [])?;
+        tx.execute("DELETE FROM pending_embeddings", [])?;
+        tx.execute(
+            "INSERT INTO pending_embeddings (note_id, operation, queued, attempts)
+            SELECT id, ?1, ?2, 0 FROM notes",
+            params![EmbeddingOperation::Upsert.as_str(), timestamp],
+        )?;
+        let ids = {
+            let mut stmt = tx.prepare("SELECT id FROM notes ORDER BY created, id")?;
+            let ids = stmt
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            ids
+        };
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    /// The model the embeddings db is written with, and whether the embeddings db was emptied
+    /// since that model was chosen. None for a notebook that was never opened.
+    pub async fn get_embedding_model(&self) -> Result<Option<(String, bool)>, NotebookError> {
+        let conn = self.reader().await;
+        let model = conn
+            .query_row("SELECT model, cleared FROM embedding_index", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?;
+        Ok(model)
+    }
 
+    /// Records the model of a notebook that has no vectors yet
+    pub async fn record_embedding_model(&self, model: &str) -> Result<(), NotebookError> {
+        let conn = self.writer.lock().await;
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS notes (
-            id CHAR(36) PRIMARY KEY,
-            content TEXT,
-            created INTEGER NOT NULL,
-            modified INTEGER NOT NULL)", ())?;
-
-        conn.execute("CREATE TABLE IF NOT EXISTS note_category (
-            note_id CHAR(36),
-            category_id CHAR(36),
-            PRIMARY KEY (note_id, category_id),
-            FOREIGN KEY (note_id) REFERENCES notes (id) ON DELETE CASCADE,
-            FOREIGN KEY (category_id) REFERENCES categories (id)
-        )", ())?;
+            "INSERT INTO embedding_index (id, model, cleared) VALUES (1, ?1, 1)
+            ON CONFLICT (id) DO UPDATE SET model = excluded.model, cleared = 1",
+            params![model],
+        )?;
+        Ok(())
+    }
+
+    pub async fn record_embeddings_cleared(&self) -> Result<(), NotebookError> {
+        let conn = self.writer.lock().await;
+        conn.execute("UPDATE embedding_index SET cleared = 1", [])?;
+        Ok(())
+    }
+
+    /// Keeps the queued changes of these notes for a retry, recording why they failed
+    pub async fn record_embedding_failure(
+        &self,
+        note_ids: &[String],
+        error: &str,
+    ) -> Result<(), NotebookError> {
+        let conn = self.writer.lock().await;
+        let mut stmt = conn.prepare(
+            "UPDATE pending_embeddings SET attempts = attempts + 1, last_error = ?2
+            WHERE note_id = ?1",
+        )?;
+        for note_id in note_ids {
+            stmt.execute(params![note_id, error])?;
+        }
         Ok(())
     }
+
+    /// (id, text) of notes, trashed or not, so their vectors can be written
+    pub async fn get_note_texts(
+        &self,
+        note_ids: &[String],
+    ) -> Result<Vec<(String, String)>, NotebookError> {
+        let conn = self.reader().await;
+        let mut stmt = conn.prepare("SELECT id, content FROM notes WHERE id = ?1")?;
+        let mut texts = Vec::new();
+        for note_id in note_ids {
+            if let Some(text) = stmt
+                .query_row(params![note_id], |row| Ok((row.get(0)?, row.get(1)?)))
+                .optional()?
+            {
+                texts.push(text);
+            }
+        }
+        Ok(texts)
+    }
+
+    /// Copies the models db to `target` with SQLite's online backup, encrypted with `key` like
+    /// the db itself, returning the schema version of the copy. Writes wait for the copy, reads
+    /// don't.
+    pub async fn backup_to(
+        &self,
+        target: &Path,
+        key: Option<&NotebookKey>,
+    ) -> Result<u32, NotebookError> {
+        let conn = self.writer.lock().await;
+        let mut copy = Connection::open(target)?;
+        if let Some(key) = key {
+            key.apply(&copy)?;
+        }
+        Backup::new(&conn, &mut copy)?.run_to_completion(
+            BACKUP_PAGES_PER_STEP,
+            Duration::ZERO,
+            None,
+        )?;
+        // a single file rather than a db and its WAL
+        copy.pragma_update(None, "journal_mode", "DELETE")?;
+        migrations::schema_version(&copy)
+    }
+
+    pub async fn init_db(&self) -> Result<(), NotebookError> {
+        log::info!("Initializing models Db");
+        let mut conn = self.writer.lock().await;
+        // SQLite leaves foreign keys off by default, which would leave ON DELETE CASCADE inert
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrations::migrate(&mut conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::notebook::chunking::{chunk_note, CHUNK_MAX_CHARS, CHUNK_OVERLAP_CHARS};
+    use crate::notebook::links::parse_wiki_links;
+
+    use super::*;
+
+    async fn test_repository() -> NotebookRepository {
+        let conn = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        let repository = NotebookRepository::new(conn);
+        repository.init_db().await.unwrap();
+        repository
+    }
+
+    fn embedded_note(note_id: &str, text: &str, revision: i64) -> EmbeddedNote {
+        EmbeddedNote {
+            note_id: note_id.to_string(),
+            content_hash: content_hash(text),
+            revision,
+            chunks: chunk_note(note_id, text, CHUNK_MAX_CHARS, CHUNK_OVERLAP_CHARS),
+        }
+    }
+
+    async fn add_note(repository: &NotebookRepository, note: &Note) {
+        assert!(repository
+            .save_note(note, true, None, 0, &[])
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_search_notes_follows_note_writes() {
+        let repository = test_repository().await;
+        let mut note = Note::new("1", "# Tokio\nAsync runtime for rust");
+        add_note(&repository, &note).await;
+        add_note(&repository, &Note::new("2", "Borrow checker notes")).await;
+
+        let hits = repository
+            .search_notes("runt*", 10, ArchiveFilter::Exclude)
+            .await
+            .unwrap();
+        assert_eq!(1, hits.len());
+        assert_eq!("1", hits[0].0);
+        assert!(hits[0].2.contains("<mark>runtime</mark>"));
+
+        note.set_text("Green threads");
+        repository
+            .save_note(&note, false, None, 0, &[])
+            .await
+            .unwrap();
+        let search =
+            |query: &'static str| repository.search_notes(query, 10, ArchiveFilter::Exclude);
+        assert!(search("runtime").await.unwrap().is_empty());
+        assert_eq!(1, search("\"green threads\"").await.unwrap().len());
+
+        repository.trash_note("1", 0).await.unwrap();
+        repository.purge_trash(None).await.unwrap();
+        assert!(search("green").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_record_note_revision_coalesces_saves() {
+        let repository = test_repository().await;
+        let save = |text: &str, timestamp: i64, is_new: bool, reason: Option<&'static str>| {
+            let note = Note::hydrate("1", text, HashSet::new(), 0, timestamp);
+            let repository = &repository;
+            async move {
+                repository
+                    .save_note(&note, is_new, reason, 60, &[])
+                    .await
+                    .unwrap();
+            }
+        };
+        save("v1", 0, true, None).await;
+        save("v2", 30, false, None).await;
+        save("v3", 61, false, None).await;
+        save("v4", 62, false, Some("restore")).await;
+
+        let revisions = repository.get_note_revisions("1").await.unwrap();
+        let texts: Vec<&str> = revisions.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(vec!["v4", "v3", "v2"], texts);
+        assert_eq!(Some("restore".to_string()), revisions[0].reason);
+    }
+
+    #[tokio::test]
+    async fn test_trash_hides_and_restores_notes() {
+        let repository = test_repository().await;
+        add_note(&repository, &Note::new("1", "kept note")).await;
+        add_note(&repository, &Note::new("2", "trashed note")).await;
+
+        assert!(repository.trash_note("2", 100).await.unwrap());
+        assert!(!repository.trash_note("2", 100).await.unwrap());
+        assert_eq!(
+            1,
+            repository
+                .get_notes(ArchiveFilter::Exclude)
+                .await
+                .unwrap()
+                .len()
+        );
+        assert!(repository.get_note("2").await.unwrap().is_none());
+        assert!(repository
+            .get_notes_by_ids(vec!["2"])
+            .await
+            .unwrap()
+            .is_empty());
+        let hits = repository
+            .search_notes("note", 10, ArchiveFilter::Exclude)
+            .await
+            .unwrap();
+        assert_eq!(1, hits.len());
+        let trashed = repository.get_trashed_notes().await.unwrap();
+        assert_eq!(("2", 100), (trashed[0].0.get_id(), trashed[0].1));
+
+        assert!(repository.restore_note("2").await.unwrap());
+        assert_eq!(
+            2,
+            repository
+                .get_notes(ArchiveFilter::Exclude)
+                .await
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_trash() {
+        let repository = test_repository().await;
+        add_note(&repository, &Note::new("1", "old")).await;
+        add_note(&repository, &Note::new("2", "recent")).await;
+        add_note(&repository, &Note::new("3", "live")).await;
+        repository.trash_note("1", 100).await.unwrap();
+        repository.trash_note("2", 200).await.unwrap();
+
+        assert_eq!(vec!["1"], repository.purge_trash(Some(150)).await.unwrap());
+        assert_eq!(vec!["2"], repository.purge_trash(None).await.unwrap());
+        assert!(repository.get_trashed_notes().await.unwrap().is_empty());
+        assert_eq!(
+            1,
+            repository
+                .get_notes(ArchiveFilter::Exclude)
+                .await
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_category_management() {
+        let repository = test_repository().await;
+        let mut note = Note::new("1", "note");
+        add_note(&repository, &note).await;
+        let rust = repository
+            .get_or_create_category("rust", None)
+            .await
+            .unwrap();
+        let rust_lang = repository
+            .get_or_create_category("Rust-lang", None)
+            .await
+            .unwrap();
+        note.add_category(rust.clone());
+        note.add_category(rust_lang.clone());
+        repository.reconcile_note_categories(&note).await.unwrap();
+
+        assert!(matches!(
+            repository.rename_category(rust_lang.get_id(), "RUST").await,
+            Err(NotebookError::InvalidCategory(_))
+        ));
+        repository
+            .rename_category(rust.get_id(), "Rust")
+            .await
+            .unwrap();
+        assert!(matches!(
+            repository.delete_category(rust_lang.get_id()).await,
+            Err(NotebookError::InvalidCategory(_))
+        ));
+
+        repository
+            .merge_categories(rust_lang.get_id(), rust.get_id())
+            .await
+            .unwrap();
+        let categories = repository.get_categories_with_counts().await.unwrap();
+        assert_eq!(1, categories.len());
+        assert_eq!(("Rust", 1), (categories[0].0.get_label(), categories[0].1));
+
+        let unused = repository
+            .get_or_create_category("unused", None)
+            .await
+            .unwrap();
+        repository.delete_category(unused.get_id()).await.unwrap();
+        assert!(matches!(
+            repository.delete_category(unused.get_id()).await,
+            Err(NotebookError::CategoryNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_category_hierarchy() {
+        let repository = test_repository().await;
+        let programming = repository
+            .get_or_create_category("Programming", None)
+            .await
+            .unwrap();
+        let rust = repository
+            .get_or_create_category("Rust", Some(programming.get_id()))
+            .await
+            .unwrap();
+        let rust_async = repository
+            .get_or_create_category("Async", Some(rust.get_id()))
+            .await
+            .unwrap();
+        // same label under a different parent is a different category
+        let top_level_async = repository
+            .get_or_create_category("async", None)
+            .await
+            .unwrap();
+        assert_ne!(rust_async, top_level_async);
+
+        let mut note = Note::new("1", "note");
+        add_note(&repository, &note).await;
+        note.add_category(rust_async.clone());
+        repository.reconcile_note_categories(&note).await.unwrap();
+        let in_programming = repository
+            .get_notes_in_category(programming.get_id())
+            .await
+            .unwrap();
+        assert_eq!(1, in_programming.len());
+        assert!(repository
+            .get_notes_in_category(top_level_async.get_id())
+            .await
+            .unwrap()
+            .is_empty());
+
+        for parent in [rust_async.get_id(), programming.get_id()] {
+            assert!(matches!(
+                repository
+                    .move_category(programming.get_id(), Some(parent))
+                    .await,
+                Err(NotebookError::InvalidCategory(_))
+            ));
+        }
+        assert!(matches!(
+            repository
+                .merge_categories(rust.get_id(), rust_async.get_id())
+                .await,
+            Err(NotebookError::InvalidCategory(_))
+        ));
+        let moved = repository
+            .move_category(rust_async.get_id(), Some(programming.get_id()))
+            .await
+            .unwrap();
+        assert_eq!(Some(programming.get_id()), moved.get_parent_id());
+    }
+
+    #[tokio::test]
+    async fn test_merge_categories_merges_children_with_the_same_label() {
+        let repository = test_repository().await;
+        let category = |label: &'static str, parent: Option<&Category>| {
+            let parent_id = parent.map(|parent| parent.get_id().to_string());
+            let repository = &repository;
+            async move {
+                repository
+                    .get_or_create_category(label, parent_id.as_deref())
+                    .await
+                    .unwrap()
+            }
+        };
+        let rust = category("Rust", None).await;
+        let rust_async = category("Async", Some(&rust)).await;
+        let tokio = category("Tokio", Some(&rust_async)).await;
+        let rustlang = category("Rust-lang", None).await;
+        let rustlang_async = category("async", Some(&rustlang)).await;
+        let rustlang_tokio = category("tokio", Some(&rustlang_async)).await;
+        let rustlang_smol = category("smol", Some(&rustlang_async)).await;
+        let mut note = Note::new("1", "note");
+        add_note(&repository, &note).await;
+        note.add_category(rustlang_tokio.clone());
+        repository.reconcile_note_categories(&note).await.unwrap();
+
+        repository
+            .merge_categories(rustlang.get_id(), rust.get_id())
+            .await
+            .unwrap();
+        let mut labels: Vec<(String, Option<String>)> = repository
+            .get_categories_with_counts()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(category, _)| {
+                let parent_id = category.get_parent_id().map(String::from);
+                (category.get_label().to_string(), parent_id)
+            })
+            .collect();
+        labels.sort();
+        let under = |category: &Category| Some(category.get_id().to_string());
+        assert_eq!(
+            vec![
+                ("Async".to_string(), under(&rust)),
+                ("Rust".to_string(), None),
+                ("Tokio".to_string(), under(&rust_async)),
+                ("smol".to_string(), under(&rust_async)),
+            ],
+            labels
+        );
+        // a child with no namesake moves, keeping its id
+        let smol = repository
+            .get_category_by_id(rustlang_smol.get_id())
+            .await
+            .unwrap();
+        assert_eq!(under(&rust_async).as_deref(), smol.unwrap().get_parent_id());
+        let in_tokio = repository
+            .get_notes_in_category(tokio.get_id())
+            .await
+            .unwrap();
+        assert_eq!(1, in_tokio.len());
+    }
+
+    #[tokio::test]
+    async fn test_links_resolve_by_id_and_title() {
+        let repository = test_repository().await;
+        add_note(&repository, &Note::new("2", "## Rust Notes\nbody")).await;
+        let source = Note::new(
+            "1",
+            "# Source\n[[rust notes]] [[2|by id]] [[Not Written Yet]]",
+        );
+        let links = parse_wiki_links(source.get_text());
+        repository
+            .save_note(&source, true, None, 60, &links)
+            .await
+            .unwrap();
+
+        let outgoing = repository.get_outgoing_links("1").await.unwrap();
+        assert_eq!(3, outgoing.len());
+        let dangling: Vec<&str> = outgoing
+            .iter()
+            .filter(|link| link.is_dangling())
+            .map(|link| link.target.as_str())
+            .collect();
+        assert_eq!(vec!["Not Written Yet"], dangling);
+        assert_eq!(2, repository.get_backlinks("2").await.unwrap().len());
+        assert_eq!(
+            Some("Rust Notes".to_string()),
+            repository.get_backlinks("2").await.unwrap()[0].target_title
+        );
+
+        // the dangling link resolves once a note with that title exists
+        add_note(&repository, &Note::new("3", "Not written yet")).await;
+        assert_eq!(1, repository.get_backlinks("3").await.unwrap().len());
+        assert!(repository.get_dangling_links().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unreferenced_attachments() {
+        let repository = test_repository().await;
+        add_note(&repository, &Note::new("1", "note")).await;
+        for (id, hash) in [("a", "shared"), ("b", "shared"), ("c", "single")] {
+            let attachment = Attachment {
+                id: id.to_string(),
+                note_id: "1".to_string(),
+                file_name: format!("{}.png", id),
+                hash: hash.to_string(),
+                size: 1,
+                added: 0,
+            };
+            repository.add_attachment(&attachment).await.unwrap();
+        }
+        assert_eq!(3, repository.get_attachments("1").await.unwrap().len());
+
+        repository.remove_attachment("a").await.unwrap();
+        repository.remove_attachment("c").await.unwrap();
+        let in_use = repository.delete_unreferenced_attachments().await.unwrap();
+        assert_eq!(HashSet::from(["shared".to_string()]), in_use);
+    }
+
+    #[tokio::test]
+    async fn test_list_notes() {
+        let repository = test_repository().await;
+        let rust = repository
+            .get_or_create_category("Rust", None)
+            .await
+            .unwrap();
+        let tokio = repository
+            .get_or_create_category("Tokio", Some(rust.get_id()))
+            .await
+            .unwrap();
+        let db = repository
+            .get_or_create_category("Databases", None)
+            .await
+            .unwrap();
+        for (id, text, timestamp, categories) in [
+            (
+                "1",
+                "# Borrowing\nReferences and lifetimes",
+                100,
+                vec![&rust],
+            ),
+            (
+                "2",
+                "# async runtimes\nTasks and executors",
+                200,
+                vec![&tokio, &db],
+            ),
+            ("3", "# Sqlite\nEmbedded database", 300, vec![&db]),
+        ] {
+            let mut note = Note::hydrate(id, text, HashSet::new(), timestamp, timestamp);
+            for category in categories {
+                note.add_category(category.clone());
+            }
+            add_note(&repository, &note).await;
+            repository.reconcile_note_categories(&note).await.unwrap();
+        }
+
+        let (page, total) = repository
+            .list_notes(&NoteListQuery::default(), 2)
+            .await
+            .unwrap();
+        assert_eq!(3, total);
+        assert_eq!(
+            vec!["3", "2"],
+            page.iter().map(|n| n.id.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!("Sqlite", page[0].title);
+        assert_eq!("Embedded database", page[0].snippet);
+        assert_eq!(2, page[1].categories.len());
+
+        let query = NoteListQuery {
+            sort: NoteSortField::Title,
+            direction: SortDirection::Asc,
+            offset: 1,
+            ..Default::default()
+        };
+        let (page, _) = repository.list_notes(&query, 10).await.unwrap();
+        assert_eq!(
+            vec!["1", "3"],
+            page.iter().map(|n| n.id.as_str()).collect::<Vec<_>>()
+        );
+
+        // a note is in a category when it is in a descendant of it
+        let query = NoteListQuery {
+            category_ids: vec![rust.get_id().to_string(), db.get_id().to_string()],
+            category_match: CategoryMatch::All,
+            ..Default::default()
+        };
+        let (page, total) = repository.list_notes(&query, 10).await.unwrap();
+        assert_eq!(1, total);
+        assert_eq!("2", page[0].id);
+
+        let query = NoteListQuery {
+            category_ids: vec![rust.get_id().to_string()],
+            modified_to: Some(150),
+            ..Default::default()
+        };
+        let (page, total) = repository.list_notes(&query, 10).await.unwrap();
+        assert_eq!(1, total);
+        assert_eq!("1", page[0].id);
+    }
+
+    #[tokio::test]
+    async fn test_note_flags() {
+        let repository = test_repository().await;
+        for (id, timestamp) in [("1", 100), ("2", 200), ("3", 300)] {
+            let note = Note::hydrate(id, "# Flagged\nnote", HashSet::new(), timestamp, timestamp);
+            add_note(&repository, &note).await;
+        }
+
+        let set = |ids: &'static [&'static str], flag| repository.set_note_flag(ids, flag, true);
+        assert_eq!(2, set(&["1", "2"], NoteFlag::Favorite).await.unwrap());
+        assert_eq!(1, set(&["1"], NoteFlag::Pinned).await.unwrap());
+        assert_eq!(1, set(&["3"], NoteFlag::Archived).await.unwrap());
+        assert_eq!(0, set(&["4"], NoteFlag::Pinned).await.unwrap());
+
+        // flags are not edits
+        let note = repository.get_note("1").await.unwrap().unwrap();
+        assert_eq!(100, note.get_modified());
+        let flags = NoteFlags {
+            pinned: true,
+            favorite: true,
+            archived: false,
+        };
+        assert_eq!(flags, note.get_flags());
+
+        // pinned notes come first, archived notes are left out unless asked for
+        let (page, total) = repository
+            .list_notes(&NoteListQuery::default(), 10)
+            .await
+            .unwrap();
+        assert_eq!(2, total);
+        assert_eq!(
+            vec!["1", "2"],
+            page.iter().map(|n| n.id.as_str()).collect::<Vec<_>>()
+        );
+        assert!(page[0].flags.pinned);
+
+        let query = NoteListQuery {
+            favorite: Some(true),
+            pinned: Some(false),
+            ..Default::default()
+        };
+        let (page, _) = repository.list_notes(&query, 10).await.unwrap();
+        assert_eq!(
+            vec!["2"],
+            page.iter().map(|n| n.id.as_str()).collect::<Vec<_>>()
+        );
+
+        let query = NoteListQuery {
+            archived: ArchiveFilter::Only,
+            ..Default::default()
+        };
+        let (page, _) = repository.list_notes(&query, 10).await.unwrap();
+        assert_eq!(
+            vec!["3"],
+            page.iter().map(|n| n.id.as_str()).collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            2,
+            repository
+                .get_notes(ArchiveFilter::Exclude)
+                .await
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            3,
+            repository
+                .get_notes(ArchiveFilter::Include)
+                .await
+                .unwrap()
+                .len()
+        );
+        let search = |archived| repository.search_notes("flagged", 10, archived);
+        assert_eq!(2, search(ArchiveFilter::Exclude).await.unwrap().len());
+        assert_eq!(1, search(ArchiveFilter::Only).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_save_note_is_atomic_and_queues_embeddings() {
+        let repository = test_repository().await;
+        let note = Note::new("1", "# One\nLinks to [[Two]]");
+        let links = parse_wiki_links(note.get_text());
+        repository
+            .save_note(&note, true, None, 60, &links)
+            .await
+            .unwrap();
+        assert_eq!(1, repository.get_outgoing_links("1").await.unwrap().len());
+        assert_eq!(1, repository.get_note_revisions("1").await.unwrap().len());
+        let pending = repository.get_pending_embeddings(None).await.unwrap();
+        assert_eq!(1, pending.len());
+        assert_eq!(EmbeddingOperation::Upsert, pending[0].operation);
+
+        // a failing write leaves nothing behind
+        assert!(repository
+            .save_note(&note, true, None, 60, &[])
+            .await
+            .is_err());
+        assert_eq!(1, repository.get_outgoing_links("1").await.unwrap().len());
+        assert!(repository
+            .save_note(&Note::new("2", "missing"), false, None, 60, &[])
+            .await
+            .is_err());
+        assert!(repository.get_note_revisions("2").await.unwrap().is_empty());
+
+        let ids = vec!["1".to_string()];
+        repository
+            .record_embedding_failure(&ids, "model not loaded")
+            .await
+            .unwrap();
+        let pending = repository.get_pending_embeddings(None).await.unwrap();
+        assert_eq!(1, pending[0].attempts);
+        assert_eq!(Some("model not loaded".to_string()), pending[0].last_error);
+
+        // saving again while the first change is being applied keeps the new change queued
+        let first_revision = pending[0].revision;
+        repository
+            .save_note(&note, false, None, 60, &links)
+            .await
+            .unwrap();
+        let applied = vec![("1".to_string(), first_revision)];
+        repository.clear_pending_embeddings(&applied).await.unwrap();
+        let pending = repository.get_pending_embeddings(None).await.unwrap();
+        assert_eq!(1, pending.len());
+        assert_eq!(0, pending[0].attempts);
+        let applied = vec![("1".to_string(), pending[0].revision)];
+        repository.clear_pending_embeddings(&applied).await.unwrap();
+        assert_eq!(0, repository.count_pending_embeddings().await.unwrap());
+
+        // purging queues the vector for deletion
+        repository.trash_note("1", 0).await.unwrap();
+        repository.purge_trash(None).await.unwrap();
+        let pending = repository.get_pending_embeddings(None).await.unwrap();
+        assert_eq!(EmbeddingOperation::Delete, pending[0].operation);
+        assert!(repository.get_note_texts(&ids).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_index_report() {
+        let repository = test_repository().await;
+        add_note(&repository, &Note::new("1", "embedded")).await;
+        add_note(&repository, &Note::new("2", "edited since")).await;
+        add_note(&repository, &Note::new("3", "never embedded")).await;
+        repository
+            .record_embedded(
+                &[
+                    embedded_note("1", "embedded", 0),
+                    embedded_note("2", "original", 0),
+                    embedded_note("4", "purged", 0),
+                ],
+                0,
+            )
+            .await
+            .unwrap();
+
+        // vectors as stored: a note that predates the ledger under its own id, one left behind
+        // by a deleted note and one stored under a note text
+        let vector_ids: HashSet<String> = ["1#0", "2#0", "4#0", "5", "legacy note text"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        add_note(&repository, &Note::new("5", "before the ledger")).await;
+        let pending = repository.get_pending_embeddings(None).await.unwrap();
+        let unqueued = pending
+            .iter()
+            .find(|pending| pending.note_id == "5")
+            .unwrap();
+        let applied = [("5".to_string(), unqueued.revision)];
+        repository.clear_pending_embeddings(&applied).await.unwrap();
+
+        let report = repository
+            .get_index_report(Some(&vector_ids))
+            .await
+            .unwrap();
+        assert!(report.vectors_listed);
+        assert_eq!(4, report.notes_checked);
+        assert_eq!(vec!["3"], report.missing);
+        assert_eq!(vec!["2"], report.stale);
+        assert_eq!(vec!["5"], report.unrecorded);
+        assert_eq!(vec!["4#0", "legacy note text"], report.orphaned);
+        // note 3 is still queued from its save
+        assert_eq!(1, report.pending);
+
+        // from the ledger alone, vectors under other ids can't be found
+        let report = repository.get_index_report(None).await.unwrap();
+        assert!(!report.vectors_listed);
+        assert_eq!(vec!["3"], report.missing);
+        assert_eq!(vec!["2"], report.stale);
+        assert_eq!(vec!["5"], report.unrecorded);
+        assert_eq!(vec!["4#0"], report.orphaned);
+
+        repository
+            .record_vectors_deleted(&[("4".to_string(), 0)])
+            .await
+            .unwrap();
+        let reindexed = repository
+            .queue_full_reindex("bge-base-en-v1.5", 0)
+            .await
+            .unwrap();
+        assert_eq!(vec!["1", "2", "3", "5"], reindexed);
+        let report = repository.get_index_report(None).await.unwrap();
+        assert_eq!(4, report.missing.len());
+        assert!(report.orphaned.is_empty());
+        assert_eq!(4, report.pending);
+        assert_eq!(
+            Some(("bge-base-en-v1.5".to_string(), false)),
+            repository.get_embedding_model().await.unwrap()
+        );
+        repository.record_embeddings_cleared().await.unwrap();
+        assert_eq!(
+            Some(("bge-base-en-v1.5".to_string(), true)),
+            repository.get_embedding_model().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_note_skips_embedding_unchanged_content() {
+        let repository = test_repository().await;
+        let mut note = Note::new("1", "# Title\nbody");
+        add_note(&repository, &note).await;
+        let pending = repository.get_pending_embeddings(None).await.unwrap();
+        let embedded = embedded_note("1", note.get_text(), pending[0].revision);
+        repository.record_embedded(&[embedded], 0).await.unwrap();
+
+        note.set_text("# Title\nbody  \n");
+        assert!(!repository
+            .save_note(&note, false, None, 60, &[])
+            .await
+            .unwrap());
+        assert_eq!(0, repository.count_pending_embeddings().await.unwrap());
+
+        note.set_text("# Title\nedited body");
+        assert!(repository
+            .save_note(&note, false, None, 60, &[])
+            .await
+            .unwrap());
+        assert_eq!(1, repository.count_pending_embeddings().await.unwrap());
+        // reverting before the edit was embedded keeps a change queued, see below
+        note.set_text("# Title\nbody");
+        assert!(repository
+            .save_note(&note, false, None, 60, &[])
+            .await
+            .unwrap());
+        assert_eq!(1, repository.count_pending_embeddings().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_revert_during_embedding_batch_is_embedded() {
+        let repository = test_repository().await;
+        let mut note = Note::new("1", "original");
+        add_note(&repository, &note).await;
+        let pending = repository.get_pending_embeddings(None).await.unwrap();
+        let embedded = embedded_note("1", "original", pending[0].revision);
+        repository.record_embedded(&[embedded], 0).await.unwrap();
+
+        note.set_text("intermediate");
+        repository
+            .save_note(&note, false, None, 60, &[])
+            .await
+            .unwrap();
+        // a batch picks up the edit, the note is reverted while it embeds
+        let batch = repository.get_pending_embeddings(None).await.unwrap();
+        note.set_text("original");
+        repository
+            .save_note(&note, false, None, 60, &[])
+            .await
+            .unwrap();
+        let embedded = embedded_note("1", "intermediate", batch[0].revision);
+        repository.record_embedded(&[embedded], 0).await.unwrap();
+
+        // the vector recorded is that of the intermediate text, the revert is still to embed
+        let pending = repository.get_pending_embeddings(None).await.unwrap();
+        assert_eq!(1, pending.len());
+        assert!(pending[0].revision > batch[0].revision);
+        let report = repository.get_index_report(None).await.unwrap();
+        assert_eq!(vec!["1".to_string()], report.stale);
+    }
+
+    #[tokio::test]
+    async fn test_record_embedded_replaces_chunks() {
+        let repository = test_repository().await;
+        let long_text = format!("# Long\n\n{}", "paragraph text\n\n".repeat(200));
+        add_note(&repository, &Note::new("1", &long_text)).await;
+        let embedded = embedded_note("1", &long_text, 0);
+        assert!(embedded.chunks.len() > 1);
+        repository
+            .record_embedded(std::slice::from_ref(&embedded), 0)
+            .await
+            .unwrap();
+        let ids = vec!["1".to_string()];
+        assert_eq!(
+            embedded.chunks.len(),
+            repository.get_chunk_ids(&ids).await.unwrap().len()
+        );
+        let ranges = repository
+            .get_chunk_ranges(&["1#1", "missing#0"])
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(&(embedded.chunks[1].start, embedded.chunks[1].end)),
+            ranges.get("1#1")
+        );
+        assert_eq!(1, ranges.len());
+
+        repository
+            .record_embedded(&[embedded_note("1", "short", 0)], 0)
+            .await
+            .unwrap();
+        assert_eq!(vec!["1#0"], repository.get_chunk_ids(&ids).await.unwrap());
+        repository
+            .record_vectors_deleted(&[("1".to_string(), 0)])
+            .await
+            .unwrap();
+        assert!(repository.get_chunk_ids(&ids).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_search_notes_invalid_query() {
+        let repository = test_repository().await;
+        let result = repository
+            .search_notes("AND OR", 10, ArchiveFilter::Exclude)
+            .await;
+        assert!(matches!(result, Err(NotebookError::SearchQuery(_))));
+    }
+
+    #[tokio::test]
+    async fn test_search_notes_query_errors_without_fts5_prefix() {
+        let repository = test_repository().await;
+        add_note(&repository, &Note::new("1", "error: E0502 in a-b")).await;
+        for query in ["a:b", "a-b", "unbalanced\""] {
+            let result = repository
+                .search_notes(query, 10, ArchiveFilter::Exclude)
+                .await;
+            assert!(
+                matches!(result, Err(NotebookError::SearchQuery(_))),
+                "{}: {:?}",
+                query,
+                result
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reads_do_not_wait_for_the_writer() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let db_path = dir.join("db.sqlite");
+        let writer = Connection::open(&db_path).unwrap();
+        writer.pragma_update(None, "journal_mode", "WAL").unwrap();
+        let writer = Arc::new(Mutex::new(writer));
+        NotebookRepository::new(writer.clone())
+            .init_db()
+            .await
+            .unwrap();
+        let reader =
+            Connection::open_with_flags(&db_path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .unwrap();
+        let repository = NotebookRepository::with_readers(writer.clone(), vec![reader]);
+        add_note(&repository, &Note::new("1", "committed")).await;
+
+        // a write in progress
+        let conn = writer.lock().await;
+        conn.execute_batch("BEGIN; UPDATE notes SET content = 'uncommitted' WHERE id = '1';")
+            .unwrap();
+        let note = tokio::time::timeout(Duration::from_secs(1), repository.get_note("1"))
+            .await
+            .expect("reads don't wait for the writer")
+            .unwrap()
+            .unwrap();
+        assert_eq!("committed", note.get_text());
+        conn.execute_batch("COMMIT").unwrap();
+        drop(conn);
+        let note = repository.get_note("1").await.unwrap().unwrap();
+        assert_eq!("uncommitted", note.get_text());
+    }
 }
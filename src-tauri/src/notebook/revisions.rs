@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffMode {
+    #[default]
+    Line,
+    Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffTag {
+    Equal,
+    Insert,
+    Delete,
+}
+
+/// A run of text that is unchanged, added or removed between two revisions
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DiffChange {
+    pub tag: DiffTag,
+    pub value: String,
+}
+
+/// Diffs two texts by line or by word. Consecutive changes with the same tag are merged so the
+/// frontend can render the result as a sequence of spans.
+pub fn diff_texts(old: &str, new: &str, mode: DiffMode) -> Vec<DiffChange> {
+    let diff = match mode {
+        DiffMode::Line => TextDiff::from_lines(old, new),
+        DiffMode::Word => TextDiff::from_words(old, new),
+    };
+    let mut changes: Vec<DiffChange> = Vec::new();
+    for change in diff.iter_all_changes() {
+        let tag = match change.tag() {
+            ChangeTag::Equal => DiffTag::Equal,
+            ChangeTag::Insert => DiffTag::Insert,
+            ChangeTag::Delete => DiffTag::Delete,
+        };
+        match changes.last_mut() {
+            Some(last) if last.tag == tag => last.value.push_str(change.value()),
+            _ => changes.push(DiffChange {
+                tag,
+                value: change.value().to_string(),
+            }),
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_diff() {
+        let changes = diff_texts("a\nb\nc\n", "a\nc\nd\n", DiffMode::Line);
+        assert_eq!(
+            vec![
+                (DiffTag::Equal, "a\n"),
+                (DiffTag::Delete, "b\n"),
+                (DiffTag::Equal, "c\n"),
+                (DiffTag::Insert, "d\n"),
+            ],
+            changes
+                .iter()
+                .map(|c| (c.tag, c.value.as_str()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_word_diff() {
+        let changes = diff_texts("the quick fox", "the slow fox", DiffMode::Word);
+        assert!(changes.contains(&DiffChange {
+            tag: DiffTag::Delete,
+            value: "quick".to_string()
+        }));
+        assert!(changes.contains(&DiffChange {
+            tag: DiffTag::Insert,
+            value: "slow".to_string()
+        }));
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+/// Constant from the original RRF paper, dampens the advantage of the very top ranks so a
+/// document ranked well by both retrievers beats one ranked first by a single retriever.
+const RRF_K: f32 = 60.0;
+
+/// Relative influence of each retriever when fusing rankings
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RankFusionWeights {
+    pub keyword: f32,
+    pub semantic: f32,
+}
+
+impl Default for RankFusionWeights {
+    fn default() -> Self {
+        RankFusionWeights {
+            keyword: 1.0,
+            semantic: 1.0,
+        }
+    }
+}
+
+/// Merges two ranked id lists (best first) with weighted reciprocal rank fusion.
+/// Returns (id, fused score) ordered by descending fused score.
+pub fn reciprocal_rank_fusion(
+    keyword_ranking: &[&str],
+    semantic_ranking: &[&str],
+    weights: RankFusionWeights,
+) -> Vec<(String, f32)> {
+    let mut scores: HashMap<&str, f32> = HashMap::new();
+    for (ranking, weight) in [
+        (keyword_ranking, weights.keyword),
+        (semantic_ranking, weights.semantic),
+    ] {
+        for (rank, id) in ranking.iter().enumerate() {
+            // ranks are 1 based in the RRF formula
+            *scores.entry(id).or_insert(0.0) += weight / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    let mut fused: Vec<(String, f32)> = scores
+        .into_iter()
+        .map(|(id, score)| (id.to_string(), score))
+        .collect();
+    // tie break on id so results are stable between calls
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    fused
+}
+
+/// Turns free text into an FTS5 query that cannot be a syntax error, by quoting each term.
+/// Used when a search box query is not valid FTS syntax (ex: `error: E0502`).
+pub fn quote_fts_terms(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rrf_rewards_agreement() {
+        let fused = reciprocal_rank_fusion(
+            &["a", "b", "c"],
+            &["c", "d", "b"],
+            RankFusionWeights::default(),
+        );
+        let ids: Vec<&str> = fused.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(vec!["c", "b", "a", "d"], ids);
+    }
+
+    #[test]
+    fn test_rrf_weights() {
+        let weights = RankFusionWeights {
+            keyword: 0.0,
+            semantic: 1.0,
+        };
+        let fused = reciprocal_rank_fusion(&["a"], &["b"], weights);
+        assert_eq!("b", fused[0].0);
+        assert_eq!(0.0, fused[1].1);
+    }
+
+    #[test]
+    fn test_quote_fts_terms() {
+        assert_eq!(r#""error:" "E0502""#, quote_fts_terms("error:  E0502"));
+        assert_eq!(r#""say" """hi""""#, quote_fts_terms(r#"say "hi""#));
+    }
+}
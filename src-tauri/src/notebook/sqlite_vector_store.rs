@@ -0,0 +1,148 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use fastembed::TextEmbedding;
+use rusqlite::{params, Connection};
+
+use crate::notebook::chunking::NoteChunk;
+use crate::notebook::vector_store::{SimilarText, VectorStore};
+use crate::notebook::NotebookError;
+
+/// Computes the vectors of texts
+pub trait Embedder: Send + Sync {
+    fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, NotebookError>;
+}
+
+impl Embedder for TextEmbedding {
+    fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, NotebookError> {
+        TextEmbedding::embed(self, texts, None)
+            .map_err(|e| NotebookError::EmbeddingError(e.to_string()))
+    }
+}
+
+/// A `VectorStore` keeping vectors in the `chunk_vectors` table of the models db, used by
+/// encrypted notebooks so their vectors and chunk texts are encrypted with the rest of the db.
+/// Queries compare against every stored vector, which is fast enough for a personal notebook.
+/// Distances are one minus the cosine similarity, results closer than `1 - threshold` are
+/// returned.
+pub struct SqliteVectorStore {
+    conn: Mutex<Connection>,
+    embedder: Box<dyn Embedder>,
+}
+
+fn normalized(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+fn to_bytes(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect()
+}
+
+impl SqliteVectorStore {
+    /// `conn` must be open on a models db at the latest schema version
+    pub fn new(conn: Connection, embedder: Box<dyn Embedder>) -> Self {
+        SqliteVectorStore {
+            conn: Mutex::new(conn),
+            embedder,
+        }
+    }
+}
+
+#[async_trait]
+impl VectorStore for SqliteVectorStore {
+    async fn upsert_chunks(&self, chunks: &[NoteChunk]) -> Result<(), NotebookError> {
+        let texts = chunks.iter().map(|chunk| chunk.text.clone()).collect();
+        let vectors = self.embedder.embed(texts)?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO chunk_vectors (chunk_id, text, vector) VALUES (?1, ?2, ?3)
+                ON CONFLICT (chunk_id) DO UPDATE SET text = excluded.text, vector = excluded.vector",
+            )?;
+            for (chunk, vector) in chunks.iter().zip(vectors) {
+                upsert.execute(params![chunk.id, chunk.text, to_bytes(&normalized(vector))])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<(), NotebookError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut delete = tx.prepare("DELETE FROM chunk_vectors WHERE chunk_id = ?1")?;
+            for id in ids {
+                delete.execute(params![id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn find_similar(
+        &self,
+        text: &str,
+        limit: usize,
+        threshold: f32,
+    ) -> Result<Vec<SimilarText>, NotebookError> {
+        let query = match self.embedder.embed(vec![text.to_string()])?.pop() {
+            Some(vector) => normalized(vector),
+            None => return Ok(vec![]),
+        };
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare("SELECT chunk_id, text, vector FROM chunk_vectors")?;
+        let mut similar = select
+            .query_map([], |row| {
+                let vector: Vec<u8> = row.get(2)?;
+                let similarity: f32 = query
+                    .iter()
+                    .zip(from_bytes(&vector))
+                    .map(|(a, b)| a * b)
+                    .sum();
+                Ok(SimilarText {
+                    id: row.get(0)?,
+                    text: row.get(1)?,
+                    distance: 1.0 - similarity,
+                })
+            })?
+            .filter(|similar| {
+                similar
+                    .as_ref()
+                    .map_or(true, |similar| 1.0 - similar.distance >= threshold)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        similar.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        similar.truncate(limit);
+        Ok(similar)
+    }
+
+    async fn empty(&self) -> Result<(), NotebookError> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM chunk_vectors", [])?;
+        Ok(())
+    }
+
+    async fn list_ids(&self) -> Result<Option<Vec<String>>, NotebookError> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare("SELECT chunk_id FROM chunk_vectors ORDER BY chunk_id")?;
+        let ids = select
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(Some(ids))
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use log::warn;
+use serde::Serialize;
+
+use crate::notebook::attachments::{Attachment, AttachmentStore};
+use crate::notebook::note::Note;
+use crate::notebook::{NotebookError, ATTACHMENTS_DIR};
+
+const NOTE_FILE_EXTENSION: &str = "md";
+
+/// The notes of a notebook with their attachments, taken so an export is written without
+/// holding the notebook (see `Notebook::export_snapshot`)
+pub struct NotesExport {
+    notes: Vec<(Note, Vec<Attachment>)>,
+    attachments: AttachmentStore,
+}
+
+/// A note read from an export with the files exported alongside it, see `read_import`
+pub struct ImportedNote {
+    /// The markdown file the note was read from
+    pub file: PathBuf,
+    pub content: String,
+    pub attachments: Vec<PathBuf>,
+}
+
+/// The outcome of adding imported notes, a failing note or attachment doesn't stop the others
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    /// Notes written, along with whichever of their attachments could be added
+    pub imported: usize,
+    pub failed: Vec<ImportFailure>,
+}
+
+/// A note file or attachment that could not be imported
+#[derive(Debug, Serialize)]
+pub struct ImportFailure {
+    pub file: String,
+    pub error: String,
+}
+
+impl ImportReport {
+    pub fn fail(&mut self, file: &Path, error: NotebookError) {
+        self.failed.push(ImportFailure {
+            file: file.to_string_lossy().into_owned(),
+            error: error.to_string(),
+        });
+    }
+
+    pub fn merge(&mut self, other: ImportReport) {
+        self.imported += other.imported;
+        self.failed.extend(other.failed);
+    }
+}
+
+impl NotesExport {
+    pub fn new(notes: Vec<(Note, Vec<Attachment>)>, attachments: AttachmentStore) -> Self {
+        NotesExport { notes, attachments }
+    }
+
+    /// Writes every note as a markdown file into a new directory under `export_path`. A note's
+    /// attachments are written to `attachments/<note file name without .md>/`, which is where
+    /// `read_import` looks for them. An attachment removed since the snapshot is skipped.
+    /// Returns the number of notes and the export directory.
+    pub fn write_to(&self, export_path: &Path) -> Result<(usize, String), NotebookError> {
+        if !is_writable(export_path) {
+            return Err(NotebookError::FileAccess(format!(
+                "{:?} is not writable",
+                export_path
+            )));
+        }
+
+        let export_dir = create_export_directory(export_path)?;
+        for (index, (note, attachments)) in self.notes.iter().enumerate() {
+            let mut note_title = note_title(note);
+            let mut note_file_path = export_dir.join(format!("{}.md", note_title));
+
+            if note_file_path.exists() {
+                note_title = format!("{}-dupe_{}", note_title, index);
+                note_file_path = export_dir.join(format!("{}.md", note_title));
+            }
+
+            fs::write(&note_file_path, note.get_text())
+                .map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            let attachments_dir = export_dir.join(ATTACHMENTS_DIR).join(&note_title);
+            let mut file_names = HashSet::new();
+            for attachment in attachments {
+                let file_name = unique_file_name(&attachment.file_name, &mut file_names);
+                match self
+                    .attachments
+                    .copy_out_as(attachment, &attachments_dir, &file_name)
+                {
+                    Ok(_) => {}
+                    // removed since the snapshot was taken and its file collected
+                    Err(e) if !self.attachments.blob_path(&attachment.hash).exists() => {
+                        warn!(
+                            "Skipping attachment {} of note {} removed during the export: {}",
+                            attachment.id,
+                            note.get_id(),
+                            e
+                        )
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        Ok((self.notes.len(), export_dir.to_string_lossy().into_owned()))
+    }
+}
+
+/// Reads the markdown files in `import_path` and the attachments exported alongside them, see
+/// `NotesExport::write_to`
+pub fn read_import(import_path: &Path) -> Result<Vec<ImportedNote>, NotebookError> {
+    if !import_path.exists() {
+        return Err(NotebookError::FileAccess(format!(
+            "{:?} does not exist",
+            import_path
+        )));
+    }
+
+    if !import_path.is_dir() {
+        return Err(NotebookError::FileAccess(format!(
+            "{:?} is not a directory",
+            import_path
+        )));
+    }
+
+    let mut notes = Vec::new();
+    for entry in fs::read_dir(import_path).map_err(|e| NotebookError::FileAccess(e.to_string()))? {
+        let entry = entry.map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+        let path = entry.path();
+
+        if path.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext == NOTE_FILE_EXTENSION)
+        {
+            let content =
+                fs::read_to_string(&path).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+            notes.push(ImportedNote {
+                attachments: exported_attachments(import_path, &path)?,
+                file: path,
+                content,
+            });
+        }
+    }
+    Ok(notes)
+}
+
+/// The files exported alongside a note file
+fn exported_attachments(
+    import_path: &Path,
+    note_file_path: &Path,
+) -> Result<Vec<PathBuf>, NotebookError> {
+    let Some(note_file_stem) = note_file_path.file_stem() else {
+        return Ok(Vec::new());
+    };
+    let attachments_dir = import_path.join(ATTACHMENTS_DIR).join(note_file_stem);
+    if !attachments_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut attachments = Vec::new();
+    for entry in
+        fs::read_dir(&attachments_dir).map_err(|e| NotebookError::FileAccess(e.to_string()))?
+    {
+        let path = entry
+            .map_err(|e| NotebookError::FileAccess(e.to_string()))?
+            .path();
+        if path.is_file() {
+            attachments.push(path);
+        }
+    }
+    Ok(attachments)
+}
+
+fn note_title(note: &Note) -> String {
+    let mut title = note.get_text().lines().next().unwrap_or("").to_string();
+
+    // Strip any leading "#" or spaces from the title
+    while title.starts_with('#') || title.starts_with(' ') {
+        title.remove(0);
+    }
+
+    // Replace any characters not suitable for a file name with "_"
+    title = title
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+
+    // Ensure the final title string does not have multiple "_"'s in a row
+    while title.contains("__") {
+        title = title.replace("__", "_");
+    }
+
+    // If title is longer than 100 characters, take the first 50 and the last 50, combine them with a "..." in the middle
+    if title.len() > 100 {
+        let (start, end) = title.split_at(50);
+        title = format!("{}...{}", start, end.split_at(end.len() - 50).1);
+    }
+
+    title
+}
+
+/// `file_name`, or `<stem>-<n>.<extension>` when a note has several attachments by that name.
+/// Names are compared case-insensitively as some file systems do.
+fn unique_file_name(file_name: &str, taken: &mut HashSet<String>) -> String {
+    let path = Path::new(file_name);
+    let stem = path
+        .file_stem()
+        .map_or(file_name.into(), |stem| stem.to_string_lossy());
+    let extension = path.extension().map(|ext| ext.to_string_lossy());
+    let mut unique = file_name.to_string();
+    let mut suffix = 1;
+    while !taken.insert(unique.to_lowercase()) {
+        suffix += 1;
+        unique = match &extension {
+            Some(extension) => format!("{}-{}.{}", stem, suffix, extension),
+            None => format!("{}-{}", stem, suffix),
+        };
+    }
+    unique
+}
+
+fn is_writable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_dir() && !metadata.permissions().readonly(),
+        Err(_) => false,
+    }
+}
+
+fn create_export_directory(export_path: &Path) -> Result<PathBuf, NotebookError> {
+    let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    let export_dir_name = format!("knowling_export_{}", timestamp);
+    let export_dir = export_path.join(export_dir_name);
+    fs::create_dir_all(&export_dir).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+    Ok(export_dir)
+}
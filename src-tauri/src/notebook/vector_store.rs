@@ -0,0 +1,235 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::sync::RwLockReadGuard;
+use tokio::time::Instant;
+use vec_embed_store::{EmbeddingsDb, TextChunk};
+
+use crate::notebook::chunking::NoteChunk;
+use crate::notebook::NotebookError;
+
+// how long a failed load of the embedding engine is remembered before trying again
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A stored text close to a query, lower distances are closer
+#[derive(Debug, Clone)]
+pub struct SimilarText {
+    pub id: String,
+    pub text: String,
+    pub distance: f32,
+}
+
+/// Embeds note chunks and finds the chunks closest to a text. `Notebook` only talks to the
+/// embedding engine through this trait, `EmbeddingsDb` is the one used by the app.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// Embeds the chunks, replacing any stored under the same ids
+    async fn upsert_chunks(&self, chunks: &[NoteChunk]) -> Result<(), NotebookError>;
+
+    /// Ids that are not stored are ignored
+    async fn delete(&self, ids: &[String]) -> Result<(), NotebookError>;
+
+    /// Up to `limit` stored texts closest to `text`, closest first
+    async fn find_similar(
+        &self,
+        text: &str,
+        limit: usize,
+        threshold: f32,
+    ) -> Result<Vec<SimilarText>, NotebookError>;
+
+    /// Deletes every stored text
+    async fn empty(&self) -> Result<(), NotebookError>;
+
+    /// Ids of every stored text, None for a store that can't list them
+    async fn list_ids(&self) -> Result<Option<Vec<String>>, NotebookError> {
+        Ok(None)
+    }
+
+    /// Gets the embedding engine ready if it is not, failing with `EmbeddingUnavailable` when
+    /// it can't be
+    async fn ensure_ready(&self) -> Result<(), NotebookError> {
+        Ok(())
+    }
+}
+
+/// Whether semantic features can be used
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum EngineStatus {
+    Loading,
+    Ready,
+    /// The engine could not be loaded, for example the model could not be downloaded. Notes can
+    /// still be edited and searched by keyword, their vectors are written once it loads.
+    Unavailable {
+        reason: String,
+    },
+}
+
+pub type EngineLoader =
+    Box<dyn Fn() -> BoxFuture<'static, Result<Box<dyn VectorStore>, NotebookError>> + Send + Sync>;
+
+/// A `VectorStore` whose engine is loaded on first use, and loaded again on a later use when
+/// that fails, at most every `RELOAD_INTERVAL`. Calls made while it can't be loaded fail with
+/// `EmbeddingUnavailable`.
+pub struct LazyVectorStore {
+    loader: EngineLoader,
+    // loading takes the write lock, every other call shares the loaded engine
+    store: tokio::sync::RwLock<Option<Box<dyn VectorStore>>>,
+    last_failure: std::sync::Mutex<Option<Instant>>,
+    status: Arc<RwLock<EngineStatus>>,
+}
+
+impl LazyVectorStore {
+    /// `status` is kept up to date with the outcome of each load
+    pub fn new(loader: EngineLoader, status: Arc<RwLock<EngineStatus>>) -> Self {
+        LazyVectorStore {
+            loader,
+            store: tokio::sync::RwLock::new(None),
+            last_failure: std::sync::Mutex::new(None),
+            status,
+        }
+    }
+
+    fn unavailable(&self) -> NotebookError {
+        match &*self.status.read().unwrap() {
+            EngineStatus::Unavailable { reason } => {
+                NotebookError::EmbeddingUnavailable(reason.clone())
+            }
+            status => NotebookError::EmbeddingUnavailable(format!("{:?}", status)),
+        }
+    }
+
+    /// The engine, loading it if needed. The returned guard always holds an engine.
+    async fn loaded(
+        &self,
+    ) -> Result<RwLockReadGuard<'_, Option<Box<dyn VectorStore>>>, NotebookError> {
+        let store = self.store.read().await;
+        if store.is_some() {
+            return Ok(store);
+        }
+        drop(store);
+        let mut store = self.store.write().await;
+        // loaded by another call while this one waited
+        if store.is_none() {
+            let recently_failed = self
+                .last_failure
+                .lock()
+                .unwrap()
+                .is_some_and(|failed| failed.elapsed() < RELOAD_INTERVAL);
+            if recently_failed {
+                return Err(self.unavailable());
+            }
+            match (self.loader)().await {
+                Ok(loaded) => {
+                    info!("Embedding engine loaded");
+                    *store = Some(loaded);
+                    *self.status.write().unwrap() = EngineStatus::Ready;
+                }
+                Err(e) => {
+                    warn!(
+                        "Embedding engine unavailable, semantic features are disabled: {}",
+                        e
+                    );
+                    *self.last_failure.lock().unwrap() = Some(Instant::now());
+                    *self.status.write().unwrap() = EngineStatus::Unavailable {
+                        reason: e.to_string(),
+                    };
+                    return Err(self.unavailable());
+                }
+            }
+        }
+        Ok(store.downgrade())
+    }
+}
+
+fn engine(store: &Option<Box<dyn VectorStore>>) -> &dyn VectorStore {
+    store.as_deref().expect("loaded engine")
+}
+
+#[async_trait]
+impl VectorStore for LazyVectorStore {
+    async fn upsert_chunks(&self, chunks: &[NoteChunk]) -> Result<(), NotebookError> {
+        engine(&*self.loaded().await?).upsert_chunks(chunks).await
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<(), NotebookError> {
+        engine(&*self.loaded().await?).delete(ids).await
+    }
+
+    async fn find_similar(
+        &self,
+        text: &str,
+        limit: usize,
+        threshold: f32,
+    ) -> Result<Vec<SimilarText>, NotebookError> {
+        engine(&*self.loaded().await?)
+            .find_similar(text, limit, threshold)
+            .await
+    }
+
+    async fn empty(&self) -> Result<(), NotebookError> {
+        engine(&*self.loaded().await?).empty().await
+    }
+
+    async fn list_ids(&self) -> Result<Option<Vec<String>>, NotebookError> {
+        engine(&*self.loaded().await?).list_ids().await
+    }
+
+    async fn ensure_ready(&self) -> Result<(), NotebookError> {
+        self.loaded().await.map(|_| ())
+    }
+}
+
+#[async_trait]
+impl VectorStore for EmbeddingsDb {
+    async fn upsert_chunks(&self, chunks: &[NoteChunk]) -> Result<(), NotebookError> {
+        let text_chunks: Vec<TextChunk> = chunks
+            .iter()
+            .map(|chunk| TextChunk {
+                id: chunk.id.clone(),
+                text: chunk.text.clone(),
+            })
+            .collect();
+        self.upsert_texts(&text_chunks).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<(), NotebookError> {
+        self.delete_texts(ids).await?;
+        Ok(())
+    }
+
+    async fn find_similar(
+        &self,
+        text: &str,
+        limit: usize,
+        threshold: f32,
+    ) -> Result<Vec<SimilarText>, NotebookError> {
+        let blocks = self
+            .get_similar_to(text)
+            .limit(limit)
+            .threshold(threshold)
+            .execute()
+            .await
+            .map_err(|e| NotebookError::EmbeddingError(e.to_string()))?;
+        Ok(blocks
+            .into_iter()
+            .map(|block| SimilarText {
+                id: block.id,
+                text: block.text,
+                distance: block.distance as f32,
+            })
+            .collect())
+    }
+
+    async fn empty(&self) -> Result<(), NotebookError> {
+        self.empty_db().await?;
+        Ok(())
+    }
+
+    // vec-embed-store has no way to list the texts it stores, `list_ids` keeps the default
+}
@@ -0,0 +1,86 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::Value;
+use tauri::AppHandle;
+use tauri_plugin_store::{Store, StoreBuilder};
+
+use crate::backups::RetentionPolicy;
+
+/// Settings are persisted by the frontend (tauri-plugin-store) in this file
+const SETTINGS_FILE: &str = "settings.json";
+
+const TRASH_RETENTION_DAYS_KEY: &str = "trashRetentionDays";
+const TRASH_RETENTION_DAYS_DEFAULT: u32 = 30;
+
+const AUTO_LOCK_MINUTES_KEY: &str = "autoLockMinutes";
+const AUTO_LOCK_MINUTES_DEFAULT: u64 = 15;
+
+const BACKUP_INTERVAL_MINUTES_KEY: &str = "backupIntervalMinutes";
+const BACKUP_INTERVAL_MINUTES_DEFAULT: u64 = 60;
+const BACKUP_KEEP_HOURLY_KEY: &str = "backupKeepHourly";
+const BACKUP_KEEP_DAILY_KEY: &str = "backupKeepDaily";
+const BACKUP_KEEP_WEEKLY_KEY: &str = "backupKeepWeekly";
+
+fn load_settings(app_handle: AppHandle) -> Store<tauri::Wry> {
+    let mut store = StoreBuilder::new(app_handle, PathBuf::from(SETTINGS_FILE)).build();
+    // a missing settings file just means nothing has been set yet
+    let _ = store.load();
+    store
+}
+
+fn get_setting(app_handle: AppHandle, key: &str) -> Option<Value> {
+    load_settings(app_handle).get(key).cloned()
+}
+
+/// Where the settings are persisted, the store resolves its file against the app data dir
+pub fn settings_file(app_handle: AppHandle) -> Option<PathBuf> {
+    app_handle
+        .path_resolver()
+        .app_data_dir()
+        .map(|dir| dir.join(SETTINGS_FILE))
+}
+
+/// Number of days a note stays in the trash before it is permanently deleted
+pub fn trash_retention_days(app_handle: AppHandle) -> u32 {
+    get_setting(app_handle, TRASH_RETENTION_DAYS_KEY)
+        .and_then(|value| value.as_u64())
+        .map(|days| days as u32)
+        .unwrap_or(TRASH_RETENTION_DAYS_DEFAULT)
+}
+
+/// How long an encrypted notebook stays unlocked without being used, `None` when it never
+/// locks by itself
+pub fn auto_lock_timeout(app_handle: AppHandle) -> Option<Duration> {
+    let minutes = get_setting(app_handle, AUTO_LOCK_MINUTES_KEY)
+        .and_then(|value| value.as_u64())
+        .unwrap_or(AUTO_LOCK_MINUTES_DEFAULT);
+    // 0 turns auto-lock off
+    (minutes > 0).then(|| Duration::from_secs(minutes * 60))
+}
+
+/// How often the active notebook is snapshotted, `None` when scheduled backups are off
+pub fn backup_interval(app_handle: AppHandle) -> Option<Duration> {
+    let minutes = get_setting(app_handle, BACKUP_INTERVAL_MINUTES_KEY)
+        .and_then(|value| value.as_u64())
+        .unwrap_or(BACKUP_INTERVAL_MINUTES_DEFAULT);
+    // 0 turns scheduled backups off
+    (minutes > 0).then(|| Duration::from_secs(minutes * 60))
+}
+
+/// How many scheduled snapshots are kept
+pub fn backup_retention(app_handle: AppHandle) -> RetentionPolicy {
+    let store = load_settings(app_handle);
+    let defaults = RetentionPolicy::default();
+    let count = |key: &str, default: usize| {
+        store
+            .get(key)
+            .and_then(|value| value.as_u64())
+            .map_or(default, |count| count as usize)
+    };
+    RetentionPolicy {
+        hourly: count(BACKUP_KEEP_HOURLY_KEY, defaults.hourly),
+        daily: count(BACKUP_KEEP_DAILY_KEY, defaults.daily),
+        weekly: count(BACKUP_KEEP_WEEKLY_KEY, defaults.weekly),
+    }
+}
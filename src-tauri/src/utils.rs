@@ -22,6 +22,23 @@ pub fn get_user_app_dir() -> PathBuf {
     knowling_dir
 }
 
+/// Copies the directory `source` to `target`, hard linking rather than copying files when
+/// `link` is set, for files that are never modified in place
+pub fn copy_dir(source: &Path, target: &Path, link: bool) -> std::io::Result<()> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let target = target.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target, link)?;
+        } else if !link || fs::hard_link(entry.path(), &target).is_err() {
+            // hard links fail across file systems
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
 fn write_to_file(data: &str, file_path: PathBuf) -> std::io::Result<()> {
     let mut file = OpenOptions::new()
         .append(true)
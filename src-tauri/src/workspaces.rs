@@ -0,0 +1,321 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::backups::{self, Backups};
+use crate::notebook::embeddings::EmbeddingModelChoice;
+use crate::notebook::encryption::{self, NotebookKey};
+use crate::notebook::model_cache::ModelCache;
+use crate::notebook::{Notebook, NotebookError};
+
+/// The notebook living directly in the app dir, which is where notebooks were kept before
+/// there could be more than one
+pub const DEFAULT_NOTEBOOK: &str = "default";
+const NOTEBOOKS_DIR: &str = "notebooks";
+const WORKSPACES_FILE: &str = "notebooks.json";
+// the embedding model is shared by all notebooks
+const LLM_CACHE_DIR: &str = "llm-cache";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NotebookInfo {
+    pub name: String,
+    pub path: String,
+    pub active: bool,
+    pub encrypted: bool,
+}
+
+/// Whether the active notebook is encrypted and, if so, whether it is locked
+#[derive(Debug, Clone, Serialize)]
+pub struct LockState {
+    pub notebook: String,
+    pub encrypted: bool,
+    pub locked: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct WorkspacesConfig {
+    active: Option<String>,
+    embedding_model: Option<String>,
+}
+
+/// Knows which notebooks exist under the app dir and which one is active.
+/// Each notebook has its own models db, embeddings and attachments, all of them are embedded
+/// with the same model.
+pub struct Workspaces {
+    app_dir: PathBuf,
+    active: String,
+    embedding_model: EmbeddingModelChoice,
+    model_cache: ModelCache,
+    backups: Backups,
+}
+
+impl Workspaces {
+    /// Loads the remembered active notebook, falling back to the default notebook if there is
+    /// none or it no longer exists
+    pub fn load(app_dir: &Path) -> Self {
+        let config: WorkspacesConfig = fs::read_to_string(app_dir.join(WORKSPACES_FILE))
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        let mut workspaces = Workspaces {
+            app_dir: app_dir.to_path_buf(),
+            active: DEFAULT_NOTEBOOK.to_string(),
+            embedding_model: config
+                .embedding_model
+                .as_deref()
+                .and_then(EmbeddingModelChoice::parse)
+                .unwrap_or_default(),
+            model_cache: ModelCache::new(app_dir.join(LLM_CACHE_DIR)),
+            backups: Backups::new(app_dir),
+        };
+        if let Some(active) = config.active {
+            if workspaces
+                .notebook_dir(&active)
+                .is_ok_and(|dir| dir.is_dir())
+            {
+                workspaces.active = active;
+            }
+        }
+        workspaces
+    }
+
+    pub fn active(&self) -> &str {
+        &self.active
+    }
+
+    pub fn list(&self) -> Result<Vec<NotebookInfo>, NotebookError> {
+        let mut names = vec![DEFAULT_NOTEBOOK.to_string()];
+        let notebooks_dir = self.app_dir.join(NOTEBOOKS_DIR);
+        if notebooks_dir.is_dir() {
+            let mut named: Vec<String> = fs::read_dir(&notebooks_dir)
+                .map_err(|e| NotebookError::FileAccess(e.to_string()))?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_dir())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| Self::validate_name(name).is_ok())
+                .collect();
+            named.sort_by_key(|name| name.to_lowercase());
+            names.extend(named);
+        }
+        names.into_iter().map(|name| self.info(&name)).collect()
+    }
+
+    pub fn create(&self, name: &str) -> Result<NotebookInfo, NotebookError> {
+        let name = name.trim();
+        let dir = self.notebook_dir(name)?;
+        if name == DEFAULT_NOTEBOOK || dir.exists() {
+            return Err(NotebookError::Workspace(format!(
+                "A notebook named '{}' already exists",
+                name
+            )));
+        }
+        info!("Creating notebook '{}' at {:?}", name, dir);
+        fs::create_dir_all(&dir).map_err(|e| NotebookError::FileAccess(e.to_string()))?;
+        self.info(name)
+    }
+
+    /// Opens a notebook, this loads its embedding engine so it can take a while. An encrypted
+    /// notebook fails with `NotebookLocked`, it is opened with `unlock`.
+    pub async fn open(&self, name: &str) -> Result<Notebook, NotebookError> {
+        self.open_with_key(name, None).await
+    }
+
+    /// Opens a notebook, with its key when it is encrypted
+    pub async fn open_with_key(
+        &self,
+        name: &str,
+        key: Option<NotebookKey>,
+    ) -> Result<Notebook, NotebookError> {
+        let dir = self.existing_notebook_dir(name)?;
+        backups::finish_restore(&dir)?;
+        if key.is_none() && encryption::is_encrypted(&dir) {
+            return Err(NotebookError::NotebookLocked(name.to_string()));
+        }
+        info!("Opening notebook '{}' at {:?}", name, dir);
+        Notebook::new(self.embedding_model, self.model_cache.clone(), &dir, key).await
+    }
+
+    /// Opens an encrypted notebook with its passphrase
+    pub async fn unlock(&self, name: &str, passphrase: &str) -> Result<Notebook, NotebookError> {
+        let key = encryption::unlock(&self.existing_notebook_dir(name)?, passphrase)?;
+        self.open_with_key(name, Some(key)).await
+    }
+
+    /// Encrypts a notebook that is not open and opens it
+    pub async fn encrypt(&self, name: &str, passphrase: &str) -> Result<Notebook, NotebookError> {
+        let key =
+            encryption::encrypt_notebook(&self.existing_notebook_dir(name)?, passphrase).await?;
+        let deleted = self.backups.delete_plain(name)?;
+        info!(
+            "Deleted [{}] backups taken before notebook '{}' was encrypted",
+            deleted, name
+        );
+        self.open_with_key(name, Some(key)).await
+    }
+
+    pub fn change_passphrase(
+        &self,
+        name: &str,
+        current: &str,
+        new: &str,
+    ) -> Result<(), NotebookError> {
+        encryption::change_passphrase(&self.existing_notebook_dir(name)?, current, new)
+    }
+
+    pub fn is_encrypted(&self, name: &str) -> bool {
+        self.notebook_dir(name)
+            .is_ok_and(|dir| encryption::is_encrypted(&dir))
+    }
+
+    /// The lock state of the active notebook, `unlocked` when it is open
+    pub fn lock_state(&self, unlocked: bool) -> LockState {
+        LockState {
+            notebook: self.active.clone(),
+            encrypted: self.is_encrypted(&self.active),
+            locked: !unlocked,
+        }
+    }
+
+    /// Makes a notebook the active one, remembered across restarts
+    pub fn set_active(&mut self, name: &str) -> Result<(), NotebookError> {
+        self.notebook_dir(name)?;
+        self.active = name.to_string();
+        self.save_config()
+    }
+
+    pub fn embedding_model(&self) -> EmbeddingModelChoice {
+        self.embedding_model
+    }
+
+    /// Chooses the model notebooks are embedded with from their next open, remembered across
+    /// restarts
+    pub fn set_embedding_model(
+        &mut self,
+        model: EmbeddingModelChoice,
+    ) -> Result<(), NotebookError> {
+        self.embedding_model = model;
+        self.save_config()
+    }
+
+    /// The embedding models downloaded for all notebooks
+    pub fn model_cache(&self) -> &ModelCache {
+        &self.model_cache
+    }
+
+    /// The snapshots of all notebooks
+    pub fn backups(&self) -> &Backups {
+        &self.backups
+    }
+
+    /// Replaces a notebook that is not open with one of its snapshots, see `Backups::restore`
+    pub fn restore_backup(
+        &self,
+        name: &str,
+        id: &str,
+        settings_file: Option<&Path>,
+    ) -> Result<(), NotebookError> {
+        self.backups
+            .restore(name, id, &self.existing_notebook_dir(name)?, settings_file)
+    }
+
+    fn save_config(&self) -> Result<(), NotebookError> {
+        let config = WorkspacesConfig {
+            active: Some(self.active.clone()),
+            embedding_model: Some(self.embedding_model.as_str().to_string()),
+        };
+        let json = serde_json::to_string_pretty(&config)
+            .map_err(|e| NotebookError::Workspace(e.to_string()))?;
+        fs::write(self.app_dir.join(WORKSPACES_FILE), json)
+            .map_err(|e| NotebookError::FileAccess(e.to_string()))
+    }
+
+    fn info(&self, name: &str) -> Result<NotebookInfo, NotebookError> {
+        let dir = self.notebook_dir(name)?;
+        Ok(NotebookInfo {
+            name: name.to_string(),
+            path: dir.to_string_lossy().into_owned(),
+            active: name == self.active,
+            encrypted: encryption::is_encrypted(&dir),
+        })
+    }
+
+    fn existing_notebook_dir(&self, name: &str) -> Result<PathBuf, NotebookError> {
+        let dir = self.notebook_dir(name)?;
+        if !dir.is_dir() {
+            return Err(NotebookError::Workspace(format!(
+                "No notebook named '{}'",
+                name
+            )));
+        }
+        Ok(dir)
+    }
+
+    fn notebook_dir(&self, name: &str) -> Result<PathBuf, NotebookError> {
+        if name == DEFAULT_NOTEBOOK {
+            return Ok(self.app_dir.clone());
+        }
+        Self::validate_name(name)?;
+        Ok(self.app_dir.join(NOTEBOOKS_DIR).join(name))
+    }
+
+    /// Notebook names are used as directory names, so keep them to a portable set of characters
+    fn validate_name(name: &str) -> Result<(), NotebookError> {
+        let valid = !name.is_empty()
+            && name.len() <= 64
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.'));
+        if valid {
+            Ok(())
+        } else {
+            Err(NotebookError::Workspace(format!(
+                "'{}' is not a valid notebook name, use letters, numbers, spaces, '-', '_' or '.'",
+                name
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_list_and_remember_active() {
+        let temp = tempfile::tempdir().unwrap();
+        let app_dir = temp.path();
+        let mut workspaces = Workspaces::load(app_dir);
+        assert_eq!(DEFAULT_NOTEBOOK, workspaces.active());
+
+        workspaces.create("Work").unwrap();
+        assert!(workspaces.create("Work").is_err());
+        assert!(workspaces.create("../escape").is_err());
+        workspaces.set_active("Work").unwrap();
+        workspaces
+            .set_embedding_model(EmbeddingModelChoice::MultilingualE5Small)
+            .unwrap();
+
+        let workspaces = Workspaces::load(app_dir);
+        assert_eq!("Work", workspaces.active());
+        assert_eq!(
+            EmbeddingModelChoice::MultilingualE5Small,
+            workspaces.embedding_model()
+        );
+        let names: Vec<(String, bool)> = workspaces
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|info| (info.name, info.active))
+            .collect();
+        assert_eq!(
+            vec![
+                (DEFAULT_NOTEBOOK.to_string(), false),
+                ("Work".to_string(), true)
+            ],
+            names
+        );
+    }
+}
@@ -38,10 +38,11 @@ export async function upsertNote(noteId, noteText) {
 
 export async function getRelatedNotes(noteId, similarityThreshold) {
     try {
-        const results = await invoke('get_note_similarities', {id: noteId, threshold: similarityThreshold});
-        return results.map(([note, similarityScore]) => ({
+        const {results} = await invoke('get_note_similarities', {id: noteId, threshold: similarityThreshold});
+        return results.map(({note, distance, passage}) => ({
             note,
-            similarityScore,
+            similarityScore: distance,
+            passage,
         }));
     } catch (error) {
         console.error('Failed to get related notes:', error);